@@ -0,0 +1,145 @@
+use bevy::prelude::*;
+
+use crate::level::{Cell, LevelGrid, CELL_SIZE};
+
+/// Size of the box around a player's position that can't overlap walls
+pub const PLAYER_HITBOX: Vec2 = Vec2::splat(24.0);
+
+impl LevelGrid {
+    /// Whether a box centered on `center` overlaps a wall cell
+    pub fn overlaps_wall(&self, center: Vec2, size: Vec2) -> bool {
+        self.overlaps(center, size, Cell::Wall)
+    }
+
+    /// Whether a box centered on `center` overlaps a cell of the given kind
+    pub fn overlaps(&self, center: Vec2, size: Vec2, kind: Cell) -> bool {
+        self.overlapped_cells(center, size, kind).next().is_some()
+    }
+
+    /// Centers of the cells of the given kind that a box centered on `center` overlaps
+    pub fn overlapped_cells(
+        &self,
+        center: Vec2,
+        size: Vec2,
+        kind: Cell,
+    ) -> impl Iterator<Item = Vec2> + '_ {
+        let min = (center - size / 2.0 - self.origin) / CELL_SIZE;
+        let max = (center + size / 2.0 - self.origin) / CELL_SIZE;
+        // cells only touching the edge of the box don't count
+        let (min_x, min_y) = (min.x.floor() as i64, min.y.floor() as i64);
+        let (max_x, max_y) = (max.x.ceil() as i64 - 1, max.y.ceil() as i64 - 1);
+        // outside of the grid is floor, the level edges are handled separately
+        (min_y.max(0)..=max_y)
+            .flat_map(move |y| (min_x.max(0)..=max_x).map(move |x| (x as u32, y as u32)))
+            .filter(move |(x, y)| self.cell(*x, *y) == kind)
+            .map(|(x, y)| self.cell_center(x, y))
+    }
+
+    /// The cell under a position, what a player standing there walks on
+    pub fn terrain_at(&self, position: Vec2) -> Cell {
+        let cell = ((position - self.origin) / CELL_SIZE).floor();
+        if cell.x < 0.0 || cell.y < 0.0 {
+            return Cell::Floor;
+        }
+        self.cell(cell.x as u32, cell.y as u32)
+    }
+}
+
+/// Move a player by `delta`, dropping the part of the movement that would go into a wall.
+/// Each axis is checked on its own, so that a player running diagonally into a wall slides along it.
+/// Used by both the client prediction and the server, so it must only depend on its arguments.
+pub fn move_and_slide(level: &LevelGrid, position: Vec2, delta: Vec2) -> Vec2 {
+    let mut position = position;
+    for step in [Vec2::new(delta.x, 0.0), Vec2::new(0.0, delta.y)] {
+        let moved = position + step;
+        // a player stuck in a wall, e.g. after the level changed, can still walk out of it
+        if !level.overlaps_wall(moved, PLAYER_HITBOX)
+            || level.overlaps_wall(position, PLAYER_HITBOX)
+        {
+            position = moved;
+        }
+    }
+    position
+}
+
+/// The closest place to `position` where a player fits, inside the level and out of the walls.
+/// None when there is no room anywhere in the level.
+pub fn nearest_free_position(level: &LevelGrid, position: Vec2) -> Option<Vec2> {
+    let bounds = level.bounds();
+    let position = position.clamp(bounds.min, bounds.max);
+    if !level.overlaps_wall(position, PLAYER_HITBOX) {
+        return Some(position);
+    }
+    // a player is smaller than a cell, so it fits in the middle of any cell that isn't a wall
+    (0..level.height)
+        .flat_map(|y| (0..level.width).map(move |x| (x, y)))
+        .map(|(x, y)| level.cell_center(x, y))
+        .filter(|center| !level.overlaps_wall(*center, PLAYER_HITBOX))
+        .min_by(|a, b| {
+            a.distance_squared(position)
+                .total_cmp(&b.distance_squared(position))
+        })
+}
+
+/// How far the player at `a` must move so that it stops overlapping the player at `b`,
+/// along the axis where they overlap the least. Zero if they don't overlap.
+pub fn push_out(a: Vec2, b: Vec2) -> Vec2 {
+    let offset = a - b;
+    let overlap = PLAYER_HITBOX - offset.abs();
+    if overlap.x <= 0.0 || overlap.y <= 0.0 {
+        return Vec2::ZERO;
+    }
+    // players exactly on top of each other are pushed right/up, the other one gets the opposite push
+    let direction = |offset: f32| if offset < 0.0 { -1.0 } else { 1.0 };
+    if overlap.x < overlap.y {
+        Vec2::new(overlap.x * direction(offset.x), 0.0)
+    } else {
+        Vec2::new(0.0, overlap.y * direction(offset.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagonal_move_slides_along_a_horizontal_wall() {
+        // the player stands right below the wall along the top of the level
+        let level = LevelGrid::from_rows(&["###", "...", "..."]);
+        let position = Vec2::new(48.0, 48.0);
+        let moved = move_and_slide(&level, position, Vec2::new(5.0, 10.0));
+        assert_eq!(moved, Vec2::new(53.0, 48.0));
+    }
+
+    #[test]
+    fn free_diagonal_move_keeps_both_axes() {
+        let level = LevelGrid::from_rows(&["...", "...", "..."]);
+        let moved = move_and_slide(&level, Vec2::new(48.0, 48.0), Vec2::new(5.0, 10.0));
+        assert_eq!(moved, Vec2::new(53.0, 58.0));
+    }
+
+    #[test]
+    fn nearest_free_position_stays_in_the_level() {
+        let level = LevelGrid::from_rows(&["...", "...", "..."]);
+        assert_eq!(
+            nearest_free_position(&level, Vec2::new(-50.0, 200.0)),
+            Some(Vec2::new(0.0, 96.0))
+        );
+        assert_eq!(
+            nearest_free_position(&level, Vec2::new(40.0, 50.0)),
+            Some(Vec2::new(40.0, 50.0))
+        );
+    }
+
+    #[test]
+    fn nearest_free_position_leaves_walls() {
+        let level = LevelGrid::from_rows(&["##.", "##.", "..."]);
+        // inside the wall block, the closest cell it fits in is the one to its right
+        assert_eq!(
+            nearest_free_position(&level, Vec2::new(40.0, 60.0)),
+            Some(Vec2::new(80.0, 48.0))
+        );
+        let walls = LevelGrid::from_rows(&["##", "##"]);
+        assert_eq!(nearest_free_position(&walls, Vec2::splat(32.0)), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! The server of a listen-server, running in the same process as the client.
+//!
+//! The client and the server talk through in-memory channels instead of sockets. The server's app is
+//! updated on its own thread while running, and handed back to the [`EmbeddedServer`] when paused so
+//! that it can be stepped one update at a time.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use bevy::app::PluginsState;
+use bevy::prelude::*;
+use bevy::tasks::tick_global_task_pools_on_main_thread;
+use bevy::utils::Duration;
+use lightyear::connection::netcode::ClientId;
+use lightyear::transport::{io::TransportConfig, LOCAL_SOCKET};
+
+use crate::networking::Settings;
+
+/// How often the server's app is updated while running, often enough for every tick to run on time
+const UPDATE_INTERVAL: Duration = Duration::from_micros(1_000_000 / 120);
+
+/// A server owned by the client's process
+pub struct EmbeddedServer {
+    state: ServerState,
+}
+
+enum ServerState {
+    /// The app waits to be started or stepped
+    Paused(App),
+    /// The app is updated on its thread until `stop` is set, and given back when it is joined
+    Running {
+        thread: JoinHandle<App>,
+        stop: Arc<AtomicBool>,
+    },
+    /// Only while switching states, or once the server is gone
+    Stopped,
+}
+
+impl EmbeddedServer {
+    /// Build the server for the client `host`, paused.
+    /// Also returns the transport the client connects to it with.
+    pub fn new(settings: Settings, host: ClientId) -> (Self, TransportConfig) {
+        let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
+        let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+        let client_transport = TransportConfig::LocalChannel {
+            recv: from_server_recv,
+            send: to_server_send,
+        };
+        let server_transport = TransportConfig::Channels {
+            // even if we communicate via channels, we need to provide a socket address for the client
+            channels: vec![(LOCAL_SOCKET, to_server_recv, from_server_send)],
+        };
+        // a window needs the main thread, which the client's window already uses
+        let mut settings = settings;
+        let windowed = !std::mem::replace(&mut settings.server.headless, true);
+        let mut app = crate::server_app(settings, vec![server_transport], Some(host));
+        if windowed {
+            warn!("The embedded server can't have a window, it runs headless");
+        }
+        finish_plugins(&mut app);
+        let server = EmbeddedServer {
+            state: ServerState::Paused(app),
+        };
+        (server, client_transport)
+    }
+
+    /// Keep updating the server on its own thread
+    pub fn start(&mut self) {
+        let ServerState::Paused(mut app) = std::mem::replace(&mut self.state, ServerState::Stopped)
+        else {
+            return;
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let thread = std::thread::spawn(move || {
+            while !stopping.load(Ordering::Relaxed) {
+                app.update();
+                std::thread::sleep(UPDATE_INTERVAL);
+            }
+            app
+        });
+        self.state = ServerState::Running { thread, stop };
+    }
+
+    /// Stop updating the server after its current update, it can be started or stepped again afterwards
+    pub fn pause(&mut self) {
+        let ServerState::Running { thread, stop } =
+            std::mem::replace(&mut self.state, ServerState::Stopped)
+        else {
+            return;
+        };
+        stop.store(true, Ordering::Relaxed);
+        match thread.join() {
+            Ok(app) => self.state = ServerState::Paused(app),
+            Err(_) => error!("The embedded server panicked"),
+        }
+    }
+
+    /// Run a single update of a paused server. Whether a tick runs depends on the time elapsed since
+    /// the previous update.
+    pub fn update(&mut self) {
+        match &mut self.state {
+            ServerState::Paused(app) => app.update(),
+            ServerState::Running { .. } => warn!("Pause the embedded server before stepping it"),
+            ServerState::Stopped => warn!("The embedded server is stopped"),
+        }
+    }
+
+    /// Wait for the server's current update to end and drop it
+    pub fn stop(mut self) {
+        self.pause();
+        info!("Stopped the embedded server");
+    }
+}
+
+impl Drop for EmbeddedServer {
+    fn drop(&mut self) {
+        // the thread would otherwise keep running on its own
+        if let ServerState::Running { stop, .. } = &self.state {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// What [`App::run`] does before running the app, since the server's app is updated by hand instead
+pub(crate) fn finish_plugins(app: &mut App) {
+    while app.plugins_state() == PluginsState::Adding {
+        tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::server::HostClient;
+
+    fn paused_app(server: &EmbeddedServer) -> &App {
+        match &server.state {
+            ServerState::Paused(app) => app,
+            _ => panic!("the server isn't paused"),
+        }
+    }
+
+    #[test]
+    fn build_step_and_stop() {
+        let mut settings = Settings::embedded();
+        // only the in-memory channels, nothing to bind
+        settings.server.transport.clear();
+        let (mut server, transport) = EmbeddedServer::new(settings, 1);
+        assert!(matches!(transport, TransportConfig::LocalChannel { .. }));
+        assert_eq!(
+            paused_app(&server).world.resource::<HostClient>(),
+            &HostClient(1)
+        );
+
+        server.update();
+        let stepped = paused_app(&server).world.resource::<Time<Real>>().elapsed();
+        std::thread::sleep(Duration::from_millis(10));
+        server.update();
+        assert!(paused_app(&server).world.resource::<Time<Real>>().elapsed() > stepped);
+
+        server.start();
+        assert!(matches!(server.state, ServerState::Running { .. }));
+        std::thread::sleep(Duration::from_millis(50));
+        server.pause();
+        // it kept updating on its own thread
+        let ran = paused_app(&server).world.resource::<Time<Real>>().elapsed();
+        assert!(ran > stepped + Duration::from_millis(20));
+
+        server.update();
+        server.stop();
+    }
+}
@@ -0,0 +1,156 @@
+//! Fixed-point movement, for when the client prediction and the server must agree to the bit.
+//!
+//! Positions are whole numbers of 1/[`FIXED_ONE`] pixels, which `f32` holds exactly as long as they
+//! stay within 65536 pixels of the origin. A [`PlayerPosition`](crate::networking::protocol::PlayerPosition)
+//! can then keep storing a `Vec2`: it converts back and forth without losing anything, and all the
+//! movement math in between is done on integers.
+
+use bevy::prelude::*;
+
+use crate::collision::PLAYER_HITBOX;
+use crate::level::{Cell, LevelGrid, CELL_SIZE};
+use crate::networking::WorldEdges;
+
+/// Fixed-point units in a pixel
+pub const FIXED_ONE: i32 = 256;
+
+/// A position or a movement, in 1/[`FIXED_ONE`] pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedVec2 {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl FixedVec2 {
+    pub const fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    /// The closest fixed-point value
+    pub fn from_vec2(value: Vec2) -> Self {
+        let value = (value * FIXED_ONE as f32).round();
+        Self::new(value.x as i32, value.y as i32)
+    }
+
+    pub fn to_vec2(self) -> Vec2 {
+        Vec2::new(self.x as f32, self.y as f32) / FIXED_ONE as f32
+    }
+}
+
+impl std::ops::Add for FixedVec2 {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+/// Size of a level cell in fixed-point units
+const FIXED_CELL: i32 = CELL_SIZE as i32 * FIXED_ONE;
+
+impl LevelGrid {
+    /// [`LevelGrid::overlaps`] for a fixed-point box
+    fn overlaps_fixed(&self, center: FixedVec2, size: FixedVec2, kind: Cell) -> bool {
+        let origin = FixedVec2::from_vec2(self.origin);
+        let min_x = center.x - size.x / 2 - origin.x;
+        let min_y = center.y - size.y / 2 - origin.y;
+        let max_x = center.x + size.x / 2 - origin.x;
+        let max_y = center.y + size.y / 2 - origin.y;
+        // cells only touching the edge of the box don't count
+        let (min_x, min_y) = (min_x.div_euclid(FIXED_CELL), min_y.div_euclid(FIXED_CELL));
+        let max_x = (max_x + FIXED_CELL - 1).div_euclid(FIXED_CELL) - 1;
+        let max_y = (max_y + FIXED_CELL - 1).div_euclid(FIXED_CELL) - 1;
+        (min_y.max(0)..=max_y)
+            .any(|y| (min_x.max(0)..=max_x).any(|x| self.cell(x as u32, y as u32) == kind))
+    }
+}
+
+/// [`crate::collision::move_and_slide`] on fixed-point values
+pub fn move_and_slide_fixed(level: &LevelGrid, position: FixedVec2, delta: FixedVec2) -> FixedVec2 {
+    let hitbox = FixedVec2::from_vec2(PLAYER_HITBOX);
+    let mut position = position;
+    for step in [FixedVec2::new(delta.x, 0), FixedVec2::new(0, delta.y)] {
+        let moved = position + step;
+        if !level.overlaps_fixed(moved, hitbox, Cell::Wall)
+            || level.overlaps_fixed(position, hitbox, Cell::Wall)
+        {
+            position = moved;
+        }
+    }
+    position
+}
+
+impl WorldEdges {
+    /// [`WorldEdges::apply`] on fixed-point values
+    pub fn apply_fixed(self, position: FixedVec2, bounds: Rect) -> FixedVec2 {
+        let min = FixedVec2::from_vec2(bounds.min);
+        let max = FixedVec2::from_vec2(bounds.max);
+        match self {
+            WorldEdges::Clamp => FixedVec2::new(
+                position.x.clamp(min.x, max.x),
+                position.y.clamp(min.y, max.y),
+            ),
+            WorldEdges::Wrap => FixedVec2::new(
+                min.x + (position.x - min.x).rem_euclid(max.x - min.x),
+                min.y + (position.y - min.y).rem_euclid(max.y - min.y),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::move_and_slide;
+
+    #[test]
+    fn positions_convert_without_loss() {
+        for position in [
+            Vec2::ZERO,
+            Vec2::new(12.5, -3.25),
+            Vec2::new(1000.0 + 1.0 / 256.0, 65535.0),
+        ] {
+            assert_eq!(FixedVec2::from_vec2(position).to_vec2(), position);
+        }
+        // anything in between is rounded to the closest fixed-point value
+        assert_eq!(
+            FixedVec2::from_vec2(Vec2::new(0.3 / 256.0, 0.7 / 256.0)),
+            FixedVec2::new(0, 1)
+        );
+    }
+
+    #[test]
+    fn fixed_point_agrees_with_floats() {
+        let level = LevelGrid::from_rows(&["#####", "#...#", "#.#.#", "#...#", "#####"]);
+        let cases = [
+            // free moves
+            (Vec2::new(48.0, 48.0), Vec2::new(3.75, 0.0)),
+            (Vec2::new(48.5, 112.25), Vec2::new(-2.5, 1.125)),
+            // into the walls around the level, and sliding along them
+            (Vec2::new(48.0, 48.0), Vec2::new(-10.0, -10.0)),
+            (Vec2::new(112.0, 48.0), Vec2::new(10.0, 5.5)),
+            // into the pillar in the middle
+            (Vec2::new(48.0, 80.0), Vec2::new(9.0, 0.0)),
+        ];
+        for (position, delta) in cases {
+            let float = move_and_slide(&level, position, delta);
+            let fixed = move_and_slide_fixed(
+                &level,
+                FixedVec2::from_vec2(position),
+                FixedVec2::from_vec2(delta),
+            );
+            assert_eq!(fixed.to_vec2(), float, "moving {} by {}", position, delta);
+        }
+    }
+
+    #[test]
+    fn fixed_point_edges_agree_with_floats() {
+        let bounds = Rect::new(0.0, 0.0, 320.0, 160.0);
+        for position in [Vec2::new(-4.5, 80.0), Vec2::new(330.25, 170.0)] {
+            for edges in [WorldEdges::Clamp, WorldEdges::Wrap] {
+                let fixed = edges.apply_fixed(FixedVec2::from_vec2(position), bounds);
+                assert_eq!(fixed.to_vec2(), edges.apply(position, bounds));
+            }
+        }
+    }
+}
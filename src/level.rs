@@ -0,0 +1,221 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::LdtkJson;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Size of a level cell in pixels, the same as the LDtk levels
+pub const CELL_SIZE: f32 = 32.0;
+
+/// The LDtk project, embedded so that the headless server knows the level without loading any asset
+const LDTK_PROJECT: &str = include_str!("../assets/matrix_office.ldtk");
+
+/// The LDtk int-grid layer holding the walls, see [`crate::wall::WallBundle`], and the other terrains
+const WALLS_LAYER: &str = "walls";
+
+/// Share of the inner cells that become walls in generated levels
+const WALL_DENSITY: f64 = 0.12;
+
+/// Cells around the spawn point that are always kept free, in cells
+const SPAWN_CLEARANCE: i64 = 2;
+
+/// What a level cell is made of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cell {
+    #[default]
+    Floor,
+    /// Players can't go through
+    Wall,
+    /// Hurts the players standing on it
+    Hazard,
+    /// Slows the players wading through it
+    Water,
+    /// The players slide on it, slow to start and to stop
+    Ice,
+}
+
+impl Cell {
+    /// The cell for a value of the LDtk walls layer
+    fn from_int_grid(value: i32) -> Self {
+        match value {
+            1 => Cell::Wall,
+            2 => Cell::Hazard,
+            3 => Cell::Water,
+            4 => Cell::Ice,
+            _ => Cell::Floor,
+        }
+    }
+
+    /// Share of the walking speed the players keep on this cell
+    pub fn speed_factor(self) -> f32 {
+        match self {
+            Cell::Water => 0.5,
+            _ => 1.0,
+        }
+    }
+
+    /// Share of their acceleration the players keep on this cell, below 1 they slide
+    pub fn grip(self) -> f32 {
+        match self {
+            Cell::Ice => 0.15,
+            _ => 1.0,
+        }
+    }
+}
+
+/// A level made of square cells
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct LevelGrid {
+    pub width: u32,
+    pub height: u32,
+    /// Bottom left corner of the grid in world coordinates
+    pub origin: Vec2,
+    cells: Vec<Cell>,
+}
+
+impl LevelGrid {
+    /// Generate a level surrounded by walls, with pillars scattered inside.
+    /// The same seed always gives the same level, on every platform.
+    pub fn generate(seed: u64, width: u32, height: u32) -> Self {
+        // StdRng is the same algorithm everywhere, unlike SmallRng which depends on the pointer width
+        let mut rng = StdRng::seed_from_u64(seed);
+        // center the level on the spawn point
+        let origin = -Vec2::new(width as f32, height as f32) * CELL_SIZE / 2.0;
+        let spawn = ((width / 2) as i64, (height / 2) as i64);
+        let mut cells = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let border = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                let near_spawn = (x as i64 - spawn.0).abs() <= SPAWN_CLEARANCE
+                    && (y as i64 - spawn.1).abs() <= SPAWN_CLEARANCE;
+                // always draw from the rng so that every cell depends on the seed the same way
+                let pillar = rng.gen_bool(WALL_DENSITY);
+                let wall = border || (pillar && !near_spawn);
+                cells.push(if wall { Cell::Wall } else { Cell::Floor });
+            }
+        }
+        Self {
+            width,
+            height,
+            origin,
+            cells,
+        }
+    }
+
+    /// The cells of a level of the embedded LDtk project, placed where bevy_ecs_ldtk spawns it
+    /// with `LevelSpawnBehavior::UseZeroTranslation`
+    pub fn from_ldtk(level_index: usize) -> Result<Self, LevelError> {
+        let project: LdtkJson = serde_json::from_str(LDTK_PROJECT).map_err(LevelError::Parse)?;
+        let level = project
+            .levels
+            .get(level_index)
+            .ok_or(LevelError::MissingLevel(level_index))?;
+        let layer = level
+            .layer_instances
+            .iter()
+            .flatten()
+            .find(|layer| layer.identifier == WALLS_LAYER)
+            .ok_or(LevelError::MissingLayer(WALLS_LAYER))?;
+        if layer.grid_size as f32 != CELL_SIZE {
+            return Err(LevelError::CellSize(layer.grid_size));
+        }
+        let width = layer.c_wid as u32;
+        let height = layer.c_hei as u32;
+        // LDtk rows go from the top down, ours from the bottom up
+        let mut cells = vec![Cell::Floor; (width * height) as usize];
+        for (i, value) in layer.int_grid_csv.iter().enumerate() {
+            let (x, row) = (i as u32 % width, i as u32 / width);
+            if row < height {
+                cells[((height - 1 - row) * width + x) as usize] = Cell::from_int_grid(*value);
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            origin: Vec2::ZERO,
+            cells,
+        })
+    }
+
+    /// A level drawn with one character per cell, the first row being the top one.
+    /// `#` is a wall, `^` a hazard, `~` water, `*` ice and anything else floor.
+    #[cfg(test)]
+    pub(crate) fn from_rows(rows: &[&str]) -> Self {
+        let height = rows.len() as u32;
+        let width = rows.first().map_or(0, |row| row.len()) as u32;
+        let cells = rows
+            .iter()
+            .rev()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '#' => Cell::Wall,
+                '^' => Cell::Hazard,
+                '~' => Cell::Water,
+                '*' => Cell::Ice,
+                _ => Cell::Floor,
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            origin: Vec2::ZERO,
+            cells,
+        }
+    }
+
+    /// The cell at the given coordinates, outside of the grid is floor
+    pub fn cell(&self, x: u32, y: u32) -> Cell {
+        if x < self.width && y < self.height {
+            self.cells[(y * self.width + x) as usize]
+        } else {
+            Cell::Floor
+        }
+    }
+
+    pub fn is_wall(&self, x: u32, y: u32) -> bool {
+        self.cell(x, y) == Cell::Wall
+    }
+
+    /// Coordinates of every wall cell
+    pub fn walls(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.height)
+            .flat_map(move |y| (0..self.width).map(move |x| (x, y)))
+            .filter(|(x, y)| self.is_wall(*x, *y))
+    }
+
+    /// World position of the center of a cell
+    pub fn cell_center(&self, x: u32, y: u32) -> Vec2 {
+        self.origin + (Vec2::new(x as f32, y as f32) + 0.5) * CELL_SIZE
+    }
+
+    /// The area covered by the level in world coordinates
+    pub fn bounds(&self) -> Rect {
+        let size = Vec2::new(self.width as f32, self.height as f32) * CELL_SIZE;
+        Rect::from_corners(self.origin, self.origin + size)
+    }
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    Parse(serde_json::Error),
+    MissingLevel(usize),
+    MissingLayer(&'static str),
+    /// The level doesn't use the same cell size as the rest of the game
+    CellSize(i32),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Parse(e) => write!(f, "could not parse the LDtk project: {e}"),
+            LevelError::MissingLevel(index) => write!(f, "the LDtk project has no level {index}"),
+            LevelError::MissingLayer(layer) => write!(f, "the level has no {layer} layer"),
+            LevelError::CellSize(size) => write!(
+                f,
+                "the level cells are {size} pixels wide instead of {CELL_SIZE}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LevelError {}
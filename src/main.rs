@@ -1,110 +1,240 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(not(target_family = "wasm"))]
+use std::path::PathBuf;
 
 use bevy::{
     log::{Level, LogPlugin},
     prelude::*,
     scene::ron,
-    window::PresentMode,
 };
 use bevy_ecs_ldtk::prelude::*;
 use clap::Parser;
+#[cfg(not(target_family = "wasm"))]
+use embedded_server::EmbeddedServer;
 use iyes_perf_ui::{PerfUiCompleteBundle, PerfUiPlugin};
+use level::LevelGrid;
 use lightyear::{
     connection::netcode::ClientId,
     shared::log::add_log_layer,
     transport::{io::TransportConfig, LOCAL_SOCKET},
 };
+use manifest::AssetManifest;
+#[cfg(not(target_family = "wasm"))]
+use networking::keys::{self, KeyFile};
 use networking::{
-    client::ClientPluginGroup, server::ServerPluginGroup, ClientSettings, ClientTransports,
-    ServerTransports, Settings,
+    client::ClientPluginGroup,
+    random_client_id,
+    server::{HostClient, ServerPluginGroup},
+    ClientSettings, ClientTransports, DebugPalette, Listener, NetworkError, RngRole,
+    ServerSettings, ServerTransports, SessionRng, Settings, HEADLESS_CLIENT_ENV,
 };
-use wall::WallBundle;
 
+mod collision;
+#[cfg(not(target_family = "wasm"))]
+mod embedded_server;
+mod fixed_point;
+mod level;
+mod manifest;
+mod minimap;
 mod networking;
 mod player;
 mod wall;
+#[cfg(target_family = "wasm")]
+mod web_storage;
 
 #[derive(Parser, PartialEq, Debug)]
 enum Cli {
     #[cfg(not(target_family = "wasm"))]
     /// The program will act both as a server and as a client
-    ListenServer,
+    ListenServer {
+        /// Tells apart the clients started with the same `rng_seed`, which would draw the same id otherwise
+        #[arg(long, default_value_t = 0)]
+        instance: u32,
+    },
     #[cfg(not(target_family = "wasm"))]
     /// Dedicated server
     Server,
     /// The program will act as a client
-    Client,
+    Client {
+        /// Tells apart the clients started with the same `rng_seed`, which would draw the same id otherwise
+        #[arg(long, default_value_t = 0)]
+        instance: u32,
+    },
+    #[cfg(not(target_family = "wasm"))]
+    /// Generate a random private key and protocol id
+    GenKeys {
+        /// Write them to a key file to be loaded via `MATRIX_RPG_KEY_FILE` instead of printing them
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
 }
 
 fn main() {
     cfg_if::cfg_if! {
         if #[cfg(target_family = "wasm")] {
-            let client_id = rand::random::<u64>();
-            let cli = Cli::Client {
-                client_id: Some(client_id)
-            };
+            let cli = Cli::Client { instance: 0 };
         } else {
             let cli = Cli::parse();
         }
     }
-    let settings_str = include_str!("../assets/settings.ron");
-    let settings = ron::de::from_str::<Settings>(settings_str).unwrap();
+    let settings = load_settings().unwrap_or_else(|e| fail(e));
     run(settings, cli);
 }
 
+/// Parse the embedded settings, apply the overrides from the environment and check them
+fn load_settings() -> Result<Settings, NetworkError> {
+    let settings_str = include_str!("../assets/settings.ron");
+    #[allow(unused_mut)]
+    let mut settings = ron::de::from_str::<Settings>(settings_str)?;
+    // the embedded key is only meant for local development, deployments provide their own
+    #[cfg(not(target_family = "wasm"))]
+    settings.shared.load_key_overrides()?;
+    #[cfg(not(target_family = "wasm"))]
+    if std::env::var_os(HEADLESS_CLIENT_ENV).is_some() {
+        settings.client.headless = true;
+    }
+    // a browser can't write to disk, so the player's preferences are kept in local storage instead
+    #[cfg(target_family = "wasm")]
+    {
+        web_storage::load_client_preferences(&mut settings.client);
+        web_storage::save_client_preferences(&settings.client);
+    }
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Give up on an error that keeps the game from starting
+fn fail(e: NetworkError) -> ! {
+    cfg_if::cfg_if! {
+        if #[cfg(target_family = "wasm")] {
+            // there is no process to exit in a browser, the panic ends up in the console
+            panic!("{e}");
+        } else {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
 fn run(settings: Settings, cli: Cli) {
     match cli {
         #[cfg(not(target_family = "wasm"))]
-        Cli::ListenServer => {
-            // create client app
-            let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
-            let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
-            let transport_config = TransportConfig::LocalChannel {
-                recv: from_server_recv,
-                send: to_server_send,
-            };
+        Cli::ListenServer { instance } => {
+            let mut rng = SessionRng::new(settings.shared.rng_seed, RngRole::Client, instance);
+            let client_id = random_client_id(&mut rng.0);
+            let (mut server, transport_config) = EmbeddedServer::new(settings.clone(), client_id);
             // when communicating via channels, we need to use the address `LOCAL_SOCKET` for the server
-            let client_id = rand::random::<u64>();
             let mut client_app =
-                client_app(settings.clone(), LOCAL_SOCKET, client_id, transport_config);
-
-            // create server app
-            let extra_transport_configs = vec![TransportConfig::Channels {
-                // even if we communicate via channels, we need to provide a socket address for the client
-                channels: vec![(LOCAL_SOCKET, to_server_recv, from_server_send)],
-            }];
-            let mut server_app = server_app(settings, extra_transport_configs);
+                client_app(settings, LOCAL_SOCKET, client_id, transport_config, rng);
 
-            // run both the client and server apps
-            std::thread::spawn(move || server_app.run());
+            // the first update sets the server up, it is then ready for the client to connect
+            server.update();
+            server.start();
             client_app.run();
+            server.stop();
         }
         #[cfg(not(target_family = "wasm"))]
         Cli::Server => {
-            let mut app = server_app(settings, vec![]);
+            let mut app = server_app(settings, vec![], None);
             app.run();
         }
-        Cli::Client => {
-            let server_addr = SocketAddr::new(
-                settings.client.server_addr.into(),
-                settings.client.server_port,
-            );
-            let transport_config = get_client_transport_config(settings.client.clone());
-            let client_id = rand::random::<u64>();
-            let mut app = client_app(settings, server_addr, client_id, transport_config);
+        Cli::Client { instance } => {
+            let mut rng = SessionRng::new(settings.shared.rng_seed, RngRole::Client, instance);
+            let server_addr =
+                SocketAddr::new(settings.client.server_addr, settings.client.server_port);
+            let transport_config =
+                get_client_transport_config(settings.client.clone()).unwrap_or_else(|e| fail(e));
+            cfg_if::cfg_if! {
+                if #[cfg(target_family = "wasm")] {
+                    // reuse the id from previous visits so that a reload doesn't look like a new player
+                    let client_id = web_storage::load_or_create_client_id(&mut rng.0);
+                } else {
+                    let client_id = random_client_id(&mut rng.0);
+                }
+            }
+            let mut app = client_app(settings, server_addr, client_id, transport_config, rng);
             app.run();
         }
+        #[cfg(not(target_family = "wasm"))]
+        Cli::GenKeys { out } => gen_keys(out),
     }
 }
 
-/// Build the client app
+/// Generate new network keys and either print them or write them to a key file
+#[cfg(not(target_family = "wasm"))]
+fn gen_keys(out: Option<PathBuf>) {
+    let (protocol_id, private_key) = keys::generate_keys();
+    if let Some(path) = out {
+        KeyFile::new(protocol_id, &private_key)
+            .write(&path)
+            .expect("failed to write the key file");
+        println!("Wrote key file to {}", path.display());
+        return;
+    }
+    let bytes: Vec<String> = private_key.iter().map(u8::to_string).collect();
+    println!("// settings.ron");
+    println!("protocol_id: {},", protocol_id);
+    println!("private_key: ({}),", bytes.join(", "));
+    println!();
+    println!("// environment");
+    println!("{}={}", keys::PROTOCOL_ID_ENV, protocol_id);
+    println!(
+        "{}={}",
+        keys::PRIVATE_KEY_ENV,
+        keys::encode_private_key(&private_key)
+    );
+}
+
+/// Build the client app, `rng` is the one the client id was drawn from
 fn client_app(
     settings: Settings,
     server_addr: SocketAddr,
     client_id: ClientId,
     transport_config: TransportConfig,
+    rng: SessionRng,
 ) -> App {
     let mut app = App::new();
+    if settings.client.headless {
+        headless_client_plugins(&mut app);
+    } else {
+        render_client_plugins(&mut app, &settings);
+    }
+    app.insert_resource(settings.client.clone());
+    app.insert_resource(settings.client.animations.clone());
+    app.insert_resource(rng);
+    app.insert_resource(settings.shared);
+    app.insert_resource(ldtk_level_grid());
+    let client_plugin_group = ClientPluginGroup::new(
+        // use the cli-provided client id if it exists, otherwise use the settings client id
+        client_id,
+        server_addr,
+        transport_config,
+        settings.shared,
+    );
+    app.add_plugins(client_plugin_group.build());
+    app
+}
+
+/// The networking and the game logic of the client, without a window to show them in
+fn headless_client_plugins(app: &mut App) {
+    // NOTE: create the task pools first, like the default plugins do
+    app.add_plugins(MinimalPlugins);
+    app.add_plugins(LogPlugin {
+        level: Level::INFO,
+        filter: "wgpu=error,bevy_render=info".to_string(),
+        update_subscriber: Some(add_log_layer),
+    });
+    // the inputs can still be pressed by hand, and the window events are read when shutting down
+    app.add_plugins(bevy::input::InputPlugin);
+    app.add_plugins(WindowPlugin {
+        primary_window: None,
+        exit_condition: bevy::window::ExitCondition::DontExit,
+        close_when_requested: false,
+    });
+}
+
+/// Everything the client shows: the window, the level, the sprites and the debug overlays
+fn render_client_plugins(app: &mut App, settings: &Settings) {
     // NOTE: create the default plugins first so that the async task pools are initialized
     // use the default bevy logger for now
     // (the lightyear logger doesn't handle wasm)
@@ -119,43 +249,39 @@ fn client_app(
             .set(WindowPlugin {
                 primary_window: Some(Window {
                     title: "Matrix RPG".into(),
-                    present_mode: PresentMode::AutoVsync,
+                    present_mode: settings.client.present_mode.into(),
                     prevent_default_event_handling: false,
                     ..default()
                 }),
+                // we close the window ourselves once we told the server we're leaving
+                close_when_requested: false,
                 ..default()
             })
             .set(ImagePlugin::default_nearest()),
     );
     if settings.client.inspector {
-        app.add_plugins(PerfUiPlugin);
+        add_perf_ui(app, settings);
     }
     app.add_plugins(LdtkPlugin)
         .insert_resource(LevelSelection::index(0))
         .insert_resource(LdtkSettings {
             set_clear_color: SetClearColor::FromLevelBackground,
+            // where `LevelGrid::from_ldtk` expects the level to be
+            level_spawn_behavior: LevelSpawnBehavior::UseZeroTranslation,
             ..Default::default()
         })
-        .add_systems(Startup, move |mut commands: Commands| {
-            if settings.client.inspector {
-                commands.spawn(PerfUiCompleteBundle::default());
-            }
-        })
-        .register_ldtk_int_cell::<WallBundle>(1)
-        .add_plugins(player::PlayerPlugin);
-    let client_plugin_group = ClientPluginGroup::new(
-        // use the cli-provided client id if it exists, otherwise use the settings client id
-        client_id,
-        server_addr,
-        transport_config,
-        settings.shared,
-    );
-    app.add_plugins(client_plugin_group.build());
-    app
+        .add_plugins(wall::TerrainPlugin)
+        .add_plugins(player::PlayerPlugin)
+        .add_plugins(minimap::MinimapPlugin)
+        .add_plugins(manifest::ManifestPlugin(asset_manifest()));
 }
 
-/// Build the server app
-fn server_app(settings: Settings, extra_transport_configs: Vec<TransportConfig>) -> App {
+/// Build the server app, `host` is the client running in the same process for a listen-server
+fn server_app(
+    settings: Settings,
+    extra_transport_configs: Vec<TransportConfig>,
+    host: Option<ClientId>,
+) -> App {
     let mut app = App::new();
     if !settings.server.headless {
         app.add_plugins(DefaultPlugins.build().disable::<LogPlugin>());
@@ -168,44 +294,516 @@ fn server_app(settings: Settings, extra_transport_configs: Vec<TransportConfig>)
         update_subscriber: Some(add_log_layer),
     });
 
-    if settings.server.inspector {
-        app.add_plugins(PerfUiPlugin);
+    add_server_inspector(&mut app, &settings);
+    app.insert_resource(settings.server.clone());
+    app.insert_resource(settings.shared);
+    app.insert_resource(SessionRng::new(
+        settings.shared.rng_seed,
+        RngRole::Server,
+        0,
+    ));
+    // replaced on startup when generating the level
+    app.insert_resource(ldtk_level_grid());
+    if let Some(host) = host {
+        app.insert_resource(HostClient(host));
     }
-    app.add_systems(Startup, move |mut commands: Commands| {
-        if settings.client.inspector {
-            commands.spawn(PerfUiCompleteBundle::default());
-        }
-    });
-    let mut transport_configs = get_server_transport_configs(settings.server.transport);
+    let (mut transport_configs, reserved_ports) =
+        get_server_transport_configs(&settings.server).unwrap_or_else(|e| fail(e));
     transport_configs.extend(extra_transport_configs);
     let server_plugin_group = ServerPluginGroup::new(transport_configs, settings.shared);
+    // lightyear binds its transports when the plugins are built, the ports wait for it until then
+    drop(reserved_ports);
     app.add_plugins(server_plugin_group.build());
     app
 }
 
-/// Parse the server transport settings into a list of `TransportConfig` that are used to configure the lightyear server
-fn get_server_transport_configs(settings: Vec<ServerTransports>) -> Vec<TransportConfig> {
+/// The assets the client preloads, it can't run without them so exit right away if one is missing
+fn asset_manifest() -> AssetManifest {
+    let manifest = AssetManifest::load();
+    #[cfg(not(target_family = "wasm"))]
+    let manifest = manifest.and_then(|manifest| manifest.validate().map(|()| manifest));
+    manifest.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    })
+}
+
+/// Show the performance overlay
+fn add_perf_ui(app: &mut App, settings: &Settings) {
+    app.add_plugins(PerfUiPlugin);
+    app.insert_resource(debug_palette(settings));
+    app.add_systems(Startup, spawn_perf_ui);
+}
+
+/// Show the performance overlay on a server with `server.inspector`, if it has a window
+fn add_server_inspector(app: &mut App, settings: &Settings) {
+    if settings.server.inspector && settings.server.headless {
+        warn!("The inspector needs a window, it is disabled on a headless server");
+    } else if settings.server.inspector {
+        add_perf_ui(app, settings);
+    }
+}
+
+/// Only added along with the `PerfUiPlugin`, the bundle needs it
+fn spawn_perf_ui(mut commands: Commands) {
+    commands.spawn(PerfUiCompleteBundle::default());
+}
+
+/// The walls and bounds of the LDtk level, the same on the client and the server.
+/// Nothing can be played without it, so exit right away if it is broken.
+fn ldtk_level_grid() -> LevelGrid {
+    LevelGrid::from_ldtk(0).unwrap_or_else(|e| {
+        eprintln!("failed to load the level: {e}");
+        std::process::exit(1);
+    })
+}
+
+fn debug_palette(settings: &Settings) -> DebugPalette {
     settings
+        .debug_colors
+        .palette()
+        .expect("the debug colors are validated on startup")
+}
+
+/// Parse the server transport settings into a list of `TransportConfig` that are used to configure the lightyear server.
+/// Also returns the loopback ports reserved for the WebSocket servers behind TLS.
+fn get_server_transport_configs(
+    settings: &ServerSettings,
+) -> Result<(Vec<TransportConfig>, Vec<networking::tls::ReservedPort>), NetworkError> {
+    let bind_addr = settings.bind_addr;
+    let mut reserved_ports = vec![];
+    let transport_configs = settings
+        .transport
         .iter()
         .map(|t| match t {
-            ServerTransports::Udp { local_port } => TransportConfig::UdpSocket(SocketAddr::new(
-                Ipv4Addr::UNSPECIFIED.into(),
-                *local_port,
-            )),
-            ServerTransports::WebSocket { local_port } => TransportConfig::WebSocketServer {
-                server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *local_port),
-            },
+            ServerTransports::Udp { local_port } => {
+                let addr = check_bind(Listener::Udp, SocketAddr::new(bind_addr, *local_port))?;
+                Ok(TransportConfig::UdpSocket(addr))
+            }
+            ServerTransports::WebSocket {
+                local_port,
+                tls: None,
+            } => {
+                let addr =
+                    check_bind(Listener::WebSocket, SocketAddr::new(bind_addr, *local_port))?;
+                Ok(TransportConfig::WebSocketServer { server_addr: addr })
+            }
+            ServerTransports::WebSocket {
+                local_port,
+                tls: Some(tls),
+            } => {
+                // TLS is terminated in front of the WebSocket server, which then only listens on loopback
+                let public_addr = check_bind(
+                    Listener::SecureWebSocket,
+                    SocketAddr::new(bind_addr, *local_port),
+                )?;
+                let (server_addr, reserved_port) =
+                    networking::tls::spawn_tls_proxy(public_addr, tls)
+                        .map_err(NetworkError::Tls)?;
+                reserved_ports.push(reserved_port);
+                Ok(TransportConfig::WebSocketServer { server_addr })
+            }
         })
-        .collect()
+        .collect::<Result<_, NetworkError>>()?;
+    Ok((transport_configs, reserved_ports))
+}
+
+/// Make sure a listener can bind `addr`, and log where it listens. A port 0 gets the one the OS picked.
+/// The socket is closed right away, lightyear (or the TLS proxy) then binds the same address.
+fn check_bind(listener: Listener, addr: SocketAddr) -> Result<SocketAddr, NetworkError> {
+    let bound = match listener {
+        Listener::Udp => std::net::UdpSocket::bind(addr).and_then(|socket| socket.local_addr()),
+        Listener::WebSocket | Listener::SecureWebSocket => {
+            std::net::TcpListener::bind(addr).and_then(|listener| listener.local_addr())
+        }
+    };
+    let bound = bound.map_err(|error| NetworkError::Bind {
+        listener,
+        addr,
+        error,
+    })?;
+    let interfaces = match bound.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => " (every IPv4 interface)",
+        IpAddr::V6(ip) if ip.is_unspecified() => " (every interface)",
+        _ => "",
+    };
+    info!("Listening for {} on {}{}", listener, bound, interfaces);
+    Ok(bound)
 }
 
 /// Parse the client transport settings into a `TransportConfig` that is used to configure the lightyear client
-fn get_client_transport_config(settings: ClientSettings) -> TransportConfig {
-    let server_addr = SocketAddr::new(settings.server_addr.into(), settings.server_port);
-    let client_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), settings.client_port);
-    match settings.transport {
+fn get_client_transport_config(settings: ClientSettings) -> Result<TransportConfig, NetworkError> {
+    let server_addr = SocketAddr::new(settings.server_addr, settings.server_port);
+    // the client socket must be of the same family as the server's address to reach it
+    let any_interface: IpAddr = match settings.server_addr {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let client_addr = SocketAddr::new(any_interface, settings.client_port);
+    Ok(match settings.transport {
         #[cfg(not(target_family = "wasm"))]
         ClientTransports::Udp => TransportConfig::UdpSocket(client_addr),
         ClientTransports::WebSocket => TransportConfig::WebSocketClient { server_addr },
+        // lightyear only speaks ws://, it goes through a local proxy adding the TLS
+        #[cfg(not(target_family = "wasm"))]
+        ClientTransports::SecureWebSocket { tls } => TransportConfig::WebSocketClient {
+            server_addr: networking::tls::spawn_tls_client_proxy(server_addr, &tls)
+                .map_err(NetworkError::Tls)?,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::Duration;
+    use lightyear::prelude::client::{Interpolated, MessageEvent};
+    use lightyear::prelude::server::ServerConnectionManager;
+    use lightyear::prelude::{NetworkTarget, Tick};
+
+    use super::*;
+    use crate::networking::protocol::{
+        BulkChannel, PlayerId, PlayerPosition, PlayerSlot, PlayerSnapshot, Replicate, WorldSnapshot,
+    };
+    use crate::networking::server::ConnectedPlayers;
+
+    /// How long the in-process server and client get to do what a test waits for
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The embedded settings, without windows or sockets
+    fn test_settings() -> Settings {
+        let mut settings = Settings::embedded();
+        settings.server.headless = true;
+        settings.server.inspector = false;
+        settings.server.transport.clear();
+        settings.client.headless = true;
+        settings
+    }
+
+    /// A server and headless clients with the given ids, talking through in-memory channels
+    fn local_server_and_clients(settings: Settings, client_ids: &[ClientId]) -> (App, Vec<App>) {
+        let mut server_channels = vec![];
+        let mut clients = vec![];
+        for (i, client_id) in client_ids.iter().enumerate() {
+            let (from_server_send, from_server_recv) = crossbeam_channel::unbounded();
+            let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+            // the server tells the clients apart by the address of their channel
+            let client_addr = SocketAddr::new(LOCAL_SOCKET.ip(), LOCAL_SOCKET.port() + i as u16);
+            server_channels.push((client_addr, to_server_recv, from_server_send));
+            let client_transport = TransportConfig::LocalChannel {
+                recv: from_server_recv,
+                send: to_server_send,
+            };
+            let rng = SessionRng::new(settings.shared.rng_seed, RngRole::Client, i as u32);
+            let mut client = client_app(
+                settings.clone(),
+                LOCAL_SOCKET,
+                *client_id,
+                client_transport,
+                rng,
+            );
+            embedded_server::finish_plugins(&mut client);
+            clients.push(client);
+        }
+        let server_transport = TransportConfig::Channels {
+            channels: server_channels,
+        };
+        let mut server = server_app(settings, vec![server_transport], None);
+        embedded_server::finish_plugins(&mut server);
+        (server, clients)
+    }
+
+    /// A server and a headless client talking through in-memory channels, like a listen-server
+    fn local_apps(settings: Settings) -> (App, App) {
+        let (server, mut clients) = local_server_and_clients(settings, &[1]);
+        (server, clients.remove(0))
+    }
+
+    /// Update the server and all the clients until `done` holds
+    fn update_all_until(
+        server: &mut App,
+        clients: &mut [App],
+        mut done: impl FnMut(&App, &[App]) -> bool,
+    ) {
+        let start = std::time::Instant::now();
+        while !done(server, clients) {
+            assert!(start.elapsed() < TEST_TIMEOUT, "timed out");
+            server.update();
+            for client in clients.iter_mut() {
+                client.update();
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    /// Update both apps until `done` holds
+    fn update_until(server: &mut App, client: &mut App, mut done: impl FnMut(&App, &App) -> bool) {
+        update_all_until(server, std::slice::from_mut(client), |server, clients| {
+            done(server, &clients[0])
+        });
+    }
+
+    /// Keep updating all the apps for a while, to see that something doesn't happen
+    fn update_for(server: &mut App, clients: &mut [App], duration: Duration) {
+        let start = std::time::Instant::now();
+        update_all_until(server, clients, |_, _| start.elapsed() >= duration);
+    }
+
+    fn connect(server: &mut App, client: &mut App) {
+        update_until(server, client, |server, _| {
+            server.world.resource::<ConnectedPlayers>().count() == 1
+        });
+    }
+
+    #[derive(Resource, Default)]
+    struct ReceivedSnapshots(Vec<WorldSnapshot>);
+
+    fn record_snapshots(
+        mut snapshots: EventReader<MessageEvent<WorldSnapshot>>,
+        mut received: ResMut<ReceivedSnapshots>,
+    ) {
+        received
+            .0
+            .extend(snapshots.read().map(|snapshot| snapshot.message().clone()));
+    }
+
+    #[test]
+    fn bulk_payload_larger_than_a_packet_arrives_intact() {
+        let (mut server, mut client) = local_apps(test_settings());
+        client.init_resource::<ReceivedSnapshots>();
+        client.add_systems(Update, record_snapshots);
+        connect(&mut server, &mut client);
+
+        let players = (0..200)
+            .map(|i| PlayerSnapshot {
+                client_id: 1000 + i,
+                slot: 0,
+                position: Vec2::new(i as f32, -(i as f32)),
+                color: Some(Color::rgb(0.1, 0.2, 0.3)),
+                health: Some(i as u32),
+            })
+            .collect();
+        let snapshot = WorldSnapshot {
+            tick: Tick(0),
+            players,
+        };
+        // lightyear's packets carry about 1200 bytes
+        assert!(bitcode::serialize(&snapshot).unwrap().len() > 4 * 1200);
+        server
+            .world
+            .resource_mut::<ServerConnectionManager>()
+            .send_message_to_target::<BulkChannel, WorldSnapshot>(
+                snapshot.clone(),
+                NetworkTarget::All,
+            )
+            .unwrap();
+
+        // the server also sends its own snapshot on connection
+        let is_sent = |received: &WorldSnapshot| received.players.len() == 200;
+        update_until(&mut server, &mut client, |_, client| {
+            client
+                .world
+                .resource::<ReceivedSnapshots>()
+                .0
+                .iter()
+                .any(is_sent)
+        });
+        let received = client.world.resource::<ReceivedSnapshots>();
+        assert_eq!(received.0.iter().find(|s| is_sent(s)), Some(&snapshot));
+    }
+
+    fn perf_uis(app: &mut App) -> usize {
+        app.world
+            .query_filtered::<(), With<iyes_perf_ui::PerfUiRoot>>()
+            .iter(&app.world)
+            .count()
+    }
+
+    #[test]
+    fn server_inspector_shows_the_perf_ui() {
+        for inspector in [false, true] {
+            let mut settings = test_settings();
+            settings.server.headless = false;
+            // the client's flag has no say on the server
+            settings.client.inspector = !inspector;
+            settings.server.inspector = inspector;
+            let mut app = App::new();
+            add_server_inspector(&mut app, &settings);
+            app.world.run_schedule(Startup);
+            assert_eq!(app.is_plugin_added::<PerfUiPlugin>(), inspector);
+            assert_eq!(perf_uis(&mut app), inspector as usize);
+        }
+    }
+
+    #[test]
+    fn headless_server_has_no_perf_ui() {
+        let mut settings = test_settings();
+        settings.server.inspector = true;
+        let mut app = server_app(settings, vec![], None);
+        embedded_server::finish_plugins(&mut app);
+        app.update();
+        assert!(!app.is_plugin_added::<PerfUiPlugin>());
+        assert_eq!(perf_uis(&mut app), 0);
+    }
+
+    #[test]
+    fn headless_client_plays_without_a_renderer() {
+        let mut settings = test_settings();
+        settings.client.auto_spawn = true;
+        let (mut server, mut client) = local_apps(settings);
+        assert!(!client.is_plugin_added::<bevy::render::RenderPlugin>());
+        connect(&mut server, &mut client);
+
+        // the automatic spawn goes through the whole networking stack
+        update_until(&mut server, &mut client, |server, _| {
+            server.world.iter_entities().any(|entity| {
+                entity.get::<PlayerId>() == Some(&PlayerId(1)) && entity.contains::<Replicate>()
+            })
+        });
+    }
+
+    #[test]
+    fn port_conflict_is_a_bind_error() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let Err(e) = check_bind(Listener::Udp, addr) else {
+            panic!("bound {} twice", addr);
+        };
+        assert!(
+            matches!(&e, NetworkError::Bind { listener: Listener::Udp, addr: bound, .. } if *bound == addr)
+        );
+        assert_eq!(
+            e.to_string(),
+            format!("could not listen for UDP on {addr}: the port is already in use, is another server running?")
+        );
+
+        // a free port is fine, and port 0 gets the one the OS picked
+        let any_port = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let bound = check_bind(Listener::WebSocket, any_port).unwrap();
+        assert_ne!(bound.port(), 0);
+    }
+
+    #[test]
+    fn server_transports_report_port_conflicts() {
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut settings = test_settings().server;
+        settings.bind_addr = Ipv4Addr::LOCALHOST.into();
+        settings.transport = vec![ServerTransports::Udp {
+            local_port: taken.local_addr().unwrap().port(),
+        }];
+        assert!(matches!(
+            get_server_transport_configs(&settings),
+            Err(NetworkError::Bind {
+                listener: Listener::Udp,
+                ..
+            })
+        ));
+    }
+
+    fn server_players(server: &mut App, client_id: ClientId) -> Vec<Entity> {
+        server
+            .world
+            .query::<(Entity, &PlayerId)>()
+            .iter(&server.world)
+            .filter(|(_, player_id)| player_id.0 == client_id)
+            .map(|(entity, _)| entity)
+            .collect()
+    }
+
+    #[test]
+    fn second_client_with_a_live_id_is_ignored() {
+        let mut settings = test_settings();
+        settings.client.auto_spawn = true;
+        let (mut server, mut clients) = local_server_and_clients(settings, &[1, 1]);
+        update_all_until(&mut server, &mut clients, |server, _| {
+            server.world.iter_entities().any(|entity| {
+                entity.get::<PlayerId>() == Some(&PlayerId(1)) && entity.contains::<Replicate>()
+            })
+        });
+        let player = server_players(&mut server, 1);
+
+        // netcode ignores the second client, the first one keeps playing
+        update_for(&mut server, &mut clients, Duration::from_millis(500));
+        assert_eq!(
+            server.world.resource::<ConnectedPlayers>().client_ids(),
+            &[1]
+        );
+        assert_eq!(server_players(&mut server, 1), player);
+    }
+
+    #[test]
+    fn player_left_behind_is_replaced_on_connection() {
+        let (mut server, mut client) = local_apps(test_settings());
+        // left by a connection whose disconnection was never handled
+        let stale = server
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::new(40.0, 50.0)),
+            ))
+            .id();
+        connect(&mut server, &mut client);
+
+        update_until(&mut server, &mut client, |server, _| {
+            server.world.get_entity(stale).is_none()
+        });
+        assert!(server.world.resource::<ConnectedPlayers>().contains(1));
+    }
+
+    /// Headless clients have no sprites, give the other players the transform their sprite would get
+    fn attach_test_sprites(
+        mut commands: Commands,
+        players: Query<(Entity, &PlayerPosition), Added<Interpolated>>,
+        settings: Res<ClientSettings>,
+    ) {
+        for (entity, position) in players.iter() {
+            commands.entity(entity).insert(Transform::from_xyz(
+                position.x,
+                position.y,
+                settings.sprite_z,
+            ));
+        }
+    }
+
+    /// Where a client draws the player of another client
+    fn remote_sprite(client: &App, client_id: ClientId) -> Option<Vec3> {
+        client
+            .world
+            .iter_entities()
+            .filter(|entity| entity.get::<PlayerId>() == Some(&PlayerId(client_id)))
+            .filter(|entity| entity.contains::<Interpolated>())
+            .find_map(|entity| entity.get::<Transform>())
+            .map(|transform| transform.translation)
+    }
+
+    #[test]
+    fn remote_sprites_follow_the_replicated_position() {
+        let mut settings = test_settings();
+        settings.client.auto_spawn = true;
+        let sprite_z = settings.client.sprite_z;
+        let (mut server, mut clients) = local_server_and_clients(settings, &[1, 2]);
+        clients[1].add_systems(Update, attach_test_sprites);
+        update_all_until(&mut server, &mut clients, |_, clients| {
+            remote_sprite(&clients[1], 1).is_some()
+        });
+
+        // the server moves the first player away from the spawn point
+        let level = server.world.resource::<LevelGrid>();
+        let target = collision::nearest_free_position(level, Vec2::new(300.0, 200.0)).unwrap();
+        let mut players = server
+            .world
+            .query_filtered::<(&PlayerId, &mut PlayerPosition), With<Replicate>>();
+        for (player_id, mut position) in players.iter_mut(&mut server.world) {
+            if player_id.0 == 1 {
+                position.0 = target;
+            }
+        }
+
+        update_all_until(&mut server, &mut clients, |_, clients| {
+            remote_sprite(&clients[1], 1)
+                .is_some_and(|sprite| sprite.truncate().distance(target) < 0.01)
+        });
+        // still drawn at the same depth
+        assert_eq!(remote_sprite(&clients[1], 1).unwrap().z, sprite_z);
     }
 }
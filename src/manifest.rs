@@ -0,0 +1,143 @@
+//! The assets listed in `assets/manifest.ron`, all loaded on startup so that nothing gets loaded
+//! in the middle of the game.
+
+use std::fmt;
+
+use bevy::asset::UntypedAssetId;
+use bevy::prelude::*;
+use bevy::scene::ron;
+use bevy_ecs_ldtk::assets::LdtkProject;
+use serde::Deserialize;
+
+/// Embedded like the settings, so that the web client doesn't have to fetch it first
+const MANIFEST: &str = include_str!("../assets/manifest.ron");
+
+/// Paths of the assets to preload, relative to the assets folder
+#[derive(Clone, Debug, Deserialize)]
+pub struct AssetManifest {
+    pub tilesets: Vec<String>,
+    pub levels: Vec<String>,
+    #[serde(default)]
+    pub audio: Vec<String>,
+}
+
+impl AssetManifest {
+    pub fn load() -> Result<Self, ManifestError> {
+        ron::from_str(MANIFEST).map_err(ManifestError::Parse)
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &String> {
+        self.tilesets.iter().chain(&self.levels).chain(&self.audio)
+    }
+
+    /// Check that every listed asset is in the assets folder.
+    /// The web client can't look at the files, the asset loading errors tell about them instead.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        // where bevy looks for the assets, which isn't always the working directory
+        let assets = bevy::asset::io::file::FileAssetReader::get_base_path().join("assets");
+        match self.paths().find(|path| !assets.join(path).is_file()) {
+            Some(path) => Err(ManifestError::Missing(path.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Parse(ron::error::SpannedError),
+    /// A listed asset isn't in the assets folder
+    Missing(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Parse(e) => write!(f, "could not parse the asset manifest: {e}"),
+            ManifestError::Missing(path) => {
+                write!(
+                    f,
+                    "the asset manifest lists {path}, which is not in the assets folder"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// Loads the assets of the manifest on startup
+pub struct ManifestPlugin(pub AssetManifest);
+
+impl Plugin for ManifestPlugin {
+    fn build(&self, app: &mut App) {
+        let manifest = self.0.clone();
+        app.add_systems(
+            Startup,
+            move |mut commands: Commands, asset_server: Res<AssetServer>| {
+                commands.insert_resource(ManifestHandles::load(&manifest, &asset_server));
+            },
+        );
+        app.add_systems(Update, log_manifest_loaded.run_if(manifest_loaded));
+    }
+}
+
+/// The handles of the manifest's assets, which keep them loaded for the whole game
+#[derive(Resource, Debug)]
+pub struct ManifestHandles {
+    pub tilesets: Vec<Handle<Image>>,
+    pub levels: Vec<Handle<LdtkProject>>,
+    pub audio: Vec<Handle<AudioSource>>,
+}
+
+impl ManifestHandles {
+    fn load(manifest: &AssetManifest, asset_server: &AssetServer) -> Self {
+        Self {
+            tilesets: manifest
+                .tilesets
+                .iter()
+                .map(|path| asset_server.load(path.clone()))
+                .collect(),
+            levels: manifest
+                .levels
+                .iter()
+                .map(|path| asset_server.load(path.clone()))
+                .collect(),
+            audio: manifest
+                .audio
+                .iter()
+                .map(|path| asset_server.load(path.clone()))
+                .collect(),
+        }
+    }
+
+    fn ids(&self) -> impl Iterator<Item = UntypedAssetId> + '_ {
+        let tilesets = self.tilesets.iter().map(|handle| handle.id().untyped());
+        let levels = self.levels.iter().map(|handle| handle.id().untyped());
+        let audio = self.audio.iter().map(|handle| handle.id().untyped());
+        tilesets.chain(levels).chain(audio)
+    }
+
+    pub fn is_loaded(&self, asset_server: &AssetServer) -> bool {
+        self.ids()
+            .all(|id| asset_server.is_loaded_with_dependencies(id))
+    }
+}
+
+/// Whether every asset of the manifest is loaded, for the systems that must wait for them
+pub fn manifest_loaded(
+    handles: Option<Res<ManifestHandles>>,
+    asset_server: Res<AssetServer>,
+) -> bool {
+    handles.is_some_and(|handles| handles.is_loaded(&asset_server))
+}
+
+fn log_manifest_loaded(handles: Res<ManifestHandles>, mut logged: Local<bool>) {
+    if !*logged {
+        *logged = true;
+        info!(
+            "Loaded the {} assets of the manifest",
+            handles.ids().count()
+        );
+    }
+}
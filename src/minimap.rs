@@ -0,0 +1,207 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_ecs_ldtk::prelude::*;
+use lightyear::client::components::Confirmed;
+use lightyear::prelude::client::Predicted;
+
+use crate::level::LevelGrid;
+use crate::networking::client::OwnInterpolated;
+use crate::networking::protocol::{PlayerColor, PlayerId, PlayerPosition};
+
+/// Plugin for the minimap in the corner of the screen, showing where every player is in the level.
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_minimap);
+        app.add_systems(
+            Update,
+            (toggle_minimap, fit_minimap_to_level, update_minimap_dots).chain(),
+        );
+    }
+}
+
+pub const TOGGLE_MINIMAP_KEY: KeyCode = KeyCode::KeyM;
+
+/// Width of the minimap in pixels, its height follows the shape of the level
+const MINIMAP_WIDTH: f32 = 200.0;
+const DOT_SIZE: f32 = 6.0;
+/// Our own players are drawn bigger and with a border so they stand out
+const LOCAL_DOT_SIZE: f32 = 10.0;
+
+#[derive(Component)]
+struct Minimap {
+    /// The part of the world covered by the minimap, known once the level is spawned
+    bounds: Option<Rect>,
+    shown: bool,
+}
+
+impl Minimap {
+    fn visibility(&self) -> Visibility {
+        if self.shown && self.bounds.is_some() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        }
+    }
+}
+
+/// The dot representing the given player on the minimap
+#[derive(Component)]
+struct MinimapDot(Entity);
+
+fn spawn_minimap(mut commands: Commands) {
+    let minimap = Minimap {
+        bounds: None,
+        shown: true,
+    };
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.0),
+                right: Val::Px(10.0),
+                width: Val::Px(MINIMAP_WIDTH),
+                height: Val::Px(MINIMAP_WIDTH),
+                ..default()
+            },
+            background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+            visibility: minimap.visibility(),
+            ..default()
+        },
+        minimap,
+    ));
+}
+
+fn toggle_minimap(
+    keypress: Res<ButtonInput<KeyCode>>,
+    mut minimaps: Query<(&mut Minimap, &mut Visibility)>,
+) {
+    if !keypress.just_pressed(TOGGLE_MINIMAP_KEY) {
+        return;
+    }
+    for (mut minimap, mut visibility) in minimaps.iter_mut() {
+        minimap.shown = !minimap.shown;
+        *visibility = minimap.visibility();
+    }
+}
+
+/// Cover the level that just got spawned, keeping its aspect ratio
+fn fit_minimap_to_level(
+    mut level_events: EventReader<LevelEvent>,
+    levels: Query<(&LevelIid, &Transform)>,
+    projects: Query<&Handle<LdtkProject>>,
+    project_assets: Res<Assets<LdtkProject>>,
+    generated_level: Option<Res<LevelGrid>>,
+    mut minimaps: Query<(&mut Minimap, &mut Style, &mut Visibility)>,
+) {
+    let mut fit = |bounds: Rect| {
+        for (mut minimap, mut style, mut visibility) in minimaps.iter_mut() {
+            minimap.bounds = Some(bounds);
+            style.height = Val::Px(MINIMAP_WIDTH * bounds.height() / bounds.width());
+            *visibility = minimap.visibility();
+        }
+    };
+    if let Some(grid) = generated_level.filter(|grid| grid.is_changed()) {
+        fit(grid.bounds());
+    }
+    for event in level_events.read() {
+        let LevelEvent::Transformed(iid) = event else {
+            continue;
+        };
+        let Some((_, transform)) = levels.iter().find(|(level_iid, _)| *level_iid == iid) else {
+            continue;
+        };
+        let Some(level) = projects
+            .iter()
+            .filter_map(|handle| project_assets.get(handle))
+            .find_map(|project| project.get_raw_level_by_iid(iid.get()))
+        else {
+            continue;
+        };
+        if level.px_wid <= 0 || level.px_hei <= 0 {
+            continue;
+        }
+        // levels are anchored at their bottom left corner
+        let origin = transform.translation.truncate();
+        let size = Vec2::new(level.px_wid as f32, level.px_hei as f32);
+        fit(Rect::from_corners(origin, origin + size));
+    }
+}
+
+/// Keep one dot per player, at its position in the level and in its color
+#[allow(clippy::type_complexity)]
+fn update_minimap_dots(
+    mut commands: Commands,
+    minimaps: Query<(Entity, &Minimap)>,
+    players: Query<
+        (
+            Entity,
+            &PlayerPosition,
+            Option<&PlayerColor>,
+            Has<Predicted>,
+        ),
+        (With<PlayerId>, Without<Confirmed>, Without<OwnInterpolated>),
+    >,
+    mut dots: Query<(Entity, &MinimapDot, &mut Style, &mut BackgroundColor)>,
+) {
+    let Ok((minimap_entity, minimap)) = minimaps.get_single() else {
+        return;
+    };
+    let Some(bounds) = minimap.bounds else {
+        return;
+    };
+    let mut placed = HashSet::new();
+    for (dot_entity, dot, mut style, mut background) in dots.iter_mut() {
+        let Ok((player, position, color, local)) = players.get(dot.0) else {
+            commands.entity(dot_entity).despawn_recursive();
+            continue;
+        };
+        place_dot(&mut style, bounds, position.0, local);
+        background.0 = color.map_or(Color::WHITE, |color| color.0);
+        placed.insert(player);
+    }
+    for (player, position, color, local) in players.iter() {
+        if placed.contains(&player) {
+            continue;
+        }
+        let mut style = Style {
+            position_type: PositionType::Absolute,
+            ..default()
+        };
+        place_dot(&mut style, bounds, position.0, local);
+        let dot = commands
+            .spawn((
+                NodeBundle {
+                    style,
+                    background_color: color.map_or(Color::WHITE, |color| color.0).into(),
+                    border_color: Color::WHITE.into(),
+                    ..default()
+                },
+                MinimapDot(player),
+            ))
+            .id();
+        commands.entity(minimap_entity).add_child(dot);
+    }
+}
+
+fn place_dot(style: &mut Style, bounds: Rect, position: Vec2, local: bool) {
+    // players outside of the level stick to the edge of the minimap
+    let relative = ((position - bounds.min) / bounds.size()).clamp(Vec2::ZERO, Vec2::ONE);
+    let size = if local { LOCAL_DOT_SIZE } else { DOT_SIZE };
+    style.left = Val::Percent(relative.x * 100.0);
+    style.bottom = Val::Percent(relative.y * 100.0);
+    style.width = Val::Px(size);
+    style.height = Val::Px(size);
+    // center the dot on the position
+    style.margin = UiRect {
+        left: Val::Px(-size / 2.0),
+        bottom: Val::Px(-size / 2.0),
+        ..default()
+    };
+    style.border = if local {
+        UiRect::all(Val::Px(2.0))
+    } else {
+        UiRect::DEFAULT
+    };
+}
@@ -0,0 +1,275 @@
+//! Estimated bandwidth used by each client, to spot the ones sending or receiving far more than the others.
+//!
+//! lightyear only measures the traffic of a whole transport, so the payloads are counted here instead:
+//! the inputs received from each client, and the messages and component updates sent to it. They are
+//! counted in the bitcode encoding lightyear sends them with, the packet headers, message ids and
+//! acknowledgements come on top.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
+use lightyear::connection::netcode::ClientId;
+use lightyear::prelude::server::*;
+use lightyear::prelude::{Channel, Message, NetworkTarget};
+use serde::Serialize;
+
+use super::protocol::{
+    Inputs, Knockback, PlayerColor, PlayerHealth, PlayerId, PlayerPosition, PlayerSlot,
+    PlayerVelocity, Replicate,
+};
+use super::server::ConnectedPlayers;
+use super::SharedSettings;
+
+/// Counts the traffic of each client, shown by the `bandwidth` admin command
+pub struct BandwidthPlugin;
+
+impl Plugin for BandwidthPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BandwidthStats>();
+        app.register_diagnostic(Diagnostic::new(BUSIEST_CLIENT_IN).with_suffix("B/s"));
+        app.register_diagnostic(Diagnostic::new(BUSIEST_CLIENT_OUT).with_suffix("B/s"));
+        app.add_systems(FixedUpdate, count_incoming_inputs);
+        app.add_systems(
+            Update,
+            (
+                count_outgoing_updates::<PlayerId>,
+                count_outgoing_updates::<PlayerSlot>,
+                count_outgoing_updates::<PlayerPosition>,
+                count_outgoing_updates::<PlayerVelocity>,
+                count_outgoing_updates::<PlayerColor>,
+                count_outgoing_updates::<PlayerHealth>,
+                count_outgoing_updates::<Knockback>,
+            )
+                .run_if(replication_send_due),
+        );
+        app.add_systems(
+            Update,
+            (forget_disconnected_clients, measure_busiest_clients),
+        );
+    }
+}
+
+/// What the server receives from the client sending the most, over the last [`BANDWIDTH_WINDOW`]
+pub const BUSIEST_CLIENT_IN: DiagnosticPath = DiagnosticPath::const_new("busiest_client_in");
+
+/// What the server sends to the client receiving the most, over the last [`BANDWIDTH_WINDOW`]
+pub const BUSIEST_CLIENT_OUT: DiagnosticPath = DiagnosticPath::const_new("busiest_client_out");
+
+/// How far back the recent rates look
+pub const BANDWIDTH_WINDOW: Duration = Duration::from_secs(5);
+
+/// Bytes exchanged with a single client
+#[derive(Default, Debug)]
+pub(crate) struct ClientBandwidth {
+    pub(crate) total_in: u64,
+    pub(crate) total_out: u64,
+    /// When each of the recent payloads was counted, with its incoming and outgoing size
+    recent: VecDeque<(Duration, u64, u64)>,
+}
+
+impl ClientBandwidth {
+    fn record(&mut self, now: Duration, bytes_in: u64, bytes_out: u64) {
+        self.total_in += bytes_in;
+        self.total_out += bytes_out;
+        self.recent.push_back((now, bytes_in, bytes_out));
+        while let Some((at, _, _)) = self.recent.front() {
+            if now.saturating_sub(*at) <= BANDWIDTH_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// Incoming and outgoing bytes per second over the last [`BANDWIDTH_WINDOW`]
+    pub(crate) fn rates(&self, now: Duration) -> (f64, f64) {
+        let (bytes_in, bytes_out) = self
+            .recent
+            .iter()
+            .filter(|(at, _, _)| now.saturating_sub(*at) <= BANDWIDTH_WINDOW)
+            .fold((0, 0), |(total_in, total_out), (_, bytes_in, bytes_out)| {
+                (total_in + bytes_in, total_out + bytes_out)
+            });
+        let window = BANDWIDTH_WINDOW.as_secs_f64();
+        (bytes_in as f64 / window, bytes_out as f64 / window)
+    }
+}
+
+/// Bandwidth of each connected client, forgotten when it disconnects
+#[derive(Resource, Default, Debug)]
+pub(crate) struct BandwidthStats(HashMap<ClientId, ClientBandwidth>);
+
+impl BandwidthStats {
+    pub(crate) fn clients(&self) -> impl Iterator<Item = (&ClientId, &ClientBandwidth)> {
+        self.0.iter()
+    }
+
+    pub(crate) fn get(&self, client_id: ClientId) -> Option<&ClientBandwidth> {
+        self.0.get(&client_id)
+    }
+}
+
+/// Size of a payload in the bitcode encoding lightyear sends it with
+fn payload_size(payload: &impl Serialize) -> u64 {
+    bitcode::serialize(payload).map_or(0, |bytes| bytes.len() as u64)
+}
+
+/// Sends messages like the [`ServerConnectionManager`], counting them in the outgoing traffic of
+/// their recipients
+#[derive(SystemParam)]
+pub(crate) struct CountedServer<'w> {
+    server: ResMut<'w, ServerConnectionManager>,
+    stats: ResMut<'w, BandwidthStats>,
+    connected_players: Res<'w, ConnectedPlayers>,
+    time: Res<'w, Time<Real>>,
+}
+
+impl CountedServer<'_> {
+    pub(crate) fn send_message_to_target<C: Channel, M: Message + Serialize>(
+        &mut self,
+        message: M,
+        target: NetworkTarget,
+    ) -> Result<(), impl fmt::Debug> {
+        let size = payload_size(&message);
+        let now = self.time.elapsed();
+        // a client that just connected may not be among the connected players yet
+        let recipients = match &target {
+            NetworkTarget::Only(client_ids) => client_ids.clone(),
+            NetworkTarget::Single(client_id) => vec![*client_id],
+            target => self
+                .connected_players
+                .client_ids()
+                .iter()
+                .filter(|client_id| target.should_send_to(client_id))
+                .copied()
+                .collect(),
+        };
+        for client_id in recipients {
+            self.stats
+                .0
+                .entry(client_id)
+                .or_default()
+                .record(now, 0, size);
+        }
+        self.server.send_message_to_target::<C, M>(message, target)
+    }
+}
+
+pub(crate) fn count_incoming_inputs(
+    time: Res<Time<Real>>,
+    mut inputs: EventReader<InputEvent<Inputs>>,
+    mut stats: ResMut<BandwidthStats>,
+) {
+    for input in inputs.read() {
+        let Some(payload) = input.input() else {
+            continue;
+        };
+        let size = payload_size(payload);
+        stats
+            .0
+            .entry(*input.context())
+            .or_default()
+            .record(time.elapsed(), size, 0);
+    }
+}
+
+/// Whether the component updates are being sent, they are only counted then
+fn replication_send_due(
+    time: Res<Time<Real>>,
+    shared_settings: Res<SharedSettings>,
+    mut timer: Local<Timer>,
+) -> bool {
+    let interval = Duration::from_millis(shared_settings.replication.position_interval_ms);
+    if timer.duration() != interval {
+        *timer = Timer::new(interval, TimerMode::Repeating);
+    }
+    timer.tick(time.delta()).just_finished()
+}
+
+/// Count the updates of a replicated component sent to each client
+pub(crate) fn count_outgoing_updates<C: Component + Serialize>(
+    time: Res<Time<Real>>,
+    connected_players: Res<ConnectedPlayers>,
+    players: Query<(Ref<C>, &Replicate)>,
+    mut stats: ResMut<BandwidthStats>,
+) {
+    // all the changes since the previous update are sent together, only the latest value of each
+    let changes: Vec<_> = players
+        .iter()
+        .filter(|(component, _)| component.is_changed())
+        .map(|(component, replicate)| (payload_size(&*component), replicate))
+        .collect();
+    for client_id in connected_players.client_ids() {
+        let size: u64 = changes
+            .iter()
+            .filter(|(_, replicate)| replicate.replication_target.should_send_to(client_id))
+            .map(|(size, _)| size)
+            .sum();
+        stats
+            .0
+            .entry(*client_id)
+            .or_default()
+            .record(time.elapsed(), 0, size);
+    }
+}
+
+pub(crate) fn forget_disconnected_clients(
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut stats: ResMut<BandwidthStats>,
+) {
+    for disconnection in disconnections.read() {
+        stats.0.remove(disconnection.context());
+    }
+}
+
+pub(crate) fn measure_busiest_clients(
+    time: Res<Time<Real>>,
+    stats: Res<BandwidthStats>,
+    mut diagnostics: Diagnostics,
+) {
+    let rates: Vec<(f64, f64)> = stats
+        .0
+        .values()
+        .map(|client| client.rates(time.elapsed()))
+        .collect();
+    let busiest_in = rates
+        .iter()
+        .map(|(bytes_in, _)| *bytes_in)
+        .fold(0.0, f64::max);
+    let busiest_out = rates
+        .iter()
+        .map(|(_, bytes_out)| *bytes_out)
+        .fold(0.0, f64::max);
+    diagnostics.add_measurement(&BUSIEST_CLIENT_IN, || busiest_in);
+    diagnostics.add_measurement(&BUSIEST_CLIENT_OUT, || busiest_out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_only_cover_the_window() {
+        let mut client = ClientBandwidth::default();
+        client.record(Duration::from_secs(1), 1000, 0);
+        client.record(Duration::from_secs(7), 500, 250);
+        // the first payload is older than the window by now
+        let (rate_in, rate_out) = client.rates(Duration::from_secs(8));
+        let window = BANDWIDTH_WINDOW.as_secs_f64();
+        assert_eq!(rate_in, 500.0 / window);
+        assert_eq!(rate_out, 250.0 / window);
+        assert_eq!((client.total_in, client.total_out), (1500, 250));
+    }
+
+    #[test]
+    fn payloads_are_measured_in_the_wire_encoding() {
+        let position = PlayerPosition(Vec2::new(1.0, 2.0));
+        let wire = bitcode::serialize(&position).unwrap();
+        assert_eq!(payload_size(&position), wire.len() as u64);
+        // far smaller than its text form
+        assert!(payload_size(&position) < serde_json::to_vec(&position).unwrap().len() as u64);
+    }
+}
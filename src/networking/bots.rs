@@ -0,0 +1,66 @@
+//! Players driven by the server instead of a client, for scripted characters.
+//!
+//! Bots go through the same [`PlayerBundle`] as the players of the clients and are replicated to
+//! everyone like them, server logic (patrolling, chasing...) moves them by changing their
+//! [`PlayerPosition`]. Their ids come from [`BOT_IDS`], which the clients can't connect with.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bevy::prelude::*;
+use lightyear::connection::netcode::ClientId;
+
+use crate::player::PlayerBundle;
+
+use super::protocol::{PlayerColor, PlayerHealth, PlayerSlot};
+use super::server::{hashed_color, Throttled};
+use super::BOT_IDS;
+
+/// Marker for the players driven by the server
+#[derive(Component, Debug)]
+pub struct Bot;
+
+static NEXT_BOT_ID: AtomicU64 = AtomicU64::new(*BOT_IDS.start());
+
+pub fn is_bot(client_id: ClientId) -> bool {
+    BOT_IDS.contains(&client_id)
+}
+
+/// Spawn a bot at the given position, it gets its own id from [`BOT_IDS`]
+pub fn spawn_bot(commands: &mut Commands, position: Vec2) -> Entity {
+    let bot_id = NEXT_BOT_ID.fetch_add(1, Ordering::Relaxed);
+    commands
+        .spawn((
+            // predicted by nobody since no client has its id, interpolated by everyone
+            PlayerBundle::new(bot_id, 0, position),
+            PlayerColor(hashed_color(bot_id, PlayerSlot(0))),
+            PlayerHealth::default(),
+            Throttled(PlayerHealth::default()),
+            Bot,
+        ))
+        .id()
+}
+
+#[cfg(test)]
+mod tests {
+    use lightyear::prelude::{NetworkTarget, Replicate};
+
+    use super::*;
+    use crate::networking::protocol::{PlayerId, PlayerPosition};
+
+    #[test]
+    fn bot_spawns_replicated_with_a_bot_id() {
+        let mut app = App::new();
+        app.add_systems(Update, |mut commands: Commands| {
+            spawn_bot(&mut commands, Vec2::new(10.0, 20.0));
+        });
+        app.update();
+
+        let mut bots = app
+            .world
+            .query_filtered::<(&PlayerId, &PlayerPosition, &Replicate), With<Bot>>();
+        let (player_id, position, replicate) = bots.single(&app.world);
+        assert!(is_bot(player_id.0));
+        assert_eq!(position.0, Vec2::new(10.0, 20.0));
+        assert!(matches!(replicate.replication_target, NetworkTarget::All));
+    }
+}
@@ -0,0 +1,438 @@
+//! Text chat between the players.
+//!
+//! Lines typed in the chat box are parsed by the client: plain text is said to everyone, and lines
+//! starting with a slash are commands:
+//! - `/me <action>`: describe what your character does
+//! - `/w <client_id> <message>`: whisper to a single client, nobody else receives it
+//! - `/list`: list the connected clients
+//!
+//! The server then decides who gets to see each line.
+
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+use lightyear::connection::netcode::ClientId;
+use lightyear::prelude::client::*;
+use lightyear::prelude::*;
+
+use super::protocol::{Channel1, ChatKind, ChatLine, ChatRequest, ClientMut, Whisper};
+
+/// The chat box of the client
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatBox>();
+        app.add_systems(Startup, spawn_chat);
+        app.add_systems(
+            Update,
+            (
+                type_in_chat,
+                receive_chat_lines,
+                receive_whispers,
+                update_chat_text,
+            )
+                .chain(),
+        );
+    }
+}
+
+pub const OPEN_CHAT_KEY: KeyCode = KeyCode::KeyT;
+
+/// How many of the latest lines are shown
+const CHAT_HISTORY: usize = 8;
+
+/// Longest line the server forwards, in characters
+pub(crate) const MAX_CHAT_LENGTH: usize = 200;
+
+/// What a line typed in the chat box is sent as
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ChatInput {
+    Request(ChatRequest),
+    Whisper { to: ClientId, text: String },
+}
+
+impl ChatInput {
+    /// Parse a line typed in the chat box, commands the client doesn't know are left to the server.
+    /// Errors are meant for the player, they are shown right away instead of being sent.
+    pub(crate) fn parse(line: &str) -> Result<Self, String> {
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(ChatInput::Request(ChatRequest::Say(line.to_string())));
+        };
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+        let request = match name {
+            "me" if !args.is_empty() => ChatRequest::Me(args.to_string()),
+            "me" => return Err("usage: /me <action>".to_string()),
+            "w" => {
+                let usage = || "usage: /w <client_id> <message>".to_string();
+                let (to, text) = args.split_once(' ').ok_or_else(usage)?;
+                let to = to.parse().map_err(|_| usage())?;
+                return Ok(ChatInput::Whisper {
+                    to,
+                    text: text.trim().to_string(),
+                });
+            }
+            "list" => ChatRequest::List,
+            name => ChatRequest::Unknown(name.to_string()),
+        };
+        Ok(ChatInput::Request(request))
+    }
+}
+
+/// Cut a line down to [`MAX_CHAT_LENGTH`] characters
+fn truncate_chat(text: &mut String) {
+    if let Some((end, _)) = text.char_indices().nth(MAX_CHAT_LENGTH) {
+        text.truncate(end);
+    }
+}
+
+impl ChatLine {
+    /// How the line is shown in the chat box
+    fn display(&self) -> String {
+        match &self.kind {
+            ChatKind::Say(from) => format!("{from}: {}", self.text),
+            ChatKind::Emote(from) => format!("* {from} {}", self.text),
+            ChatKind::System => self.text.clone(),
+        }
+    }
+
+    pub(crate) fn system(text: impl Into<String>) -> Self {
+        ChatLine {
+            kind: ChatKind::System,
+            text: text.into(),
+        }
+    }
+}
+
+/// What the player is typing, and the latest lines of the chat
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ChatBox {
+    /// While open, the keyboard types in the chat instead of moving the players
+    pub(crate) open: bool,
+    draft: String,
+    lines: Vec<String>,
+}
+
+impl ChatBox {
+    fn push(&mut self, line: String) {
+        self.lines.push(line);
+        if self.lines.len() > CHAT_HISTORY {
+            self.lines.remove(0);
+        }
+    }
+}
+
+/// Marker for the text showing the chat
+#[derive(Component)]
+pub(crate) struct ChatText;
+
+fn spawn_chat(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(40.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        ChatText,
+    ));
+}
+
+/// Open the chat box, type in it, and send the line with Enter. Escape closes it without sending.
+pub(crate) fn type_in_chat(
+    mut client: ClientMut,
+    metadata: Res<GlobalMetadata>,
+    mut keypress: ResMut<ButtonInput<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut chat: ResMut<ChatBox>,
+) {
+    if !chat.open {
+        characters.clear();
+        if keypress.just_pressed(OPEN_CHAT_KEY) {
+            chat.open = true;
+        }
+        return;
+    }
+    if keypress.just_pressed(KeyCode::Escape) {
+        chat.open = false;
+        chat.draft.clear();
+        // the same key also pauses the game
+        keypress.clear_just_pressed(KeyCode::Escape);
+        return;
+    }
+    if keypress.just_pressed(KeyCode::Enter) {
+        chat.open = false;
+        keypress.clear_just_pressed(KeyCode::Enter);
+        let line = std::mem::take(&mut chat.draft);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        let sent = match ChatInput::parse(line) {
+            Ok(ChatInput::Request(request)) => {
+                client.send_message::<Channel1, ChatRequest>(request)
+            }
+            Ok(ChatInput::Whisper { to, text }) => {
+                let Some(from) = metadata.client_id else {
+                    chat.push("Not connected".to_string());
+                    return;
+                };
+                client.send_message::<Channel1, Whisper>(Whisper { to, from, text })
+            }
+            Err(usage) => {
+                chat.push(usage);
+                return;
+            }
+        };
+        sent.unwrap_or_else(|e| {
+            error!("Failed to send chat message: {:?}", e);
+        });
+        return;
+    }
+    if keypress.just_pressed(KeyCode::Backspace) {
+        chat.draft.pop();
+    }
+    for character in characters.read() {
+        let typed = character.char.chars().filter(|c| !c.is_control());
+        if chat.draft.chars().count() < MAX_CHAT_LENGTH {
+            chat.draft.extend(typed);
+        }
+    }
+}
+
+pub(crate) fn receive_chat_lines(
+    mut lines: EventReader<MessageEvent<ChatLine>>,
+    mut chat: ResMut<ChatBox>,
+) {
+    for line in lines.read() {
+        let line = line.message().display();
+        info!("Chat: {}", line);
+        chat.push(line);
+    }
+}
+
+pub(crate) fn receive_whispers(
+    mut whispers: EventReader<MessageEvent<Whisper>>,
+    mut chat: ResMut<ChatBox>,
+) {
+    for whisper in whispers.read() {
+        let Whisper { to, from, text } = whisper.message();
+        let line = format!("[{from} -> {to}] {text}");
+        info!("Chat: {}", line);
+        chat.push(line);
+    }
+}
+
+fn update_chat_text(chat: Res<ChatBox>, mut texts: Query<&mut Text, With<ChatText>>) {
+    if !chat.is_changed() {
+        return;
+    }
+    for mut text in texts.iter_mut() {
+        let mut value = chat.lines.join("\n");
+        if chat.open {
+            value.push_str(&format!("\n> {}_", chat.draft));
+        }
+        text.sections[0].value = value;
+    }
+}
+
+/// Route the chat of the clients: said lines go to everyone,
+/// and replies to commands only to the client that sent them
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn handle_chat_requests(
+    mut requests: EventReader<lightyear::prelude::server::MessageEvent<ChatRequest>>,
+    mut server: super::bandwidth::CountedServer,
+    connected_players: Res<super::server::ConnectedPlayers>,
+    compressing: Res<super::compression::CompressingClients>,
+    settings: Res<super::SharedSettings>,
+) {
+    for request in requests.read() {
+        let from = *request.context();
+        let (mut line, target) = match request.message().clone() {
+            ChatRequest::Say(text) => (
+                ChatLine {
+                    kind: ChatKind::Say(from),
+                    text,
+                },
+                NetworkTarget::All,
+            ),
+            ChatRequest::Me(text) => (
+                ChatLine {
+                    kind: ChatKind::Emote(from),
+                    text,
+                },
+                NetworkTarget::All,
+            ),
+            ChatRequest::List => {
+                let clients: Vec<String> = connected_players
+                    .client_ids()
+                    .iter()
+                    .map(ClientId::to_string)
+                    .collect();
+                (
+                    ChatLine::system(format!("Connected: {}", clients.join(", "))),
+                    NetworkTarget::Only(vec![from]),
+                )
+            }
+            ChatRequest::Unknown(name) => (
+                ChatLine::system(format!("Unknown command /{name}")),
+                NetworkTarget::Only(vec![from]),
+            ),
+        };
+        if line.text.trim().is_empty() {
+            continue;
+        }
+        truncate_chat(&mut line.text);
+        compressing.send::<Channel1, _>(&mut server, &settings, line, target, "chat line");
+    }
+}
+
+/// What the server does with a whisper
+#[cfg(not(target_family = "wasm"))]
+#[derive(Debug, PartialEq)]
+enum WhisperRoute {
+    /// Tell the sender that nobody received it
+    NotConnected(ChatLine),
+    /// Send the whisper to these clients only
+    Deliver(Whisper, NetworkTarget),
+    /// There is nothing to whisper
+    Drop,
+}
+
+/// Where a whisper sent by `from` goes
+#[cfg(not(target_family = "wasm"))]
+fn route_whisper(
+    from: ClientId,
+    whisper: Whisper,
+    connected_players: &super::server::ConnectedPlayers,
+) -> WhisperRoute {
+    // the sender can't pretend to be someone else
+    let Whisper { to, text, .. } = whisper;
+    if !connected_players.contains(to) {
+        return WhisperRoute::NotConnected(ChatLine::system(format!(
+            "Client {to} is not connected"
+        )));
+    }
+    if text.trim().is_empty() {
+        return WhisperRoute::Drop;
+    }
+    let mut whisper = Whisper { to, from, text };
+    truncate_chat(&mut whisper.text);
+    // the target gets it, and the sender sees what was whispered
+    let recipients = if to == from {
+        vec![from]
+    } else {
+        vec![to, from]
+    };
+    WhisperRoute::Deliver(whisper, NetworkTarget::Only(recipients))
+}
+
+/// Forward whispers to their target only, and echo them back to the sender
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn forward_whispers(
+    mut whispers: EventReader<lightyear::prelude::server::MessageEvent<Whisper>>,
+    mut server: super::bandwidth::CountedServer,
+    connected_players: Res<super::server::ConnectedPlayers>,
+) {
+    for whisper in whispers.read() {
+        let from = *whisper.context();
+        let sent = match route_whisper(from, whisper.message().clone(), &connected_players) {
+            WhisperRoute::NotConnected(line) => server
+                .send_message_to_target::<Channel1, ChatLine>(
+                    line,
+                    NetworkTarget::Only(vec![from]),
+                ),
+            WhisperRoute::Deliver(whisper, target) => {
+                server.send_message_to_target::<Channel1, Whisper>(whisper, target)
+            }
+            WhisperRoute::Drop => continue,
+        };
+        sent.unwrap_or_else(|e| {
+            error!("Failed to send whisper: {:?}", e);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::server::ConnectedPlayers;
+
+    #[test]
+    fn parse_chat_input() {
+        assert_eq!(
+            ChatInput::parse("hello there"),
+            Ok(ChatInput::Request(ChatRequest::Say(
+                "hello there".to_string()
+            )))
+        );
+        assert_eq!(
+            ChatInput::parse("/me waves"),
+            Ok(ChatInput::Request(ChatRequest::Me("waves".to_string())))
+        );
+        assert!(ChatInput::parse("/me").is_err());
+        assert_eq!(
+            ChatInput::parse("/w 42  psst, over here "),
+            Ok(ChatInput::Whisper {
+                to: 42,
+                text: "psst, over here".to_string()
+            })
+        );
+        assert!(ChatInput::parse("/w 42").is_err());
+        assert!(ChatInput::parse("/w someone hi").is_err());
+        assert_eq!(
+            ChatInput::parse("/list"),
+            Ok(ChatInput::Request(ChatRequest::List))
+        );
+        // left for the server to answer
+        assert_eq!(
+            ChatInput::parse("/dance now"),
+            Ok(ChatInput::Request(ChatRequest::Unknown(
+                "dance".to_string()
+            )))
+        );
+    }
+
+    fn whisper(to: ClientId, from: ClientId, text: &str) -> Whisper {
+        Whisper {
+            to,
+            from,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn whispers_skip_third_parties() {
+        let connected_players = ConnectedPlayers::from_ids(&[1, 2, 3]);
+        // the claimed sender is replaced by the actual one
+        let route = route_whisper(1, whisper(2, 3, "psst"), &connected_players);
+        let WhisperRoute::Deliver(delivered, target) = route else {
+            panic!("the whisper isn't delivered: {:?}", route);
+        };
+        assert_eq!(delivered, whisper(2, 1, "psst"));
+        assert!(target.should_send_to(&2));
+        assert!(target.should_send_to(&1));
+        assert!(!target.should_send_to(&3));
+    }
+
+    #[test]
+    fn whispers_to_absent_clients_go_back_to_the_sender() {
+        let connected_players = ConnectedPlayers::from_ids(&[1, 2]);
+        assert_eq!(
+            route_whisper(1, whisper(7, 1, "hi"), &connected_players),
+            WhisperRoute::NotConnected(ChatLine::system("Client 7 is not connected"))
+        );
+        assert_eq!(
+            route_whisper(1, whisper(2, 1, "   "), &connected_players),
+            WhisperRoute::Drop
+        );
+    }
+}
@@ -1,22 +1,46 @@
 use std::net::SocketAddr;
 
-use bevy::app::PluginGroupBuilder;
+use bevy::app::{AppExit, PluginGroupBuilder};
+use bevy::asset::UntypedAssetLoadFailedEvent;
 use bevy::prelude::*;
+use bevy::render::camera::Viewport;
+use bevy::render::RenderPlugin;
 use bevy::utils::Duration;
+use bevy::window::{PrimaryWindow, WindowCloseRequested, WindowResized};
 
+use bevy_ecs_ldtk::prelude::LdtkProject;
 use bevy_ecs_ldtk::LdtkWorldBundle;
+use lightyear::client::components::Confirmed;
+use lightyear::client::prediction::despawn::PredictionDespawnCommandsExt;
 use lightyear::prelude::client::*;
 use lightyear::prelude::*;
 
-use crate::player::{AnimationIndices, AnimationTimer, PlayerBundle};
+use crate::collision::{push_out, PLAYER_HITBOX};
+use crate::level::{LevelGrid, CELL_SIZE};
+use crate::manifest::manifest_loaded;
+use crate::player::{
+    AnimationIndices, AnimationSet, AnimationTimer, Facing, LastFacing, MovementTracker,
+    PlayerBundle,
+};
+use crate::wall::WallBundle;
 
+use super::chat::{type_in_chat, ChatBox};
+use super::interpolation::{SmoothComponentExt, Smoothed};
 use super::protocol::{
-    protocol, ClientMut, Components, Inputs, MatrixRPGGameProto, PlayerId, PlayerPosition,
+    protocol, Channel1, ClientMut, Components, Direction, Ghost, Inputs, Knockback, LevelSeed,
+    MatrixRPGGameProto, PauseGame, PlayerColor, PlayerHealth, PlayerId, PlayerPosition, PlayerSlot,
+    PlayerSnapshot, PlayerVelocity, ReclaimPlayer, ReconnectToken, ViewRadius, WorldSnapshot,
+};
+use super::{
+    player_collisions_enabled, shared_config, shared_knockback_behaviour,
+    shared_movement_behaviour, shared_push_behaviour, ClientSettings, NetworkError, SharedSettings,
+    MAX_LOCAL_PLAYERS,
 };
-use super::{shared_config, shared_movement_behaviour, SharedSettings};
 
 pub struct ClientPluginGroup {
     lightyear: ClientPlugin<MatrixRPGGameProto>,
+    /// Whether the server runs in the same process, see [`Pause`]
+    listen_server: bool,
 }
 
 impl ClientPluginGroup {
@@ -26,23 +50,28 @@ impl ClientPluginGroup {
         transport_config: TransportConfig,
         shared_settings: SharedSettings,
     ) -> ClientPluginGroup {
+        let listen_server = matches!(transport_config, TransportConfig::LocalChannel { .. });
         let auth = Authentication::Manual {
             server_addr,
             client_id,
             private_key: shared_settings.private_key,
             protocol_id: shared_settings.protocol_id,
         };
-        let link_conditioner = LinkConditionerConfig {
-            incoming_latency: Duration::from_millis(200),
-            incoming_jitter: Duration::from_millis(20),
-            incoming_loss: 0.05,
-        };
+        let link_conditioner = shared_settings.link_conditioner(&transport_config);
+        let mut io = IoConfig::from_transport(transport_config);
+        if let Some(link_conditioner) = link_conditioner {
+            io = io.with_conditioner(link_conditioner);
+        }
         let config = ClientConfig {
-            shared: shared_config(),
+            shared: shared_config(&shared_settings),
             net: NetConfig::Netcode {
                 auth,
-                config: NetcodeConfig::default(),
-                io: IoConfig::from_transport(transport_config).with_conditioner(link_conditioner),
+                config: NetcodeConfig {
+                    keepalive_packet_send_rate: shared_settings.keepalive_interval_secs,
+                    client_timeout_secs: shared_settings.connection_timeout_secs,
+                    ..default()
+                },
+                io,
             },
             interpolation: InterpolationConfig {
                 delay: InterpolationDelay::default(),
@@ -53,6 +82,7 @@ impl ClientPluginGroup {
         let plugin_config = PluginConfig::new(config, protocol());
         ClientPluginGroup {
             lightyear: ClientPlugin::new(plugin_config),
+            listen_server,
         }
     }
 }
@@ -61,41 +91,497 @@ impl PluginGroup for ClientPluginGroup {
     fn build(self) -> PluginGroupBuilder {
         PluginGroupBuilder::start::<Self>()
             .add(self.lightyear)
-            .add(MatrixRPGClientPlugin)
+            .add(MatrixRPGClientPlugin {
+                listen_server: self.listen_server,
+            })
+            .add(super::interpolation::AdaptiveInterpolationPlugin)
+            .add(super::name_tags::NameTagPlugin)
+            .add(super::chat::ChatPlugin)
+            .add(super::disconnect::DisconnectScreenPlugin)
+            .add(super::tick_sync::TickOffsetPlugin)
+            .add(super::compression::CompressionPlugin)
+            .add(super::link_quality::LinkQualityPlugin)
             .add(super::SharedPlugin)
     }
 }
 
-pub struct MatrixRPGClientPlugin;
+pub struct MatrixRPGClientPlugin {
+    pub(crate) listen_server: bool,
+}
 
 impl Plugin for MatrixRPGClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, init);
-        app.add_systems(PreUpdate, handle_connection.after(MainSet::ReceiveFlush));
+        app.init_resource::<ReconnectCredentials>();
+        app.init_resource::<ConnectionAttempts>();
+        app.init_resource::<AutoSpawn>();
+        app.init_resource::<PendingSnapshot>();
+        app.insert_resource(Pause {
+            paused: false,
+            pauses_world: self.listen_server,
+        });
+        app.configure_sets(Update, AnimationSet.run_if(game_running));
+        app.smooth_component::<PlayerHealth>();
+        #[cfg(target_family = "wasm")]
+        app.add_systems(Startup, watch_tab_close);
+        app.add_systems(
+            PreUpdate,
+            (
+                handle_connection,
+                reclaim_player,
+                send_view_radius,
+                store_reconnect_token,
+                apply_world_snapshot,
+            )
+                .chain()
+                .after(MainSet::ReceiveFlush),
+        );
         // Inputs have to be buffered in the FixedPreUpdate schedule
         app.add_systems(
             FixedPreUpdate,
             buffer_input.in_set(InputSystemSet::BufferInputs),
         );
-        app.add_systems(FixedUpdate, player_movement);
-        app.add_systems(Update, spawn_player);
+        app.add_systems(
+            FixedUpdate,
+            (
+                (
+                    player_movement,
+                    predict_knockback,
+                    predict_player_separation.run_if(player_collisions_enabled),
+                )
+                    .chain(),
+                delete_player,
+                rollback_rejected_spawns,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                layout_split_screen,
+                follow_players,
+                build_generated_level,
+                disconnect_on_exit,
+                finish_shutdown,
+                retry_connection,
+                toggle_pause.after(type_in_chat),
+            ),
+        );
+        app.add_systems(
+            Update,
+            move_remote_player_sprites.after(InterpolationSet::Interpolate),
+        );
+        // a headless client plays without anything to show, the players then have no sprite
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(Startup, (init, load_player_sprite_sheet));
+            app.add_systems(
+                FixedUpdate,
+                // the player sprites must be loaded first
+                spawn_player.run_if(manifest_loaded),
+            );
+            app.add_systems(Update, ready_auto_spawn.run_if(manifest_loaded));
+            app.add_systems(
+                Update,
+                (
+                    attach_remote_player_sprites,
+                    apply_player_color,
+                    draw_health_bars,
+                    validate_player_sprite_sheet,
+                    report_asset_load_failures,
+                    // after the LDtk plugin set the color of the level
+                    override_clear_color.run_if(resource_changed::<ClearColor>),
+                ),
+            );
+        } else {
+            app.add_systems(FixedUpdate, spawn_player);
+            app.add_systems(Update, ready_auto_spawn);
+        }
     }
 }
 
 // Startup system for the client
-pub(crate) fn init(mut commands: Commands, mut client: ClientMut, asset_server: Res<AssetServer>) {
-    let mut camera = Camera2dBundle::default();
-    camera.projection.scale = 0.5;
-    camera.transform.translation.x += 1920.0 / 4.0;
-    camera.transform.translation.y += 1080.0 / 4.0;
-    commands.spawn(camera);
+pub(crate) fn init(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    settings: Res<ClientSettings>,
+) {
+    for slot in 0..settings.local_players {
+        let mut camera = Camera2dBundle::default();
+        camera.camera.order = slot as isize;
+        camera.projection.scale = 0.5;
+        // look at where the player will spawn, the camera then follows it
+        let target = spawn_position(slot) + Vec2::from(settings.camera_offset);
+        camera.transform.translation.x = target.x;
+        camera.transform.translation.y = target.y;
+        commands.spawn((camera, PlayerCamera(slot), CameraLookahead::default()));
+    }
 
     commands.spawn(LdtkWorldBundle {
         ldtk_handle: asset_server.load("matrix_office.ldtk"),
         ..Default::default()
     });
 
-    let _ = client.connect();
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        ConnectionStatusText,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 24.0,
+                color: Color::RED,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        AssetErrorText,
+    ));
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            PauseMenu,
+        ))
+        .with_children(|menu| {
+            menu.spawn(TextBundle::from_section(
+                "Paused",
+                TextStyle {
+                    font_size: 48.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            ));
+        });
+}
+
+/// Replace the background color of the level with the one from the settings, every time a level sets it
+fn override_clear_color(settings: Res<ClientSettings>, mut clear_color: ResMut<ClearColor>) {
+    let Some(color) = settings
+        .clear_color()
+        .expect("the clear color is validated on startup")
+    else {
+        return;
+    };
+    // only written when it differs, so that the change doesn't trigger this again
+    if clear_color.0 != color {
+        clear_color.0 = color;
+    }
+}
+
+/// Marker for the text listing the assets that could not be loaded
+#[derive(Component)]
+pub(crate) struct AssetErrorText;
+
+/// Tell the player which asset is missing instead of leaving them with an empty window.
+/// Failures of dependencies, like the tilesets of the LDtk project, are reported too.
+pub(crate) fn report_asset_load_failures(
+    mut failures: EventReader<UntypedAssetLoadFailedEvent>,
+    mut texts: Query<&mut Text, With<AssetErrorText>>,
+) {
+    for failure in failures.read() {
+        error!("Failed to load asset {}: {}", failure.path, failure.error);
+        for mut text in texts.iter_mut() {
+            let text = &mut text.sections[0].value;
+            if text.is_empty() {
+                text.push_str("The game files are incomplete, try reinstalling it.");
+            }
+            text.push_str(&format!("\nCould not load {}", failure.path));
+        }
+    }
+}
+
+/// The camera following the local player in the given slot
+#[derive(Component)]
+pub(crate) struct PlayerCamera(pub u8);
+
+/// How far ahead of its player a camera looks
+#[derive(Component, Default, Debug)]
+pub(crate) struct CameraLookahead {
+    /// Where the player is heading, a unit vector or zero when it stands still
+    heading: Vec2,
+    /// The current offset, easing towards the heading
+    offset: Vec2,
+}
+
+/// Keep each camera on its player, looking a bit ahead in the direction the player is heading
+fn follow_players(
+    time: Res<Time>,
+    settings: Res<ClientSettings>,
+    players: Query<(&Transform, &PlayerSlot), (With<Predicted>, With<PlayerId>, Without<Camera>)>,
+    mut cameras: Query<(
+        &mut Transform,
+        &mut CameraLookahead,
+        &PlayerCamera,
+        &Camera,
+        &OrthographicProjection,
+    )>,
+) {
+    let lookahead = &settings.camera_lookahead;
+    // the same easing whatever the frame rate
+    let blend = 1.0 - (-lookahead.smoothing * time.delta_seconds()).exp();
+    for (mut transform, mut camera_lookahead, player_camera, camera, projection) in
+        cameras.iter_mut()
+    {
+        let Some((player, _)) = players.iter().find(|(_, slot)| slot.0 == player_camera.0) else {
+            continue;
+        };
+        let wanted = camera_lookahead.heading * lookahead.distance;
+        camera_lookahead.offset = camera_lookahead.offset.lerp(wanted, blend);
+        let mut offset = Vec2::from(settings.camera_offset) + camera_lookahead.offset;
+        // however far the camera looks, the player stays in view
+        if let Some(size) = camera.logical_viewport_size() {
+            let max_offset = (size * projection.scale / 2.0 - PLAYER_HITBOX).max(Vec2::ZERO);
+            offset = offset.clamp(-max_offset, max_offset);
+        }
+        let target = player.translation.truncate() + offset;
+        transform.translation.x = target.x;
+        transform.translation.y = target.y;
+    }
+}
+
+/// Lay the cameras of the local players out side by side, each getting an equal share of the window
+fn layout_split_screen(
+    settings: Res<ClientSettings>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    mut resized: EventReader<WindowResized>,
+    new_cameras: Query<(), Added<PlayerCamera>>,
+    mut cameras: Query<(&PlayerCamera, &mut Camera)>,
+) {
+    let resized = resized.read().count() > 0;
+    if settings.local_players < 2 || !(resized || !new_cameras.is_empty()) {
+        return;
+    }
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let width = (window.physical_width() / u32::from(settings.local_players)).max(1);
+    let height = window.physical_height().max(1);
+    for (player_camera, mut camera) in cameras.iter_mut() {
+        camera.viewport = Some(Viewport {
+            physical_position: UVec2::new(width * u32::from(player_camera.0), 0),
+            physical_size: UVec2::new(width, height),
+            ..default()
+        });
+    }
+}
+
+const FLOOR_COLOR: Color = Color::rgb(0.18, 0.18, 0.22);
+const WALL_COLOR: Color = Color::rgb(0.45, 0.45, 0.5);
+
+/// Parent of everything spawned for a generated level
+#[derive(Component)]
+pub(crate) struct GeneratedLevel;
+
+/// Replace the LDtk level with the one the server generated
+fn build_generated_level(
+    mut commands: Commands,
+    mut seeds: EventReader<MessageEvent<LevelSeed>>,
+    ldtk_worlds: Query<Entity, With<Handle<LdtkProject>>>,
+    generated_levels: Query<Entity, With<GeneratedLevel>>,
+) {
+    let Some(level) = seeds.read().last().map(|event| *event.message()) else {
+        return;
+    };
+    info!(
+        "Building the {}x{} level generated from seed {}",
+        level.width, level.height, level.seed
+    );
+    for entity in ldtk_worlds.iter().chain(generated_levels.iter()) {
+        commands.entity(entity).despawn_recursive();
+    }
+    let grid = LevelGrid::generate(level.seed, level.width, level.height);
+    let bounds = grid.bounds();
+    commands
+        .spawn((GeneratedLevel, SpatialBundle::default()))
+        .with_children(|parent| {
+            parent.spawn(SpriteBundle {
+                sprite: Sprite {
+                    color: FLOOR_COLOR,
+                    custom_size: Some(bounds.size()),
+                    ..default()
+                },
+                transform: Transform::from_translation(bounds.center().extend(0.0)),
+                ..default()
+            });
+            for (x, y) in grid.walls() {
+                parent.spawn((
+                    WallBundle::default(),
+                    SpriteBundle {
+                        sprite: Sprite {
+                            color: WALL_COLOR,
+                            custom_size: Some(Vec2::splat(CELL_SIZE)),
+                            ..default()
+                        },
+                        transform: Transform::from_translation(grid.cell_center(x, y).extend(1.0)),
+                        ..default()
+                    },
+                ));
+            }
+        });
+    commands.insert_resource(grid);
+}
+
+/// How long the players of a [`WorldSnapshot`] wait to be replicated, later updates are newer anyway
+const SNAPSHOT_LIFETIME: Duration = Duration::from_secs(2);
+
+/// The players of the last snapshot that weren't replicated yet
+#[derive(Resource, Default, Debug)]
+pub(crate) struct PendingSnapshot {
+    tick: Tick,
+    players: Vec<PlayerSnapshot>,
+    expires: Timer,
+}
+
+/// Give the players replicated on connection the state of the snapshot the server sent along,
+/// whichever of the two arrives first. Players whose replicated state is newer than the snapshot keep it.
+pub(crate) fn apply_world_snapshot(
+    time: Res<Time>,
+    mut snapshots: EventReader<MessageEvent<WorldSnapshot>>,
+    mut pending: ResMut<PendingSnapshot>,
+    mut players: Query<(
+        &Confirmed,
+        &PlayerId,
+        &PlayerSlot,
+        &mut PlayerPosition,
+        Option<&mut PlayerColor>,
+        Option<&mut PlayerHealth>,
+    )>,
+) {
+    if let Some(snapshot) = snapshots.read().last() {
+        pending.tick = snapshot.message().tick;
+        pending.players = snapshot.message().players.clone();
+        pending.expires = Timer::new(SNAPSHOT_LIFETIME, TimerMode::Once);
+    }
+    if pending.players.is_empty() {
+        return;
+    }
+    if pending.expires.tick(time.delta()).finished() {
+        pending.players.clear();
+        return;
+    }
+    for (confirmed, player_id, slot, mut position, color, health) in players.iter_mut() {
+        let Some(index) = pending
+            .players
+            .iter()
+            .position(|player| player.client_id == player_id.0 && player.slot == slot.0)
+        else {
+            continue;
+        };
+        let snapshot = pending.players.swap_remove(index);
+        // the player moved on since the snapshot was taken, e.g. it arrived late over the bulk channel
+        if confirmed.tick > pending.tick {
+            continue;
+        }
+        position.set_if_neq(PlayerPosition(snapshot.position));
+        if let (Some(mut color), Some(snapshot_color)) = (color, snapshot.color) {
+            color.set_if_neq(PlayerColor(snapshot_color));
+        }
+        if let (Some(mut health), Some(snapshot_health)) = (health, snapshot.health) {
+            health.set_if_neq(PlayerHealth(snapshot_health));
+        }
+    }
+}
+
+/// Progress of the initial connection to the server
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ConnectionAttempts {
+    /// Number of attempts made so far
+    attempts: u32,
+    /// Time left before the current attempt is considered failed
+    timer: Timer,
+    /// Set once connected, later disconnections are not handled here
+    connected: bool,
+    /// Set once all attempts failed
+    gave_up: bool,
+}
+
+/// Marker for the text telling the player about the connection attempts
+#[derive(Component)]
+pub(crate) struct ConnectionStatusText;
+
+/// Connect to the server, retrying with an exponential backoff until it works or we run out of attempts
+pub(crate) fn retry_connection(
+    mut client: ClientMut,
+    mut attempts: ResMut<ConnectionAttempts>,
+    settings: Res<ClientSettings>,
+    time: Res<Time>,
+    mut texts: Query<&mut Text, With<ConnectionStatusText>>,
+) {
+    if attempts.connected || attempts.gave_up {
+        return;
+    }
+    if client.is_connected() {
+        info!("Connected after {} attempt(s)", attempts.attempts);
+        attempts.connected = true;
+        for mut text in texts.iter_mut() {
+            text.sections[0].value.clear();
+        }
+        return;
+    }
+    if attempts.attempts > 0 && !attempts.timer.tick(time.delta()).finished() {
+        return;
+    }
+    let retry = &settings.connect_retry;
+    if attempts.attempts >= retry.max_attempts {
+        error!(
+            "Could not connect to the server after {} attempts",
+            attempts.attempts
+        );
+        attempts.gave_up = true;
+        for mut text in texts.iter_mut() {
+            text.sections[0].value = format!(
+                "Could not connect to the server after {} attempts",
+                attempts.attempts
+            );
+        }
+        return;
+    }
+    let delay = retry.delay(attempts.attempts);
+    attempts.attempts += 1;
+    attempts.timer = Timer::new(delay, TimerMode::Once);
+    info!(
+        "Connecting to the server (attempt {}/{})",
+        attempts.attempts, retry.max_attempts
+    );
+    if let Err(e) = client.connect() {
+        error!("{}", NetworkError::Connect(format!("{e:?}")));
+    }
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = format!(
+            "Connecting to the server… (attempt {}/{})",
+            attempts.attempts, retry.max_attempts
+        );
+    }
 }
 
 pub(crate) fn handle_connection(mut commands: Commands, metadata: Res<GlobalMetadata>) {
@@ -115,110 +601,901 @@ pub(crate) fn handle_connection(mut commands: Commands, metadata: Res<GlobalMeta
     }
 }
 
-// System that reads from peripherals and adds inputs to the buffer
-pub(crate) fn buffer_input(mut client: ClientMut, keypress: Res<ButtonInput<KeyCode>>) {
-    let mut direction = super::protocol::Direction {
-        up: false,
-        down: false,
-        left: false,
-        right: false,
+/// How long we keep running after disconnecting, so that the disconnect packets get sent
+const SHUTDOWN_DELAY: Duration = Duration::from_millis(100);
+
+/// Set once we're disconnecting because the window was closed, the app exits when the timer finishes
+#[derive(Resource, Deref, DerefMut)]
+pub(crate) struct Shutdown(Timer);
+
+/// Set by the browser when the tab is being closed or reloaded
+#[cfg(target_family = "wasm")]
+static TAB_CLOSING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(target_family = "wasm")]
+fn watch_tab_close() {
+    use wasm_bindgen::{closure::Closure, JsCast};
+
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let on_unload = Closure::<dyn FnMut()>::new(|| {
+        TAB_CLOSING.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+    window.set_onbeforeunload(Some(on_unload.as_ref().unchecked_ref()));
+    // the callback must live as long as the page
+    on_unload.forget();
+}
+
+/// Tell the server we're leaving when the window gets closed, instead of letting the connection time out
+pub(crate) fn disconnect_on_exit(
+    mut commands: Commands,
+    mut client: ClientMut,
+    mut close_requests: EventReader<WindowCloseRequested>,
+    mut exits: EventReader<AppExit>,
+    shutdown: Option<Res<Shutdown>>,
+) {
+    let close_requested = close_requests.read().count() > 0;
+    // the app stops at the end of this frame, there's no time to wait for anything
+    let exiting = exits.read().count() > 0;
+    #[cfg(target_family = "wasm")]
+    // there's no way to delay a tab closing, so this is best effort
+    let exiting = exiting || TAB_CLOSING.swap(false, std::sync::atomic::Ordering::Relaxed);
+    if !(close_requested || exiting) || shutdown.is_some() {
+        return;
+    }
+    info!("Disconnecting from the server");
+    if let Err(e) = client.disconnect() {
+        error!("Failed to disconnect: {:?}", e);
+    }
+    if close_requested {
+        commands.insert_resource(Shutdown(Timer::new(SHUTDOWN_DELAY, TimerMode::Once)));
+    }
+}
+
+/// Exit the app once the disconnection had time to reach the server
+pub(crate) fn finish_shutdown(
+    time: Res<Time>,
+    shutdown: Option<ResMut<Shutdown>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(mut shutdown) = shutdown else {
+        return;
     };
-    if keypress.pressed(KeyCode::KeyW) || keypress.pressed(KeyCode::ArrowUp) {
-        direction.up = true;
+    if shutdown.tick(time.delta()).just_finished() {
+        exit.send(AppExit);
+    }
+}
+
+/// The last reconnect token the server gave us
+#[derive(Resource, Debug)]
+pub(crate) struct ReconnectCredentials(pub(crate) Option<ReconnectToken>);
+
+impl FromWorld for ReconnectCredentials {
+    fn from_world(_world: &mut World) -> Self {
+        cfg_if::cfg_if! {
+            if #[cfg(target_family = "wasm")] {
+                // survive page reloads, which is when a browser player is most likely to reconnect
+                ReconnectCredentials(crate::web_storage::load_reconnect_token())
+            } else {
+                ReconnectCredentials(None)
+            }
+        }
+    }
+}
+
+/// Present the token from our previous connection to get our player back
+pub(crate) fn reclaim_player(
+    mut connections: EventReader<ConnectEvent>,
+    mut client: ClientMut,
+    credentials: Res<ReconnectCredentials>,
+) {
+    for _ in connections.read() {
+        let Some(token) = credentials.0.clone() else {
+            continue;
+        };
+        client
+            .send_message::<Channel1, ReclaimPlayer>(ReclaimPlayer(token))
+            .unwrap_or_else(|e| {
+                error!("Failed to send reclaim request: {:?}", e);
+            });
     }
-    if keypress.pressed(KeyCode::KeyS) || keypress.pressed(KeyCode::ArrowDown) {
-        direction.down = true;
+}
+
+/// Tell the server how far we want to see, on every connection since it forgets on disconnect
+pub(crate) fn send_view_radius(
+    mut connections: EventReader<ConnectEvent>,
+    mut client: ClientMut,
+    settings: Res<ClientSettings>,
+) {
+    if connections.read().count() == 0 {
+        return;
     }
-    if keypress.pressed(KeyCode::KeyA) || keypress.pressed(KeyCode::ArrowLeft) {
-        direction.left = true;
+    let Some(radius) = settings.view_radius else {
+        return;
+    };
+    client
+        .send_message::<Channel1, ViewRadius>(ViewRadius(radius))
+        .unwrap_or_else(|e| {
+            error!("Failed to send view radius: {:?}", e);
+        });
+}
+
+/// Keep the latest reconnect token sent by the server
+pub(crate) fn store_reconnect_token(
+    mut tokens: EventReader<MessageEvent<ReconnectToken>>,
+    mut credentials: ResMut<ReconnectCredentials>,
+) {
+    for token in tokens.read() {
+        #[cfg(target_family = "wasm")]
+        crate::web_storage::save_reconnect_token(token.message());
+        credentials.0 = Some(token.message().clone());
     }
-    if keypress.pressed(KeyCode::KeyD) || keypress.pressed(KeyCode::ArrowRight) {
-        direction.right = true;
+}
+
+/// The keys controlling a single local player
+struct KeyBindings {
+    up: &'static [KeyCode],
+    down: &'static [KeyCode],
+    left: &'static [KeyCode],
+    right: &'static [KeyCode],
+    spawn: &'static [KeyCode],
+    delete: &'static [KeyCode],
+    /// Stands still on purpose, see [`Inputs::Stop`]
+    stop: &'static [KeyCode],
+    /// Pauses the game for every local player
+    pause: &'static [KeyCode],
+}
+
+/// Keys used when there is a single local player
+const SOLO_BINDINGS: KeyBindings = KeyBindings {
+    up: &[KeyCode::KeyW, KeyCode::ArrowUp],
+    down: &[KeyCode::KeyS, KeyCode::ArrowDown],
+    left: &[KeyCode::KeyA, KeyCode::ArrowLeft],
+    right: &[KeyCode::KeyD, KeyCode::ArrowRight],
+    spawn: &[KeyCode::Space],
+    delete: &[KeyCode::Delete, KeyCode::Backspace],
+    stop: &[KeyCode::KeyX],
+    pause: &[KeyCode::Escape, KeyCode::KeyP],
+};
+
+/// Keys of each split-screen player, the first one gets the left side of the keyboard
+const SPLIT_SCREEN_BINDINGS: [KeyBindings; MAX_LOCAL_PLAYERS as usize] = [
+    KeyBindings {
+        up: &[KeyCode::KeyW],
+        down: &[KeyCode::KeyS],
+        left: &[KeyCode::KeyA],
+        right: &[KeyCode::KeyD],
+        spawn: &[KeyCode::Space],
+        delete: &[KeyCode::KeyQ],
+        stop: &[KeyCode::KeyE],
+        pause: &[KeyCode::Escape],
+    },
+    KeyBindings {
+        up: &[KeyCode::ArrowUp],
+        down: &[KeyCode::ArrowDown],
+        left: &[KeyCode::ArrowLeft],
+        right: &[KeyCode::ArrowRight],
+        spawn: &[KeyCode::Enter, KeyCode::NumpadEnter],
+        delete: &[KeyCode::Delete, KeyCode::Backspace],
+        stop: &[KeyCode::ShiftRight],
+        pause: &[KeyCode::KeyP],
+    },
+];
+
+fn read_input(keypress: &ButtonInput<KeyCode>, bindings: &KeyBindings) -> Inputs {
+    if keypress.any_pressed(bindings.stop.iter().copied()) {
+        return Inputs::Stop;
     }
+    let direction = Direction {
+        up: keypress.any_pressed(bindings.up.iter().copied()),
+        down: keypress.any_pressed(bindings.down.iter().copied()),
+        left: keypress.any_pressed(bindings.left.iter().copied()),
+        right: keypress.any_pressed(bindings.right.iter().copied()),
+    };
     if !direction.is_none() {
-        return client.add_input(Inputs::Direction(direction));
+        return Inputs::Direction(direction);
+    }
+    if keypress.any_pressed(bindings.spawn.iter().copied()) {
+        return Inputs::Spawn;
+    }
+    if keypress.any_pressed(bindings.delete.iter().copied()) {
+        return Inputs::Delete;
     }
-    if keypress.pressed(KeyCode::Space) {
-        return client.add_input(Inputs::Spawn);
+    Inputs::None
+}
+
+/// The bindings of the local players
+fn active_bindings(settings: &ClientSettings) -> &'static [KeyBindings] {
+    if settings.local_players < 2 {
+        std::slice::from_ref(&SOLO_BINDINGS)
+    } else {
+        let bindings: &'static [KeyBindings] = &SPLIT_SCREEN_BINDINGS;
+        &bindings[..settings.local_players as usize]
     }
+}
+
+// System that reads from peripherals and adds inputs to the buffer
+pub(crate) fn buffer_input(
+    mut client: ClientMut,
+    keypress: Res<ButtonInput<KeyCode>>,
+    settings: Res<ClientSettings>,
+    pause: Res<Pause>,
+    chat: Res<ChatBox>,
+    mut auto_spawn: ResMut<AutoSpawn>,
+) {
     // info!("Sending input: {:?} on tick: {:?}", &input, client.tick());
-    client.add_input(Inputs::None)
+    if *auto_spawn == AutoSpawn::Ready {
+        *auto_spawn = AutoSpawn::Done;
+        info!("Spawning automatically");
+        if settings.local_players < 2 {
+            return client.add_input(Inputs::Spawn);
+        }
+        return client.add_input(Inputs::Local(vec![
+            Inputs::Spawn;
+            settings.local_players as usize
+        ]));
+    }
+    if pause.paused || chat.open {
+        // keep sending inputs so that the server doesn't count them as lost
+        return client.add_input(Inputs::None);
+    }
+    if settings.local_players < 2 {
+        return client.add_input(read_input(&keypress, &SOLO_BINDINGS));
+    }
+    let inputs = active_bindings(&settings)
+        .iter()
+        .map(|bindings| read_input(&keypress, bindings))
+        .collect();
+    client.add_input(Inputs::Local(inputs))
+}
+
+/// Progress of the automatic spawn of [`ClientSettings::auto_spawn`], which only happens once per run:
+/// after a reconnection the player is reclaimed instead, and a deleted player stays deleted
+#[derive(Resource, Default, Debug, PartialEq, Eq)]
+pub(crate) enum AutoSpawn {
+    #[default]
+    Waiting,
+    /// The `Spawn` input goes out with the next inputs
+    Ready,
+    Done,
+}
+
+/// Spawn automatically once connected, the caller only runs it once the level can be shown
+fn ready_auto_spawn(
+    settings: Res<ClientSettings>,
+    metadata: Res<GlobalMetadata>,
+    mut auto_spawn: ResMut<AutoSpawn>,
+) {
+    if settings.auto_spawn && *auto_spawn == AutoSpawn::Waiting && metadata.client_id.is_some() {
+        *auto_spawn = AutoSpawn::Ready;
+    }
+}
+
+/// Whether the game is paused by one of the local players.
+/// In a listen-server we are the only one playing, so the whole game stops while paused.
+/// Otherwise the world keeps running and only our own view is paused.
+#[derive(Resource, Debug)]
+pub(crate) struct Pause {
+    pub(crate) paused: bool,
+    pub(crate) pauses_world: bool,
+}
+
+fn game_running(pause: Res<Pause>) -> bool {
+    !pause.paused
+}
+
+/// Marker for the menu shown while the game is paused
+#[derive(Component)]
+pub(crate) struct PauseMenu;
+
+/// Pause or resume the game with the pause key of any local player
+pub(crate) fn toggle_pause(
+    mut client: ClientMut,
+    keypress: Res<ButtonInput<KeyCode>>,
+    settings: Res<ClientSettings>,
+    mut pause: ResMut<Pause>,
+    chat: Res<ChatBox>,
+    mut menus: Query<&mut Visibility, With<PauseMenu>>,
+) {
+    // the pause keys are typed in the chat while it is open
+    if chat.open {
+        return;
+    }
+    let pressed = active_bindings(&settings)
+        .iter()
+        .any(|bindings| keypress.any_just_pressed(bindings.pause.iter().copied()));
+    if !pressed {
+        return;
+    }
+    pause.paused = !pause.paused;
+    info!("Game {}", if pause.paused { "paused" } else { "resumed" });
+    for mut visibility in menus.iter_mut() {
+        *visibility = if pause.paused {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+    if pause.pauses_world {
+        client
+            .send_message::<Channel1, PauseGame>(PauseGame(pause.paused))
+            .unwrap_or_else(|e| {
+                error!("Failed to send pause request: {:?}", e);
+            });
+    }
 }
 
 // The client input only gets applied to predicted entities that we own
-// This works because we only predict the user's controlled entity.
+// This works because we only predict the user's controlled entities.
 // If we were predicting more entities, we would have to only apply movement to the player owned one.
 #[allow(clippy::type_complexity)]
-fn player_movement(
+pub(crate) fn player_movement(
     mut position_query: Query<
-        (&mut Transform, &mut PlayerPosition),
+        (
+            &mut Transform,
+            &mut PlayerPosition,
+            Option<&mut PlayerVelocity>,
+            &PlayerSlot,
+            Option<&mut LastFacing>,
+        ),
         (With<Predicted>, With<PlayerId>, Without<Camera>),
     >,
-    mut cameras: Query<&mut Transform, With<Camera>>,
+    mut cameras: Query<(&PlayerCamera, &mut CameraLookahead)>,
     mut input_reader: EventReader<InputEvent<Inputs>>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
 ) {
     if <Components as SyncMetadata<PlayerPosition>>::mode() != ComponentSyncMode::Full {
         return;
     }
     for input in input_reader.read() {
         if let Some(input) = input.input() {
-            for (mut transform, position) in position_query.iter_mut() {
+            for (mut transform, position, velocity, slot, facing) in position_query.iter_mut() {
+                let Some(input) = input.for_slot(slot.0) else {
+                    continue;
+                };
+                // standing still keeps the previous facing
+                if let (Inputs::Direction(direction), Some(mut facing)) = (input, facing) {
+                    if let Some(new_facing) = Facing::from_direction(direction) {
+                        facing.0 = new_facing;
+                    }
+                }
                 // NOTE: be careful to directly pass Mut<PlayerPosition>
                 // getting a mutable reference triggers change detection, unless you use `as_deref_mut()`
                 transform.translation = Vec3::new(position.x, position.y, transform.translation.z);
-                let pos = transform.translation;
-                for mut transform in &mut cameras {
-                    transform.translation.x = pos.x;
-                    transform.translation.y = pos.y;
+                // sliding keeps heading the same way, whatever is pressed
+                let heading = match (velocity.as_deref(), input) {
+                    (Some(velocity), _) if velocity.0 != Vec2::ZERO => velocity.0,
+                    (_, Inputs::Direction(direction)) => direction.to_vec2(),
+                    _ => Vec2::ZERO,
+                };
+                for (camera, mut lookahead) in &mut cameras {
+                    if camera.0 == slot.0 {
+                        lookahead.heading = heading.normalize_or_zero();
+                    }
                 }
-                shared_movement_behaviour(position, input);
+                shared_movement_behaviour(position, velocity, input, &level, &shared_settings);
             }
         }
     }
 }
 
-/// Spawn a player when the space command is pressed
+/// Apply the knockback of our players like the server does, the server removes it once it has settled
+fn predict_knockback(
+    mut players: Query<(&mut PlayerPosition, &mut Knockback), With<Predicted>>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+) {
+    for (mut position, mut knockback) in players.iter_mut() {
+        if knockback.0 == Vec2::ZERO {
+            continue;
+        }
+        let settled =
+            shared_knockback_behaviour(&mut position, &mut knockback, &level, &shared_settings);
+        if settled {
+            knockback.0 = Vec2::ZERO;
+        }
+    }
+}
+
+/// Push our players out of the other players like the server does,
+/// assuming the other player takes the other half of the push
+#[allow(clippy::type_complexity)]
+fn predict_player_separation(
+    mut predicted: Query<(Entity, &mut PlayerPosition), With<Predicted>>,
+    others: Query<
+        (Entity, &PlayerPosition),
+        (
+            With<PlayerId>,
+            Without<Confirmed>,
+            Without<Predicted>,
+            Without<OwnInterpolated>,
+        ),
+    >,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+) {
+    let positions: Vec<(Entity, Vec2)> = predicted
+        .iter()
+        .map(|(entity, position)| (entity, position.0))
+        .chain(others.iter().map(|(entity, position)| (entity, position.0)))
+        .collect();
+    for (entity, mut position) in predicted.iter_mut() {
+        let push: Vec2 = positions
+            .iter()
+            .filter(|(other, _)| *other != entity)
+            .map(|(_, other_position)| push_out(position.0, *other_position) / 2.0)
+            .sum();
+        if push != Vec2::ZERO {
+            shared_push_behaviour(&mut position, push, &level, &shared_settings);
+        }
+    }
+}
+
+/// How long we wait for the server to confirm a spawned player before assuming it was rejected
+const SPAWN_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A player we spawned ourselves that the server hasn't confirmed yet
+#[derive(Component, Deref, DerefMut)]
+pub(crate) struct PendingSpawn(pub Timer);
+
+/// Local players in split-screen spawn next to each other instead of on top of each other
+const SPAWN_SPACING: f32 = 32.0;
+
+/// Where the local player in the given slot appears when spawning
+fn spawn_position(slot: u8) -> Vec2 {
+    Vec2::new(f32::from(slot) * SPAWN_SPACING, 0.0)
+}
+
+/// The local player slots for which one of the inputs is `expected`
+fn slots_with_input(
+    input_reader: &mut EventReader<InputEvent<Inputs>>,
+    local_players: u8,
+    expected: &Inputs,
+) -> Vec<u8> {
+    let inputs: Vec<Inputs> = input_reader
+        .read()
+        .filter_map(|input| input.input().clone())
+        .collect();
+    (0..local_players)
+        .filter(|slot| {
+            inputs
+                .iter()
+                .any(|input| input.for_slot(*slot) == Some(expected))
+        })
+        .collect()
+}
+
+/// Spawn our players as soon as the `Spawn` input is sent, without waiting for the server
 fn spawn_player(
     mut commands: Commands,
-    players: Query<&PlayerId, With<PlayerPosition>>,
+    players: Query<(&PlayerId, &PlayerSlot), With<PlayerPosition>>,
     metadata: Res<GlobalMetadata>,
-    asset_server: Res<AssetServer>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+    mut input_reader: EventReader<InputEvent<Inputs>>,
+    settings: Res<ClientSettings>,
+    sprite_sheet: Option<Res<PlayerSpriteSheet>>,
 ) {
+    let slots = slots_with_input(&mut input_reader, settings.local_players, &Inputs::Spawn);
+    if slots.is_empty() {
+        return;
+    }
     // return early if we still don't have access to the client id
     let Some(client_id) = metadata.client_id else {
         return;
     };
 
-    for player_id in players.iter() {
-        if player_id.0 == client_id {
-            return;
+    for slot in slots {
+        let already_spawned = players
+            .iter()
+            .any(|(player_id, player_slot)| player_id.0 == client_id && player_slot.0 == slot);
+        if already_spawned {
+            continue;
+        }
+        info!("got spawn input for local player {}", slot);
+
+        let position = spawn_position(slot);
+        let mut player = commands.spawn((
+            PlayerBundle::new(client_id, slot, position),
+            // IMPORTANT: this lets the server know that the entity is pre-predicted
+            // when the server replicates this entity; we will get a Confirmed entity which will use this entity
+            // as the Predicted version
+            ShouldBePredicted::default(),
+            PendingSpawn(Timer::new(SPAWN_CONFIRMATION_TIMEOUT, TimerMode::Once)),
+        ));
+        if let Some(sprite_sheet) = &sprite_sheet {
+            player.insert(player_sprite(position, &settings, sprite_sheet));
+            if settings.show_confirmed_player {
+                // the interpolated copy is drawn instead
+                player.insert(Visibility::Hidden);
+            }
+        }
+    }
+}
+
+/// Remove our players as soon as the `Delete` input is sent, the server removes the confirmed entity
+fn delete_player(
+    mut commands: Commands,
+    players: Query<(Entity, &PlayerId, &PlayerSlot, Has<PendingSpawn>), Without<Confirmed>>,
+    metadata: Res<GlobalMetadata>,
+    mut input_reader: EventReader<InputEvent<Inputs>>,
+    settings: Res<ClientSettings>,
+) {
+    let slots = slots_with_input(&mut input_reader, settings.local_players, &Inputs::Delete);
+    if slots.is_empty() {
+        return;
+    }
+    let Some(client_id) = metadata.client_id else {
+        return;
+    };
+    for (entity, player_id, slot, pending) in players.iter() {
+        if player_id.0 != client_id || !slots.contains(&slot.0) {
+            continue;
+        }
+        info!("got delete input");
+        if pending {
+            // the server doesn't know about this entity yet, nothing to roll back to
+            commands.entity(entity).despawn();
+        } else {
+            // keep the entity around in case the server disagrees and we need to roll back
+            commands
+                .entity(entity)
+                .prediction_despawn::<MatrixRPGGameProto>();
+        }
+    }
+}
+
+/// Remove the players we spawned that the server never confirmed, e.g. because it refused the spawn
+fn rollback_rejected_spawns(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut pending_spawns: Query<(Entity, &mut PendingSpawn, Has<Predicted>)>,
+) {
+    for (entity, mut pending, confirmed) in pending_spawns.iter_mut() {
+        if confirmed {
+            commands.entity(entity).remove::<PendingSpawn>();
+            continue;
+        }
+        if pending.tick(time.delta()).finished() {
+            warn!("The server did not confirm our player, removing it");
+            commands.entity(entity).despawn();
         }
     }
-    info!("got spawn input");
+}
+
+/// The interpolated copy of one of our own players, only drawn with [`ClientSettings::show_confirmed_player`].
+/// It is left out wherever the other players are looked at.
+#[derive(Component)]
+pub(crate) struct OwnInterpolated;
 
-    let texture = asset_server.load("tilesets/user.png");
-    let layout = TextureAtlasLayout::from_grid(Vec2::new(16.0, 16.0), 8, 8, None, None);
-    let texture_atlas_layout = texture_atlas_layouts.add(layout);
-    // Use only the subset of sprites in the sheet that make up the run animation
-    let animation_indices = AnimationIndices { first: 0, last: 3 };
+/// Give the players of other clients a sprite once they get replicated to us
+fn attach_remote_player_sprites(
+    mut commands: Commands,
+    players: Query<(Entity, &PlayerId, &PlayerPosition), Added<Interpolated>>,
+    metadata: Res<GlobalMetadata>,
+    settings: Res<ClientSettings>,
+    sprite_sheet: Res<PlayerSpriteSheet>,
+) {
+    for (entity, player_id, position) in players.iter() {
+        let mut player = commands.entity(entity);
+        if metadata.client_id == Some(player_id.0) {
+            player.insert(OwnInterpolated);
+            if !settings.show_confirmed_player {
+                continue;
+            }
+        }
+        player.insert(player_sprite(position.0, &settings, &sprite_sheet));
+    }
+}
+
+/// Move the sprites of other clients' players to their interpolated position.
+/// Our own players are moved by `player_movement` instead.
+#[allow(clippy::type_complexity)]
+fn move_remote_player_sprites(
+    mut players: Query<
+        (&PlayerPosition, &mut Transform),
+        (
+            With<Interpolated>,
+            With<PlayerId>,
+            Without<Predicted>,
+            Changed<PlayerPosition>,
+        ),
+    >,
+) {
+    for (position, mut transform) in players.iter_mut() {
+        // keep the z, it decides which sprites are drawn on top
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
+
+/// Tint the player sprites with the color the server assigned to them, ghosts are grayed out
+#[allow(clippy::type_complexity)]
+fn apply_player_color(
+    mut players: Query<
+        (&PlayerColor, Has<Ghost>, &mut Sprite),
+        Or<(Changed<PlayerColor>, Added<Sprite>, Added<Ghost>)>,
+    >,
+) {
+    for (color, ghost, mut sprite) in players.iter_mut() {
+        sprite.color = if ghost { GHOST_COLOR } else { color.0 };
+    }
+}
+
+/// Width of a full health bar, in pixels
+const HEALTH_BAR_WIDTH: f32 = 24.0;
+
+/// Distance between a player's position and its health bar
+const HEALTH_BAR_OFFSET: Vec2 = Vec2::new(0.0, -20.0);
+
+/// Draw a bar under the players that lost health, sliding smoothly between the updates of the server
+fn draw_health_bars(
+    mut gizmos: Gizmos,
+    players: Query<
+        (&PlayerPosition, &Smoothed<PlayerHealth>),
+        (Without<Confirmed>, Without<OwnInterpolated>),
+    >,
+) {
+    for (position, health) in players.iter() {
+        let share = (health.value / PlayerHealth::MAX as f32).clamp(0.0, 1.0);
+        if share >= 1.0 {
+            continue;
+        }
+        let start = position.0 + HEALTH_BAR_OFFSET - Vec2::new(HEALTH_BAR_WIDTH / 2.0, 0.0);
+        gizmos.line_2d(
+            start,
+            start + Vec2::new(HEALTH_BAR_WIDTH, 0.0),
+            Color::DARK_GRAY,
+        );
+        gizmos.line_2d(
+            start,
+            start + Vec2::new(HEALTH_BAR_WIDTH * share, 0.0),
+            Color::GREEN,
+        );
+    }
+}
+
+/// Players of clients that disconnected
+const GHOST_COLOR: Color = Color::rgba(0.5, 0.5, 0.5, 0.4);
+
+/// The sheet with the player animation frames, shared by all player sprites
+#[derive(Resource)]
+pub(crate) struct PlayerSpriteSheet {
+    texture: Handle<Image>,
+    layout: Handle<TextureAtlasLayout>,
+}
+
+fn load_player_sprite_sheet(
+    mut commands: Commands,
+    settings: Res<ClientSettings>,
+    asset_server: Res<AssetServer>,
+    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
+) {
+    let sheet = &settings.sprite_sheet;
+    let layout = TextureAtlasLayout::from_grid(
+        Vec2::new(sheet.tile_width, sheet.tile_height),
+        sheet.columns,
+        sheet.rows,
+        None,
+        None,
+    );
+    commands.insert_resource(PlayerSpriteSheet {
+        texture: asset_server.load(sheet.path.clone()),
+        layout: texture_atlas_layouts.add(layout),
+    });
+}
+
+/// Warn when the configured grid doesn't match the size of the sprite sheet image,
+/// which usually means the frames will be cut at the wrong place
+fn validate_player_sprite_sheet(
+    mut events: EventReader<AssetEvent<Image>>,
+    settings: Res<ClientSettings>,
+    sprite_sheet: Res<PlayerSpriteSheet>,
+    images: Res<Assets<Image>>,
+) {
+    for event in events.read() {
+        if !event.is_loaded_with_dependencies(&sprite_sheet.texture) {
+            continue;
+        }
+        let Some(image) = images.get(&sprite_sheet.texture) else {
+            continue;
+        };
+        let sheet = &settings.sprite_sheet;
+        let expected = Vec2::new(
+            sheet.tile_width * sheet.columns as f32,
+            sheet.tile_height * sheet.rows as f32,
+        );
+        if image.size_f32() != expected {
+            warn!(
+                "The sprite sheet {} is {} pixels but the configured grid of {}x{} tiles of {}x{} needs {}",
+                sheet.path,
+                image.size_f32(),
+                sheet.columns,
+                sheet.rows,
+                sheet.tile_width,
+                sheet.tile_height,
+                expected
+            );
+        }
+    }
+}
+
+/// The animated sprite used to render a player at the given position
+fn player_sprite(
+    position: Vec2,
+    settings: &ClientSettings,
+    sprite_sheet: &PlayerSpriteSheet,
+) -> (
+    AnimationTimer,
+    AnimationIndices,
+    MovementTracker,
+    LastFacing,
+    SpriteSheetBundle,
+) {
+    // players start out standing still
+    let idle = &settings.animations.idle;
+    let animation_indices = AnimationIndices::from(idle);
     let atlas = TextureAtlas {
-        layout: texture_atlas_layout.clone(),
+        layout: sprite_sheet.layout.clone(),
         index: animation_indices.first,
     };
-    commands.spawn((
-        PlayerBundle::new(client_id, Vec2::ZERO),
-        AnimationTimer(Timer::from_seconds(0.3, TimerMode::Repeating)),
+    (
+        AnimationTimer(Timer::from_seconds(1.0 / idle.fps, TimerMode::Repeating)),
         animation_indices,
+        MovementTracker::default(),
+        // looking down until the player first moves
+        LastFacing::default(),
         SpriteSheetBundle {
-            transform: Transform::from_xyz(0., 0., 17.).with_scale(Vec3::splat(2.0)),
-            texture: texture.clone(),
+            transform: Transform::from_xyz(position.x, position.y, settings.sprite_z)
+                .with_scale(Vec3::splat(settings.sprite_scale)),
+            texture: sprite_sheet.texture.clone(),
             atlas,
             ..default()
         },
-        // IMPORTANT: this lets the server know that the entity is pre-predicted
-        // when the server replicates this entity; we will get a Confirmed entity which will use this entity
-        // as the Predicted version
-        ShouldBePredicted::default(),
-    ));
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::io::Reader;
+    use bevy::asset::{AssetLoader, LoadContext};
+    use bevy::utils::BoxedFuture;
+    use lightyear::connection::netcode::ClientId;
+
+    use super::*;
+
+    fn snapshot_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<Time>();
+        app.init_resource::<PendingSnapshot>();
+        app.add_event::<MessageEvent<WorldSnapshot>>();
+        app.add_systems(Update, apply_world_snapshot);
+        app
+    }
+
+    fn spawn_confirmed_player(app: &mut App, client_id: ClientId, tick: u16) -> Entity {
+        app.world
+            .spawn((
+                Confirmed {
+                    predicted: None,
+                    interpolated: None,
+                    tick: Tick(tick),
+                },
+                PlayerId(client_id),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::ZERO),
+                PlayerHealth(PlayerHealth::MAX),
+            ))
+            .id()
+    }
+
+    fn player_snapshot(client_id: ClientId) -> PlayerSnapshot {
+        PlayerSnapshot {
+            client_id,
+            slot: 0,
+            position: Vec2::new(50.0, 60.0),
+            color: None,
+            health: Some(40),
+        }
+    }
+
+    #[test]
+    fn late_joiner_gets_the_snapshot_state() {
+        let mut app = snapshot_app();
+        // replicated from values flushed before the snapshot was taken
+        let stale = spawn_confirmed_player(&mut app, 1, 10);
+        app.world.send_event(MessageEvent::new(
+            WorldSnapshot {
+                tick: Tick(20),
+                players: vec![player_snapshot(1)],
+            },
+            (),
+        ));
+        app.update();
+
+        let player = app.world.entity(stale);
+        assert_eq!(
+            player.get::<PlayerPosition>().unwrap().0,
+            Vec2::new(50.0, 60.0)
+        );
+        assert_eq!(player.get::<PlayerHealth>().unwrap().0, 40);
+    }
+
+    #[test]
+    fn snapshot_arriving_after_newer_updates_is_ignored() {
+        let mut app = snapshot_app();
+        let snapshot = WorldSnapshot {
+            tick: Tick(20),
+            players: vec![player_snapshot(1)],
+        };
+        app.world.send_event(MessageEvent::new(snapshot, ()));
+        app.update();
+        // replicated after the snapshot was taken, its state is the newer one
+        let fresh = spawn_confirmed_player(&mut app, 1, 30);
+        app.update();
+
+        let player = app.world.entity(fresh);
+        assert_eq!(player.get::<PlayerPosition>().unwrap().0, Vec2::ZERO);
+        assert_eq!(player.get::<PlayerHealth>().unwrap().0, PlayerHealth::MAX);
+        assert!(app.world.resource::<PendingSnapshot>().players.is_empty());
+    }
+
+    /// Stands in for the LDtk project, loading one only needs the file to exist
+    #[derive(Asset, TypePath)]
+    struct LevelFile;
+
+    struct LevelFileLoader;
+
+    impl AssetLoader for LevelFileLoader {
+        type Asset = LevelFile;
+        type Settings = ();
+        type Error = std::io::Error;
+
+        fn load<'a>(
+            &'a self,
+            _reader: &'a mut Reader,
+            _settings: &'a (),
+            _load_context: &'a mut LoadContext,
+        ) -> BoxedFuture<'a, Result<LevelFile, std::io::Error>> {
+            Box::pin(async { Ok(LevelFile) })
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &["ldtk"]
+        }
+    }
+
+    #[test]
+    fn missing_assets_are_named_on_screen() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()));
+        app.init_asset::<LevelFile>();
+        app.register_asset_loader(LevelFileLoader);
+        app.add_systems(Update, report_asset_load_failures);
+        let text = app
+            .world
+            .spawn((Text::from_section("", TextStyle::default()), AssetErrorText))
+            .id();
+        let _level: Handle<LevelFile> = app
+            .world
+            .resource::<AssetServer>()
+            .load("does_not_exist.ldtk");
+
+        // the asset is loaded on another thread
+        let start = std::time::Instant::now();
+        while app.world.get::<Text>(text).unwrap().sections[0]
+            .value
+            .is_empty()
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the failure was never reported"
+            );
+            app.update();
+        }
+
+        let message = &app.world.get::<Text>(text).unwrap().sections[0].value;
+        assert!(message.starts_with("The game files are incomplete"));
+        assert!(message.ends_with("Could not load does_not_exist.ldtk"));
+    }
 }
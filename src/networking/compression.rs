@@ -0,0 +1,275 @@
+//! Optional compression of the large messages sent over the reliable channels.
+//!
+//! A client that has compression enabled says so once connected. If the server has it enabled too, it
+//! then sends that client the messages larger than [`super::CompressionSettings::threshold_bytes`] as a
+//! [`Compressed`] message. Small messages, and everything sent to the other clients, go out as is. The
+//! client unpacks them into the usual message events, so the systems reading those can't tell the
+//! difference.
+//!
+//! Messages are compressed in the bitcode encoding lightyear sends them with, so that the threshold
+//! and the gain are measured on what actually goes on the wire.
+
+use std::fmt;
+
+use bevy::prelude::*;
+#[cfg(not(target_family = "wasm"))]
+use lightyear::connection::netcode::ClientId;
+use lightyear::prelude::client::*;
+#[cfg(not(target_family = "wasm"))]
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::protocol::{AcceptCompression, Channel1, ChatLine, ClientMut, Compressed};
+use super::SharedSettings;
+
+/// Offers compression to the server and unpacks the compressed messages
+pub struct CompressionPlugin;
+
+impl Plugin for CompressionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PreUpdate,
+            (accept_compression, unpack_compressed).after(MainSet::ReceiveFlush),
+        );
+    }
+}
+
+/// The messages that may be sent compressed. The ones sent right on connection, like the
+/// [`super::protocol::LevelSeed`], go out before the client could accept compression.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Compressible {
+    ChatLine(ChatLine),
+}
+
+impl From<ChatLine> for Compressible {
+    fn from(line: ChatLine) -> Self {
+        Compressible::ChatLine(line)
+    }
+}
+
+impl Compressible {
+    /// The message compressed, None when it is below the threshold or doesn't get any smaller
+    pub fn compress(&self, threshold_bytes: usize) -> Result<Option<Compressed>, CompressionError> {
+        let bytes = bitcode::serialize(self).map_err(CompressionError::Serialize)?;
+        if bytes.len() <= threshold_bytes {
+            return Ok(None);
+        }
+        let compressed = lz4_flex::compress_prepend_size(&bytes);
+        Ok((compressed.len() < bytes.len()).then_some(Compressed(compressed)))
+    }
+}
+
+impl Compressed {
+    pub fn decompress(&self) -> Result<Compressible, CompressionError> {
+        let bytes =
+            lz4_flex::decompress_size_prepended(&self.0).map_err(CompressionError::Decompress)?;
+        bitcode::deserialize(&bytes).map_err(CompressionError::Deserialize)
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressionError {
+    Serialize(bitcode::Error),
+    Decompress(lz4_flex::block::DecompressError),
+    Deserialize(bitcode::Error),
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::Serialize(e) => write!(f, "failed to serialize the message: {e}"),
+            CompressionError::Decompress(e) => write!(f, "failed to decompress the message: {e}"),
+            CompressionError::Deserialize(e) => {
+                write!(f, "failed to deserialize the decompressed message: {e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+/// Tell the server we can unpack compressed messages, on every connection since it forgets on disconnect
+fn accept_compression(
+    mut connections: EventReader<ConnectEvent>,
+    settings: Res<SharedSettings>,
+    mut client: ClientMut,
+) {
+    if connections.read().count() == 0 || settings.compression.is_none() {
+        return;
+    }
+    client
+        .send_message::<Channel1, AcceptCompression>(AcceptCompression)
+        .unwrap_or_else(|e| {
+            error!("Failed to accept compression: {:?}", e);
+        });
+}
+
+/// Turn the compressed messages back into the messages they hold
+fn unpack_compressed(
+    mut compressed: EventReader<MessageEvent<Compressed>>,
+    mut chat_lines: EventWriter<MessageEvent<ChatLine>>,
+) {
+    for message in compressed.read() {
+        match message.message().decompress() {
+            Ok(Compressible::ChatLine(line)) => {
+                chat_lines.send(MessageEvent::new(line, ()));
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+/// The clients that can unpack compressed messages
+#[cfg(not(target_family = "wasm"))]
+#[derive(Resource, Default, Debug)]
+pub(crate) struct CompressingClients(bevy::utils::HashSet<ClientId>);
+
+#[cfg(not(target_family = "wasm"))]
+impl CompressingClients {
+    /// Send `message` to `target`, compressed for the clients that accepted compression when it is
+    /// large enough. Failures are logged, `what` describes the message in the logs.
+    pub(crate) fn send<C: Channel, M: Message + Serialize + Clone + Into<Compressible>>(
+        &self,
+        server: &mut super::bandwidth::CountedServer,
+        settings: &SharedSettings,
+        message: M,
+        target: NetworkTarget,
+        what: &str,
+    ) {
+        let compressed = match settings.compression {
+            Some(compression) if !self.0.is_empty() => message
+                .clone()
+                .into()
+                .compress(compression.threshold_bytes)
+                .unwrap_or_else(|e| {
+                    warn!("Sending the {} uncompressed: {}", what, e);
+                    None
+                }),
+            _ => None,
+        };
+        let mut target = target;
+        if let Some(compressed) = compressed {
+            let recipients: Vec<ClientId> = self
+                .0
+                .iter()
+                .filter(|client_id| target.should_send_to(client_id))
+                .copied()
+                .collect();
+            if !recipients.is_empty() {
+                target = exclude(target, &recipients);
+                server
+                    .send_message_to_target::<C, Compressed>(
+                        compressed,
+                        NetworkTarget::Only(recipients),
+                    )
+                    .unwrap_or_else(|e| {
+                        error!("Failed to send the compressed {}: {:?}", what, e);
+                    });
+            }
+        }
+        if matches!(target, NetworkTarget::None) {
+            return;
+        }
+        server
+            .send_message_to_target::<C, M>(message, target)
+            .unwrap_or_else(|e| {
+                error!("Failed to send the {}: {:?}", what, e);
+            });
+    }
+}
+
+/// The same target without `clients`
+#[cfg(not(target_family = "wasm"))]
+fn exclude(target: NetworkTarget, clients: &[ClientId]) -> NetworkTarget {
+    match target {
+        NetworkTarget::All => NetworkTarget::AllExcept(clients.to_vec()),
+        NetworkTarget::AllExceptSingle(client_id) => {
+            let mut excluded = clients.to_vec();
+            excluded.push(client_id);
+            NetworkTarget::AllExcept(excluded)
+        }
+        NetworkTarget::AllExcept(mut excluded) => {
+            excluded.extend_from_slice(clients);
+            NetworkTarget::AllExcept(excluded)
+        }
+        NetworkTarget::Only(only) => NetworkTarget::Only(
+            only.into_iter()
+                .filter(|client_id| !clients.contains(client_id))
+                .collect(),
+        ),
+        NetworkTarget::Single(client_id) if clients.contains(&client_id) => NetworkTarget::None,
+        other => other,
+    }
+}
+
+/// Remember the clients accepting compression, if the server compresses at all
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn register_compressing_clients(
+    mut accepted: EventReader<lightyear::prelude::server::MessageEvent<AcceptCompression>>,
+    mut disconnections: EventReader<lightyear::prelude::server::DisconnectEvent>,
+    settings: Res<SharedSettings>,
+    mut compressing: ResMut<CompressingClients>,
+) {
+    for disconnection in disconnections.read() {
+        compressing.0.remove(disconnection.context());
+    }
+    if settings.compression.is_none() {
+        accepted.clear();
+        return;
+    }
+    for accept in accepted.read() {
+        compressing.0.insert(*accept.context());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::protocol::ChatKind;
+
+    /// What `/list` answers on a busy server
+    fn large_chat_line() -> Compressible {
+        let players: Vec<String> = (0..100)
+            .map(|i| format!("client {} (player 0, player 1)", 1000 + i))
+            .collect();
+        Compressible::ChatLine(ChatLine {
+            kind: ChatKind::System,
+            text: format!("Connected: {}", players.join(", ")),
+        })
+    }
+
+    #[test]
+    fn compressed_message_round_trips() {
+        let message = large_chat_line();
+        let compressed = message
+            .compress(0)
+            .unwrap()
+            .expect("large enough to compress");
+        assert_eq!(compressed.decompress().unwrap(), message);
+    }
+
+    #[test]
+    fn compression_saves_bandwidth_on_large_messages() {
+        let message = large_chat_line();
+        let wire_size = bitcode::serialize(&message).unwrap().len();
+        let compressed = message.compress(0).unwrap().unwrap();
+        let compressed_size = bitcode::serialize(&compressed).unwrap().len();
+        assert!(
+            compressed_size * 2 < wire_size,
+            "{} bytes compressed to {}",
+            wire_size,
+            compressed_size
+        );
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let message = Compressible::ChatLine(ChatLine {
+            kind: ChatKind::Say(1),
+            text: "hi".to_string(),
+        });
+        assert_eq!(message.compress(256).unwrap(), None);
+        // not worth it even without a threshold
+        assert_eq!(message.compress(0).unwrap(), None);
+    }
+}
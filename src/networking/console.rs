@@ -0,0 +1,463 @@
+//! Admin commands typed into the server's terminal, one per line.
+//!
+//! - `tp <client_id> <x> <y>`: move the player of a client to a position
+//! - `tp <client_id> <other_client_id>`: move the player of a client onto the player of another one
+//! - `bandwidth`: show how much each client sends and receives
+//! - `inspect <client_id>`: show the players, latency and traffic of a client
+//! - `rewind <client_id> <ticks>`: show where the player of a client was a few ticks ago
+//! - `reach <client_id> <other_client_id> <ticks>`: check whether the player of a client could have
+//!   interacted with the player of another one a few ticks ago, as lag compensation would judge it
+//! - `kick <client_id> [reason]`: disconnect a client, telling it why
+//! - `bot <x> <y>`: spawn a player driven by the server at a position
+
+use std::fmt;
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use crossbeam_channel::Receiver;
+use lightyear::connection::netcode::ClientId;
+use lightyear::prelude::server::ServerConnectionManager;
+use lightyear::prelude::TickManager;
+
+use crate::collision::nearest_free_position;
+use crate::level::LevelGrid;
+
+use super::bandwidth::{BandwidthStats, BANDWIDTH_WINDOW};
+use super::bots::spawn_bot;
+use super::lag_compensation::{PositionHistory, INTERACTION_REACH};
+use super::protocol::{
+    DisconnectReason, Knockback, PlayerHealth, PlayerId, PlayerPosition, PlayerSlot,
+};
+use super::server::{ConnectedPlayers, PendingDisconnects, Throttled};
+use super::{SharedSettings, WorldEdges};
+
+/// Reads admin commands from stdin and runs them on the server
+pub struct AdminConsolePlugin;
+
+impl Plugin for AdminConsolePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(AdminConsole::from_stdin());
+        app.add_systems(Update, run_admin_commands);
+    }
+}
+
+/// The lines typed into the terminal, read on their own thread since reading stdin blocks
+#[derive(Resource)]
+pub(crate) struct AdminConsole(Receiver<String>);
+
+impl AdminConsole {
+    fn from_stdin() -> Self {
+        let (send, recv) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        AdminConsole(recv)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum AdminCommand {
+    Teleport {
+        client_id: ClientId,
+        target: TeleportTarget,
+    },
+    Bandwidth,
+    Inspect(ClientId),
+    Rewind {
+        client_id: ClientId,
+        ticks: u16,
+    },
+    Reach {
+        client_id: ClientId,
+        other: ClientId,
+        ticks: u16,
+    },
+    Kick {
+        client_id: ClientId,
+        reason: Option<String>,
+    },
+    Bot(Vec2),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TeleportTarget {
+    Position(Vec2),
+    Player(ClientId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CommandError {
+    Unknown(String),
+    /// The arguments don't match the command, holds its usage
+    Usage(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "unknown command `{name}`"),
+            CommandError::Usage(usage) => write!(f, "usage: {usage}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+const TELEPORT_USAGE: &str = "tp <client_id> <x> <y> | tp <client_id> <other_client_id>";
+
+const INSPECT_USAGE: &str = "inspect <client_id>";
+
+const REWIND_USAGE: &str = "rewind <client_id> <ticks>";
+
+const REACH_USAGE: &str = "reach <client_id> <other_client_id> <ticks>";
+
+const KICK_USAGE: &str = "kick <client_id> [reason]";
+
+const BOT_USAGE: &str = "bot <x> <y>";
+
+impl FromStr for AdminCommand {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        match name {
+            "tp" => {
+                let usage = CommandError::Usage(TELEPORT_USAGE);
+                let client_id = args
+                    .first()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or(usage.clone())?;
+                let target = match args[1..] {
+                    [other] => TeleportTarget::Player(other.parse().map_err(|_| usage)?),
+                    [x, y] => {
+                        let x = x.parse().map_err(|_| usage.clone())?;
+                        let y = y.parse().map_err(|_| usage)?;
+                        TeleportTarget::Position(Vec2::new(x, y))
+                    }
+                    _ => return Err(usage),
+                };
+                Ok(AdminCommand::Teleport { client_id, target })
+            }
+            "bandwidth" => Ok(AdminCommand::Bandwidth),
+            "inspect" => match args[..] {
+                [client_id] => client_id
+                    .parse()
+                    .map(AdminCommand::Inspect)
+                    .map_err(|_| CommandError::Usage(INSPECT_USAGE)),
+                _ => Err(CommandError::Usage(INSPECT_USAGE)),
+            },
+            "rewind" => {
+                let usage = CommandError::Usage(REWIND_USAGE);
+                let [client_id, ticks] = args[..] else {
+                    return Err(usage);
+                };
+                let client_id = client_id.parse().map_err(|_| usage.clone())?;
+                let ticks = ticks.parse().map_err(|_| usage)?;
+                Ok(AdminCommand::Rewind { client_id, ticks })
+            }
+            "reach" => {
+                let usage = CommandError::Usage(REACH_USAGE);
+                let [client_id, other, ticks] = args[..] else {
+                    return Err(usage);
+                };
+                let client_id = client_id.parse().map_err(|_| usage.clone())?;
+                let other = other.parse().map_err(|_| usage.clone())?;
+                let ticks = ticks.parse().map_err(|_| usage)?;
+                Ok(AdminCommand::Reach {
+                    client_id,
+                    other,
+                    ticks,
+                })
+            }
+            "kick" => {
+                let client_id = args
+                    .first()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or(CommandError::Usage(KICK_USAGE))?;
+                let reason = args[1..].join(" ");
+                let reason = (!reason.is_empty()).then_some(reason);
+                Ok(AdminCommand::Kick { client_id, reason })
+            }
+            "bot" => {
+                let usage = CommandError::Usage(BOT_USAGE);
+                let [x, y] = args[..] else {
+                    return Err(usage);
+                };
+                let x = x.parse().map_err(|_| usage.clone())?;
+                let y = y.parse().map_err(|_| usage)?;
+                Ok(AdminCommand::Bot(Vec2::new(x, y)))
+            }
+            name => Err(CommandError::Unknown(name.to_string())),
+        }
+    }
+}
+
+/// The players as the admin commands see them
+type ConsolePlayers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static PlayerId,
+        &'static PlayerSlot,
+        &'static mut PlayerPosition,
+        // the replicated health lags behind until it is flushed
+        Option<&'static Throttled<PlayerHealth>>,
+    ),
+>;
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_admin_commands(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    console: Res<AdminConsole>,
+    bandwidth: Res<BandwidthStats>,
+    mut server: ResMut<ServerConnectionManager>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    connected_players: Res<ConnectedPlayers>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+    tick_manager: Res<TickManager>,
+    history: Res<PositionHistory>,
+    mut players: ConsolePlayers,
+) {
+    for line in console.0.try_iter() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match line.parse::<AdminCommand>() {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        match command {
+            AdminCommand::Teleport { client_id, target } => {
+                let Some((entity, _)) = main_player(&players, client_id) else {
+                    warn!("Client {} has no player to teleport", client_id);
+                    continue;
+                };
+                let destination = match target {
+                    TeleportTarget::Position(position) => position,
+                    TeleportTarget::Player(other) => {
+                        let Some((_, position)) = main_player(&players, other) else {
+                            warn!("Client {} has no player to teleport to", other);
+                            continue;
+                        };
+                        position
+                    }
+                };
+                let Some(destination) =
+                    teleport_destination(shared_settings.world_edges, &level, destination)
+                else {
+                    warn!("There is no room left in the level to teleport to");
+                    continue;
+                };
+                if let Ok((_, _, _, mut position, _)) = players.get_mut(entity) {
+                    // the client's prediction rolls back to the new position, and since it is further
+                    // than a player can walk, interpolation snaps to it instead of sliding there
+                    position.0 = destination;
+                }
+                // a knockback would carry on from the old position
+                commands.entity(entity).remove::<Knockback>();
+                info!(
+                    "Teleported the player of client {} to {}",
+                    client_id, destination
+                );
+            }
+            AdminCommand::Bandwidth => {
+                let window = BANDWIDTH_WINDOW.as_secs();
+                for (client_id, client) in bandwidth.clients() {
+                    let (rate_in, rate_out) = client.rates(time.elapsed());
+                    info!(
+                        "Client {}: in {} B ({:.0} B/s over {}s), out {} B ({:.0} B/s over {}s)",
+                        client_id,
+                        client.total_in,
+                        rate_in,
+                        window,
+                        client.total_out,
+                        rate_out,
+                        window
+                    );
+                }
+            }
+            AdminCommand::Inspect(client_id) => {
+                if !connected_players.contains(client_id) {
+                    warn!("Client {} is not connected", client_id);
+                    continue;
+                }
+                let rtt = match server.connection(client_id) {
+                    Ok(connection) => format!("{} ms", connection.rtt().as_millis()),
+                    Err(_) => "unknown".to_string(),
+                };
+                let (total_in, total_out) = bandwidth
+                    .get(client_id)
+                    .map_or((0, 0), |client| (client.total_in, client.total_out));
+                info!(
+                    "Client {}: rtt {}, in {} B, out {} B",
+                    client_id, rtt, total_in, total_out
+                );
+                let mut client_players: Vec<_> = players
+                    .iter()
+                    .filter(|(_, player_id, _, _, _)| player_id.0 == client_id)
+                    .collect();
+                client_players.sort_by_key(|(_, _, slot, _, _)| slot.0);
+                if client_players.is_empty() {
+                    info!("  no player spawned");
+                }
+                for (entity, _, slot, position, health) in client_players {
+                    let health = health.map_or("none".to_string(), |health| {
+                        format!("{}/{}", health.0 .0, PlayerHealth::MAX)
+                    });
+                    info!(
+                        "  player {:?} in slot {}: position {}, health {}",
+                        entity, slot.0, position.0, health
+                    );
+                }
+            }
+            AdminCommand::Rewind { client_id, ticks } => {
+                let Some((entity, position)) = main_player(&players, client_id) else {
+                    warn!("Client {} has no player", client_id);
+                    continue;
+                };
+                let tick = tick_manager.tick() - ticks;
+                match history.position_at(entity, tick) {
+                    Ok(past) => info!(
+                        "The player of client {} was at {} on tick {:?}, now at {}",
+                        client_id, past, tick, position
+                    ),
+                    Err(e) => warn!("Can't rewind the player of client {}: {}", client_id, e),
+                }
+            }
+            AdminCommand::Reach {
+                client_id,
+                other,
+                ticks,
+            } => {
+                let (Some((actor, _)), Some((target, _))) = (
+                    main_player(&players, client_id),
+                    main_player(&players, other),
+                ) else {
+                    warn!(
+                        "Clients {} and {} must both have a player",
+                        client_id, other
+                    );
+                    continue;
+                };
+                let tick = tick_manager.tick() - ticks;
+                match history.could_reach(actor, target, tick, INTERACTION_REACH) {
+                    Ok(reached) => info!(
+                        "On tick {:?} the player of client {} {} the player of client {}",
+                        tick,
+                        client_id,
+                        if reached {
+                            "could reach"
+                        } else {
+                            "was out of reach of"
+                        },
+                        other
+                    ),
+                    Err(e) => warn!("Can't rewind to tick {:?}: {}", tick, e),
+                }
+            }
+            AdminCommand::Kick { client_id, reason } => {
+                if !connected_players.contains(client_id) {
+                    warn!("Client {} is not connected", client_id);
+                    continue;
+                }
+                pending_disconnects.schedule(client_id, DisconnectReason::Kicked(reason));
+            }
+            AdminCommand::Bot(position) => {
+                let Some(position) = nearest_free_position(&level, position) else {
+                    warn!("There is no room left in the level for a bot");
+                    continue;
+                };
+                let entity = spawn_bot(&mut commands, position);
+                info!("Spawned bot {:?} at {}", entity, position);
+            }
+        }
+    }
+}
+
+/// Where a player teleported to `destination` lands, inside the level and out of the walls
+fn teleport_destination(edges: WorldEdges, level: &LevelGrid, destination: Vec2) -> Option<Vec2> {
+    nearest_free_position(level, edges.apply(destination, level.bounds()))
+}
+
+/// The first player of a client, split-screen players other than the first one can't be picked from the console
+fn main_player(players: &ConsolePlayers, client_id: ClientId) -> Option<(Entity, Vec2)> {
+    players
+        .iter()
+        .find(|(_, player_id, slot, _, _)| player_id.0 == client_id && **slot == PlayerSlot(0))
+        .map(|(entity, _, _, position, _)| (entity, position.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reach() {
+        assert_eq!(
+            "reach 1 2 10".parse::<AdminCommand>(),
+            Ok(AdminCommand::Reach {
+                client_id: 1,
+                other: 2,
+                ticks: 10
+            })
+        );
+        assert_eq!(
+            "reach 1 2".parse::<AdminCommand>(),
+            Err(CommandError::Usage(REACH_USAGE))
+        );
+    }
+
+    #[test]
+    fn parse_bot() {
+        assert_eq!(
+            "bot 10 -20.5".parse::<AdminCommand>(),
+            Ok(AdminCommand::Bot(Vec2::new(10.0, -20.5)))
+        );
+        assert_eq!(
+            "bot 10".parse::<AdminCommand>(),
+            Err(CommandError::Usage(BOT_USAGE))
+        );
+        assert_eq!(
+            "bot ten 20".parse::<AdminCommand>(),
+            Err(CommandError::Usage(BOT_USAGE))
+        );
+    }
+
+    #[test]
+    fn teleport_lands_out_of_walls() {
+        let level = LevelGrid::from_rows(&["...", ".#.", "..."]);
+        let destination = teleport_destination(WorldEdges::Clamp, &level, Vec2::splat(48.0))
+            .expect("there is room around the wall");
+        assert!(!level.overlaps_wall(destination, crate::collision::PLAYER_HITBOX));
+        // one of the cells next to the wall
+        assert_eq!(destination.distance(Vec2::splat(48.0)), 32.0);
+    }
+
+    #[test]
+    fn teleport_stays_in_the_level() {
+        let level = LevelGrid::from_rows(&["...", ".#.", "..."]);
+        let clamped = teleport_destination(WorldEdges::Clamp, &level, Vec2::new(-100.0, 16.0))
+            .expect("the edge is free");
+        assert!(level.bounds().contains(clamped));
+        assert!(!level.overlaps_wall(clamped, crate::collision::PLAYER_HITBOX));
+        assert_eq!(
+            teleport_destination(WorldEdges::Wrap, &level, Vec2::new(112.0, 16.0)),
+            Some(Vec2::new(16.0, 16.0))
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! What the player sees once disconnected: why the server dropped us, and a button to connect again.
+//!
+//! The server sends a [`DisconnectReason`] right before disconnecting a client. Without one, the
+//! connection was lost on the way, e.g. it timed out.
+
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use lightyear::prelude::client::*;
+
+use super::protocol::{ClientMut, DisconnectReason};
+use super::NetworkError;
+
+/// Keeps the reason of the last disconnection, and shows it on a disconnect screen
+pub struct DisconnectScreenPlugin;
+
+impl Plugin for DisconnectScreenPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LastDisconnectReason>();
+        app.add_systems(Update, receive_disconnect_reason);
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(
+                Update,
+                (show_disconnect_screen, reconnect_on_click).after(receive_disconnect_reason),
+            );
+        }
+    }
+}
+
+/// Why the server disconnected us the last time, kept until we are connected again
+#[derive(Resource, Default, Debug)]
+pub(crate) struct LastDisconnectReason(pub(crate) Option<DisconnectReason>);
+
+impl LastDisconnectReason {
+    /// What the disconnect screen says
+    fn text(&self) -> String {
+        match &self.0 {
+            Some(reason) => reason.to_string(),
+            None => "Lost the connection to the server".to_string(),
+        }
+    }
+}
+
+pub(crate) fn receive_disconnect_reason(
+    mut reasons: EventReader<MessageEvent<DisconnectReason>>,
+    mut connections: EventReader<ConnectEvent>,
+    mut last_reason: ResMut<LastDisconnectReason>,
+) {
+    // a new connection starts over, a later timeout must not show the previous reason
+    if connections.read().count() > 0 {
+        last_reason.0 = None;
+    }
+    for reason in reasons.read() {
+        warn!("The server is disconnecting us: {}", reason.message());
+        last_reason.0 = Some(reason.message().clone());
+    }
+}
+
+/// Marker for the screen covering the game while disconnected
+#[derive(Component)]
+pub(crate) struct DisconnectScreen;
+
+/// Marker for the button connecting to the server again
+#[derive(Component)]
+pub(crate) struct ReconnectButton;
+
+fn show_disconnect_screen(
+    mut commands: Commands,
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    last_reason: Res<LastDisconnectReason>,
+    screens: Query<Entity, With<DisconnectScreen>>,
+) {
+    if connections.read().count() > 0 {
+        for screen in screens.iter() {
+            commands.entity(screen).despawn_recursive();
+        }
+    }
+    if disconnections.read().count() == 0 || !screens.is_empty() {
+        return;
+    }
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(20.0),
+                    ..default()
+                },
+                background_color: Color::rgba(0.0, 0.0, 0.0, 0.7).into(),
+                ..default()
+            },
+            DisconnectScreen,
+        ))
+        .with_children(|screen| {
+            screen.spawn(TextBundle::from_section(
+                last_reason.text(),
+                TextStyle {
+                    font_size: 32.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            ));
+            screen
+                .spawn((
+                    ButtonBundle {
+                        style: Style {
+                            padding: UiRect::axes(Val::Px(20.0), Val::Px(10.0)),
+                            ..default()
+                        },
+                        background_color: Color::rgb(0.25, 0.25, 0.3).into(),
+                        ..default()
+                    },
+                    ReconnectButton,
+                ))
+                .with_children(|button| {
+                    button.spawn(TextBundle::from_section(
+                        "Reconnect",
+                        TextStyle {
+                            font_size: 24.0,
+                            color: Color::WHITE,
+                            ..default()
+                        },
+                    ));
+                });
+        });
+}
+
+/// Connect again when the button is clicked, the reconnect token then gives us our player back
+fn reconnect_on_click(
+    mut client: ClientMut,
+    buttons: Query<&Interaction, (Changed<Interaction>, With<ReconnectButton>)>,
+) {
+    for interaction in buttons.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        info!("Reconnecting to the server");
+        if let Err(e) = client.connect() {
+            error!("{}", NetworkError::Connect(format!("{e:?}")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<LastDisconnectReason>();
+        app.add_event::<MessageEvent<DisconnectReason>>();
+        app.add_event::<ConnectEvent>();
+        app.add_systems(Update, receive_disconnect_reason);
+        app
+    }
+
+    #[test]
+    fn kicked_client_stores_the_reason() {
+        let mut app = reason_app();
+        let reason = DisconnectReason::Kicked(Some("spamming the chat".to_string()));
+        app.world.send_event(MessageEvent::new(reason.clone(), ()));
+        app.update();
+
+        let last_reason = app.world.resource::<LastDisconnectReason>();
+        assert_eq!(last_reason.0, Some(reason));
+        assert_eq!(last_reason.text(), "Kicked by an admin: spamming the chat");
+    }
+
+    #[test]
+    fn reconnecting_forgets_the_reason() {
+        let mut app = reason_app();
+        app.world
+            .send_event(MessageEvent::new(DisconnectReason::Kicked(None), ()));
+        app.update();
+        app.world.send_event(ConnectEvent::new(1));
+        app.update();
+
+        let last_reason = app.world.resource::<LastDisconnectReason>();
+        assert_eq!(last_reason.0, None);
+        assert_eq!(last_reason.text(), "Lost the connection to the server");
+    }
+}
@@ -0,0 +1,199 @@
+use std::marker::PhantomData;
+
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::prelude::*;
+use bevy::utils::Duration;
+
+use lightyear::prelude::client::*;
+
+use super::{ClientSettings, SharedSettings};
+
+/// Tunes the interpolation delay of remote players to the quality of the connection
+pub struct AdaptiveInterpolationPlugin;
+
+impl Plugin for AdaptiveInterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterpolationDelayEstimate>();
+        app.register_diagnostic(Diagnostic::new(INTERPOLATION_DELAY).with_suffix("ms"));
+        app.add_systems(Update, adapt_interpolation_delay);
+    }
+}
+
+/// The current interpolation delay, shown in the inspector
+pub const INTERPOLATION_DELAY: DiagnosticPath = DiagnosticPath::const_new("interpolation_delay");
+
+/// The interpolation delay currently in use, in seconds
+#[derive(Resource, Default, Debug)]
+pub(crate) struct InterpolationDelayEstimate(f32);
+
+/// Raise the interpolation delay when the jitter goes up, so that remote players keep moving smoothly
+/// even when server updates arrive irregularly, and lower it again once the connection is stable
+fn adapt_interpolation_delay(
+    time: Res<Time>,
+    settings: Res<ClientSettings>,
+    connection: Res<ClientConnectionManager>,
+    mut config: ResMut<ClientConfig>,
+    mut estimate: ResMut<InterpolationDelayEstimate>,
+    mut diagnostics: Diagnostics,
+) {
+    let bounds = &settings.interpolation_delay;
+    let min = Duration::from_millis(bounds.min_delay_ms).as_secs_f32();
+    let max = Duration::from_millis(bounds.max_delay_ms).as_secs_f32();
+    let target = (min + connection.jitter().as_secs_f32() * bounds.jitter_factor).clamp(min, max);
+    // move towards the target gradually, jumping straight to it would make the delay oscillate
+    let step = (bounds.adaptation_rate * time.delta_seconds()).min(1.0);
+    estimate.0 = (estimate.0 + (target - estimate.0) * step).clamp(min, max);
+    config.interpolation.delay.min_delay = Duration::from_secs_f32(estimate.0);
+    diagnostics.add_measurement(&INTERPOLATION_DELAY, || estimate.0 as f64 * 1000.0);
+}
+
+/// A replicated component that is shown as a number, like a health bar, see [`SmoothComponentExt`]
+pub trait SmoothValue: Component {
+    fn value(&self) -> f32;
+}
+
+/// The value shown for a `C`, which slides to each new replicated value instead of jumping to it
+#[derive(Component, Debug)]
+pub struct Smoothed<C> {
+    pub value: f32,
+    from: f32,
+    to: f32,
+    elapsed: Duration,
+    _component: PhantomData<C>,
+}
+
+impl<C> Smoothed<C> {
+    fn new(value: f32) -> Self {
+        Self {
+            value,
+            from: value,
+            to: value,
+            elapsed: Duration::ZERO,
+            _component: PhantomData,
+        }
+    }
+
+    /// Start sliding from the value shown now to `to`
+    fn retarget(&mut self, to: f32) {
+        self.from = self.value;
+        self.to = to;
+        self.elapsed = Duration::ZERO;
+    }
+
+    /// Advance the slide, it takes `duration` to reach the target
+    fn advance(&mut self, delta: Duration, duration: Duration) {
+        self.elapsed += delta;
+        let t = if duration.is_zero() {
+            1.0
+        } else {
+            (self.elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0)
+        };
+        self.value = self.from + (self.to - self.from) * t;
+    }
+}
+
+pub trait SmoothComponentExt {
+    /// Keep a [`Smoothed<C>`] next to every `C`, which slides between the replicated values
+    /// over the interval they are sent at ([`super::ReplicationRates::slow_interval_ms`])
+    fn smooth_component<C: SmoothValue>(&mut self) -> &mut Self;
+}
+
+impl SmoothComponentExt for App {
+    fn smooth_component<C: SmoothValue>(&mut self) -> &mut Self {
+        self.add_systems(Update, (add_smoothed::<C>, smooth::<C>).chain())
+    }
+}
+
+fn add_smoothed<C: SmoothValue>(
+    mut commands: Commands,
+    components: Query<(Entity, &C), Without<Smoothed<C>>>,
+) {
+    for (entity, component) in components.iter() {
+        commands
+            .entity(entity)
+            .insert(Smoothed::<C>::new(component.value()));
+    }
+}
+
+fn smooth<C: SmoothValue>(
+    time: Res<Time>,
+    shared_settings: Res<SharedSettings>,
+    mut components: Query<(Ref<C>, &mut Smoothed<C>)>,
+) {
+    let duration = Duration::from_millis(shared_settings.replication.slow_interval_ms);
+    for (component, mut smoothed) in components.iter_mut() {
+        if component.is_changed() && component.value() != smoothed.to {
+            smoothed.retarget(component.value());
+        }
+        if smoothed.value != smoothed.to {
+            smoothed.advance(time.delta(), duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::protocol::PlayerHealth;
+    use crate::networking::Settings;
+
+    fn assert_shows(smoothed: &Smoothed<PlayerHealth>, value: f32) {
+        assert!(
+            (smoothed.value - value).abs() < 1e-3,
+            "shows {} instead of {}",
+            smoothed.value,
+            value
+        );
+    }
+
+    #[test]
+    fn advance_slides_to_the_target() {
+        let mut smoothed = Smoothed::<PlayerHealth>::new(100.0);
+        smoothed.retarget(60.0);
+        let duration = Duration::from_millis(400);
+        smoothed.advance(Duration::from_millis(100), duration);
+        assert_shows(&smoothed, 90.0);
+        smoothed.advance(Duration::from_millis(200), duration);
+        assert_shows(&smoothed, 70.0);
+        // never past the target
+        smoothed.advance(Duration::from_secs(1), duration);
+        assert_shows(&smoothed, 60.0);
+
+        // a new value arriving halfway slides on from what is shown
+        smoothed.retarget(100.0);
+        smoothed.advance(Duration::from_millis(200), duration);
+        assert_shows(&smoothed, 80.0);
+    }
+
+    #[test]
+    fn intermediate_frames_show_values_in_between() {
+        let mut app = App::new();
+        app.init_resource::<Time>();
+        let mut shared = Settings::embedded().shared;
+        shared.replication.slow_interval_ms = 400;
+        app.insert_resource(shared);
+        app.smooth_component::<PlayerHealth>();
+        let player = app.world.spawn(PlayerHealth(100)).id();
+        app.update();
+
+        app.world.get_mut::<PlayerHealth>(player).unwrap().0 = 60;
+        let mut shown = vec![];
+        for _ in 0..5 {
+            app.world
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_millis(100));
+            app.update();
+            shown.push(
+                app.world
+                    .get::<Smoothed<PlayerHealth>>(player)
+                    .unwrap()
+                    .value,
+            );
+        }
+        assert!(shown[..3]
+            .iter()
+            .all(|value| *value > 60.0 && *value < 100.0));
+        assert!(shown.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(shown[4], 60.0);
+    }
+}
@@ -0,0 +1,127 @@
+//! Loading of the Netcode authentication secrets from outside of `settings.ron`,
+//! so that a dedicated server doesn't need to commit its private key with the rest of the config.
+//!
+//! The values are looked up in this order, the first one found wins:
+//! 1. the `MATRIX_RPG_PRIVATE_KEY` / `MATRIX_RPG_PROTOCOL_ID` environment variables
+//! 2. the RON key file pointed to by `MATRIX_RPG_KEY_FILE`
+//! 3. the values embedded in `settings.ron`
+
+use std::fmt;
+use std::path::Path;
+
+use base64::Engine;
+use bevy::scene::ron;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use super::SharedSettings;
+
+pub const PRIVATE_KEY_ENV: &str = "MATRIX_RPG_PRIVATE_KEY";
+pub const PROTOCOL_ID_ENV: &str = "MATRIX_RPG_PROTOCOL_ID";
+pub const KEY_FILE_ENV: &str = "MATRIX_RPG_KEY_FILE";
+
+/// Contents of a key file, both fields are optional so a file can override only one of them
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct KeyFile {
+    /// An id to identify the protocol version
+    pub protocol_id: Option<u64>,
+
+    /// The private key, encoded as hex or base64
+    pub private_key: Option<String>,
+}
+
+/// Generate a random protocol id and private key, taken from the OS's secure RNG
+pub fn generate_keys() -> (u64, [u8; 32]) {
+    let mut private_key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut private_key);
+    (rand::rngs::OsRng.next_u64(), private_key)
+}
+
+impl KeyFile {
+    pub fn new(protocol_id: u64, private_key: &[u8; 32]) -> Self {
+        KeyFile {
+            protocol_id: Some(protocol_id),
+            private_key: Some(encode_private_key(private_key)),
+        }
+    }
+
+    /// Write the key file as RON, so that it can be loaded through `MATRIX_RPG_KEY_FILE`
+    pub fn write(&self, path: &Path) -> Result<(), KeyError> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .expect("a key file can always be serialized");
+        std::fs::write(path, contents).map_err(KeyError::Io)
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// The key file could not be read
+    Io(std::io::Error),
+    /// The key file is not valid RON
+    Parse(ron::de::SpannedError),
+    /// The private key is neither valid hex nor valid base64
+    Encoding(String),
+    /// The decoded private key doesn't have the expected length
+    Length(usize),
+    /// The protocol id is not a valid u64
+    ProtocolId(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(e) => write!(f, "could not read the key file: {e}"),
+            KeyError::Parse(e) => write!(f, "could not parse the key file: {e}"),
+            KeyError::Encoding(key) => {
+                write!(f, "the private key `{key}` is neither valid hex nor base64")
+            }
+            KeyError::Length(len) => {
+                write!(f, "the private key must be exactly 32 bytes, got {len}")
+            }
+            KeyError::ProtocolId(id) => write!(f, "the protocol id `{id}` is not a valid u64"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Decode a private key given either as 64 hex characters or as base64
+pub fn decode_private_key(encoded: &str) -> Result<[u8; 32], KeyError> {
+    let encoded = encoded.trim();
+    let bytes = hex::decode(encoded)
+        .or_else(|_| base64::engine::general_purpose::STANDARD.decode(encoded))
+        .map_err(|_| KeyError::Encoding(encoded.to_string()))?;
+    let len = bytes.len();
+    bytes.try_into().map_err(|_| KeyError::Length(len))
+}
+
+/// Encode a private key in the format expected by [`decode_private_key`]
+pub fn encode_private_key(key: &[u8; 32]) -> String {
+    hex::encode(key)
+}
+
+impl SharedSettings {
+    /// Replace the protocol id and private key with the ones from the key file or the environment, if any
+    pub fn load_key_overrides(&mut self) -> Result<(), KeyError> {
+        if let Ok(path) = std::env::var(KEY_FILE_ENV) {
+            let contents = std::fs::read_to_string(path).map_err(KeyError::Io)?;
+            let key_file = ron::de::from_str::<KeyFile>(&contents).map_err(KeyError::Parse)?;
+            if let Some(protocol_id) = key_file.protocol_id {
+                self.protocol_id = protocol_id;
+            }
+            if let Some(private_key) = key_file.private_key {
+                self.private_key = decode_private_key(&private_key)?;
+            }
+        }
+        if let Ok(protocol_id) = std::env::var(PROTOCOL_ID_ENV) {
+            self.protocol_id = protocol_id
+                .trim()
+                .parse()
+                .map_err(|_| KeyError::ProtocolId(protocol_id))?;
+        }
+        if let Ok(private_key) = std::env::var(PRIVATE_KEY_ENV) {
+            self.private_key = decode_private_key(&private_key)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Past positions of the players, to check an interaction against the world the client saw when it
+//! acted, which the server had already left behind by a round trip.
+//!
+//! The history only goes back [`ServerSettings::lag_compensation_ticks`], so that a client with a huge
+//! ping can't make the server rewind (and search) arbitrarily far.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::*;
+
+use super::protocol::PlayerPosition;
+use super::ServerSettings;
+
+/// How close (in pixels) a player must be to another one to interact with it
+pub const INTERACTION_REACH: f32 = 48.0;
+
+/// Records the positions of the players every tick
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PositionHistory>();
+        // after the movements of the tick
+        app.add_systems(
+            FixedPostUpdate,
+            record_positions.run_if(lag_compensation_enabled),
+        );
+    }
+}
+
+/// The positions of each player over the last ticks, oldest first
+#[derive(Resource, Default, Debug)]
+pub struct PositionHistory(HashMap<Entity, VecDeque<(Tick, Vec2)>>);
+
+impl PositionHistory {
+    fn record(&mut self, entity: Entity, tick: Tick, position: Vec2, window: usize) {
+        let history = self.0.entry(entity).or_default();
+        history.push_back((tick, position));
+        while history.len() > window {
+            history.pop_front();
+        }
+    }
+
+    /// Where a player was on `tick`, as long as it is within the recorded window
+    pub fn position_at(&self, entity: Entity, tick: Tick) -> Result<Vec2, RewindError> {
+        let history = self.0.get(&entity).ok_or(RewindError::UnknownPlayer)?;
+        let (oldest, _) = history.front().ok_or(RewindError::UnknownPlayer)?;
+        let (newest, _) = history.back().ok_or(RewindError::UnknownPlayer)?;
+        // ticks wrap around, compare their difference instead of their values
+        if tick - *oldest < 0 || tick - *newest > 0 {
+            return Err(RewindError::OutsideWindow);
+        }
+        // a paused world skips ticks, the last position recorded before `tick` is still the right one
+        history
+            .iter()
+            .rev()
+            .find(|(recorded, _)| *recorded - tick <= 0)
+            .map(|(_, position)| *position)
+            .ok_or(RewindError::OutsideWindow)
+    }
+
+    /// Whether the player `actor` could reach `target` on `tick`, where both were back then.
+    /// This is what an interaction a client made on `tick` (e.g. hitting another player) is
+    /// validated against, since that is the world the client saw when it acted.
+    pub fn could_reach(
+        &self,
+        actor: Entity,
+        target: Entity,
+        tick: Tick,
+        reach: f32,
+    ) -> Result<bool, RewindError> {
+        let from = self.position_at(actor, tick)?;
+        let to = self.position_at(target, tick)?;
+        Ok(from.distance(to) <= reach)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewindError {
+    /// No position was recorded for the entity, it isn't a player or just spawned
+    UnknownPlayer,
+    /// The tick is older than the lag compensation window, or hasn't happened yet
+    OutsideWindow,
+}
+
+impl fmt::Display for RewindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RewindError::UnknownPlayer => write!(f, "no position was recorded for this player"),
+            RewindError::OutsideWindow => {
+                write!(f, "the tick is outside of the lag compensation window")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RewindError {}
+
+fn lag_compensation_enabled(settings: Res<ServerSettings>) -> bool {
+    settings.lag_compensation_ticks > 0
+}
+
+pub(crate) fn record_positions(
+    tick_manager: Res<TickManager>,
+    settings: Res<ServerSettings>,
+    players: Query<(Entity, &PlayerPosition)>,
+    mut history: ResMut<PositionHistory>,
+) {
+    let tick = tick_manager.tick();
+    let window = settings.lag_compensation_ticks as usize;
+    for (entity, position) in players.iter() {
+        history.record(entity, tick, position.0, window);
+    }
+    // forget the players that were removed
+    history.0.retain(|entity, _| players.contains(*entity));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: usize = 3;
+
+    /// Two players walking towards each other, 10 pixels a tick each, recorded on ticks 1 to 5
+    fn history() -> (PositionHistory, Entity, Entity) {
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut history = PositionHistory::default();
+        for tick in 1..=5 {
+            let offset = tick as f32 * 10.0;
+            history.record(a, Tick(tick), Vec2::new(offset, 0.0), WINDOW);
+            history.record(b, Tick(tick), Vec2::new(200.0 - offset, 0.0), WINDOW);
+        }
+        (history, a, b)
+    }
+
+    #[test]
+    fn history_is_bounded_by_the_window() {
+        let (history, a, _) = history();
+        assert_eq!(history.position_at(a, Tick(3)), Ok(Vec2::new(30.0, 0.0)));
+        assert_eq!(history.position_at(a, Tick(5)), Ok(Vec2::new(50.0, 0.0)));
+        assert_eq!(
+            history.position_at(a, Tick(2)),
+            Err(RewindError::OutsideWindow)
+        );
+        assert_eq!(
+            history.position_at(a, Tick(6)),
+            Err(RewindError::OutsideWindow)
+        );
+        assert_eq!(
+            history.position_at(Entity::from_raw(3), Tick(4)),
+            Err(RewindError::UnknownPlayer)
+        );
+    }
+
+    #[test]
+    fn interaction_within_the_window_is_validated_on_its_tick() {
+        let (history, a, b) = history();
+        // 100 pixels apart on tick 5, 140 on tick 3
+        assert_eq!(history.could_reach(a, b, Tick(5), 100.0), Ok(true));
+        assert_eq!(history.could_reach(a, b, Tick(3), 100.0), Ok(false));
+    }
+
+    #[test]
+    fn interaction_outside_the_window_is_refused() {
+        let (history, a, b) = history();
+        assert_eq!(
+            history.could_reach(a, b, Tick(1), 1000.0),
+            Err(RewindError::OutsideWindow)
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! An icon in the corner of the screen showing how good the connection to the server is, as bars.
+//!
+//! The round trip time and the jitter come from lightyear's measurements, and the loss from the
+//! share of our inputs the server reports missing, see [`InputLoss`]. Each of them is graded, and the
+//! worst grade gives the number of bars lit.
+
+use bevy::prelude::*;
+use bevy::render::RenderPlugin;
+use bevy::utils::Duration;
+use lightyear::prelude::client::*;
+
+use super::protocol::InputLoss;
+use super::ClientSettings;
+
+/// Shows the link quality indicator, only when rendering
+pub struct LinkQualityPlugin;
+
+impl Plugin for LinkQualityPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<RenderPlugin>() {
+            return;
+        }
+        app.init_resource::<LinkQuality>();
+        app.add_systems(Startup, spawn_link_indicator);
+        app.add_systems(
+            Update,
+            (track_link, update_link_indicator, toggle_link_indicator).chain(),
+        );
+    }
+}
+
+pub const TOGGLE_LINK_INDICATOR_KEY: KeyCode = KeyCode::F4;
+
+/// How often the indicator is updated
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of bars of the indicator
+const BARS: u8 = 4;
+
+/// What the indicator is based on
+#[derive(Resource, Default, Debug)]
+pub(crate) struct LinkQuality {
+    connected: bool,
+    /// Share of our inputs the server missed over its last report, between 0 and 1
+    input_loss: f32,
+    timer: Timer,
+}
+
+/// How many bars are lit for these conditions, from 1 for a barely usable connection to [`BARS`]
+fn grade(rtt: Duration, jitter: Duration, input_loss: f32) -> u8 {
+    let rtt = match rtt.as_millis() {
+        0..=80 => 4,
+        81..=150 => 3,
+        151..=250 => 2,
+        _ => 1,
+    };
+    let jitter = match jitter.as_millis() {
+        0..=10 => 4,
+        11..=30 => 3,
+        31..=60 => 2,
+        _ => 1,
+    };
+    let loss = match input_loss {
+        loss if loss <= 0.01 => 4,
+        loss if loss <= 0.05 => 3,
+        loss if loss <= 0.15 => 2,
+        _ => 1,
+    };
+    rtt.min(jitter).min(loss)
+}
+
+/// The color of the lit bars
+fn grade_color(bars: u8) -> Color {
+    match bars {
+        4 => Color::rgb(0.3, 0.85, 0.3),
+        3 => Color::rgb(0.85, 0.85, 0.3),
+        2 => Color::rgb(0.95, 0.55, 0.2),
+        _ => Color::rgb(0.9, 0.25, 0.25),
+    }
+}
+
+const UNLIT_BAR_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.2);
+
+/// Marker for the node holding the bars
+#[derive(Component)]
+pub(crate) struct LinkIndicator;
+
+/// A bar of the indicator, the first one is the shortest
+#[derive(Component)]
+pub(crate) struct LinkBar(u8);
+
+fn spawn_link_indicator(mut commands: Commands, settings: Res<ClientSettings>) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(10.0),
+                    right: Val::Px(10.0),
+                    align_items: AlignItems::FlexEnd,
+                    column_gap: Val::Px(2.0),
+                    ..default()
+                },
+                visibility: if settings.show_link_quality {
+                    Visibility::Inherited
+                } else {
+                    Visibility::Hidden
+                },
+                ..default()
+            },
+            LinkIndicator,
+        ))
+        .with_children(|indicator| {
+            for bar in 0..BARS {
+                indicator.spawn((
+                    NodeBundle {
+                        style: Style {
+                            width: Val::Px(4.0),
+                            height: Val::Px(4.0 * f32::from(bar + 1)),
+                            ..default()
+                        },
+                        background_color: UNLIT_BAR_COLOR.into(),
+                        ..default()
+                    },
+                    LinkBar(bar),
+                ));
+            }
+        });
+}
+
+pub(crate) fn track_link(
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut reports: EventReader<MessageEvent<InputLoss>>,
+    mut quality: ResMut<LinkQuality>,
+) {
+    if connections.read().count() > 0 {
+        quality.connected = true;
+        // the previous connection's loss says nothing about this one
+        quality.input_loss = 0.0;
+    }
+    if disconnections.read().count() > 0 {
+        quality.connected = false;
+    }
+    if let Some(report) = reports.read().last() {
+        quality.input_loss = report.message().0;
+    }
+}
+
+fn update_link_indicator(
+    time: Res<Time>,
+    connection: Res<ClientConnectionManager>,
+    mut quality: ResMut<LinkQuality>,
+    mut bars: Query<(&LinkBar, &mut BackgroundColor)>,
+) {
+    if quality.timer.duration() != UPDATE_INTERVAL {
+        quality.timer = Timer::new(UPDATE_INTERVAL, TimerMode::Repeating);
+    }
+    if !quality.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    // nothing is lit while disconnected
+    let lit = if quality.connected {
+        grade(connection.rtt(), connection.jitter(), quality.input_loss)
+    } else {
+        0
+    };
+    for (bar, mut color) in bars.iter_mut() {
+        color.0 = if bar.0 < lit {
+            grade_color(lit)
+        } else {
+            UNLIT_BAR_COLOR
+        };
+    }
+}
+
+fn toggle_link_indicator(
+    keypress: Res<ButtonInput<KeyCode>>,
+    mut indicators: Query<&mut Visibility, With<LinkIndicator>>,
+) {
+    if !keypress.just_pressed(TOGGLE_LINK_INDICATOR_KEY) {
+        return;
+    }
+    for mut visibility in indicators.iter_mut() {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
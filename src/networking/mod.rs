@@ -1,23 +1,92 @@
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 
 use bevy::prelude::*;
 use bevy::render::RenderPlugin;
-use bevy::utils::Duration;
+use bevy::scene::ron;
+use bevy::utils::{Duration, HashSet};
 
-use lightyear::{client::components::Confirmed, prelude::*};
+use lightyear::connection::netcode::ClientId;
+use lightyear::prelude::client::{Interpolated, Predicted};
+use lightyear::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
-use self::protocol::{Inputs, PlayerPosition};
+use crate::collision::move_and_slide;
+use crate::fixed_point::{move_and_slide_fixed, FixedVec2};
+use crate::level::{Cell, LevelGrid};
 
+use self::protocol::{Inputs, Knockback, PlayerPosition, PlayerVelocity};
+
+#[cfg(not(target_family = "wasm"))]
+mod bandwidth;
+#[cfg(not(target_family = "wasm"))]
+pub mod bots;
+pub mod chat;
 pub mod client;
+pub mod compression;
+#[cfg(not(target_family = "wasm"))]
+mod console;
+pub mod disconnect;
+pub mod interpolation;
+#[cfg(not(target_family = "wasm"))]
+pub mod keys;
+#[cfg(not(target_family = "wasm"))]
+pub mod lag_compensation;
+pub mod link_quality;
+pub mod name_tags;
 pub mod protocol;
 #[cfg(not(target_family = "wasm"))]
+mod reconnect;
+#[cfg(not(target_family = "wasm"))]
 pub mod server;
+pub mod tick_sync;
+#[cfg(not(target_family = "wasm"))]
+pub mod tls;
+
+/// How many players can share a client in split-screen
+pub const MAX_LOCAL_PLAYERS: u8 = 2;
 
-pub fn shared_config() -> SharedConfig {
+/// Ids of the players driven by the server, clients can't connect with them
+pub const BOT_IDS: RangeInclusive<ClientId> = (1 << 63)..=ClientId::MAX;
+
+/// A random id for a client, outside of [`BOT_IDS`]
+pub fn random_client_id(rng: &mut impl Rng) -> ClientId {
+    rng.gen_range(0..*BOT_IDS.start())
+}
+
+/// Which side of the game a [`SessionRng`] is used by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RngRole {
+    Server,
+    Client,
+}
+
+/// Where everything random in a session comes from, except the network keys.
+/// Seeded from [`SharedSettings::rng_seed`] to get the same client ids and generated levels again.
+#[derive(Resource, Debug)]
+pub struct SessionRng(pub StdRng);
+
+impl SessionRng {
+    /// Every process started from the same settings shares the seed, so each role and instance
+    /// gets its own stream of it, otherwise all the clients would draw the same id
+    pub fn new(seed: Option<u64>, role: RngRole, instance: u32) -> Self {
+        Self(match seed {
+            Some(seed) => {
+                let stream = (role as u64) << 32 | u64::from(instance);
+                StdRng::seed_from_u64(seed ^ stream)
+            }
+            None => StdRng::from_entropy(),
+        })
+    }
+}
+
+pub fn shared_config(settings: &SharedSettings) -> SharedConfig {
     SharedConfig {
         client_send_interval: Duration::default(),
-        server_send_interval: Duration::from_millis(40),
+        server_send_interval: Duration::from_millis(settings.replication.position_interval_ms),
         // server_send_interval: Duration::from_millis(100),
         tick: TickConfig {
             tick_duration: Duration::from_secs_f64(1.0 / 64.0),
@@ -30,7 +99,17 @@ pub struct SharedPlugin;
 impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
         if app.is_plugin_added::<RenderPlugin>() {
-            app.add_systems(PostUpdate, draw_elements);
+            app.init_resource::<ShowDebugGizmos>();
+            app.add_systems(
+                Update,
+                toggle_debug_gizmos.run_if(resource_exists::<ButtonInput<KeyCode>>),
+            );
+            app.add_systems(
+                PostUpdate,
+                draw_elements
+                    .run_if(resource_exists::<DebugPalette>)
+                    .run_if(resource_equals(ShowDebugGizmos(true))),
+            );
             // app.add_plugins(LogDiagnosticsPlugin {
             //     filter: Some(vec![
             //         IoDiagnosticsPlugin::BYTES_IN,
@@ -42,51 +121,275 @@ impl Plugin for SharedPlugin {
     }
 }
 
+/// Speed of a walking player in pixels per tick, reached right away unless the players accelerate
+const MOVE_SPEED: f32 = 10.0;
+
 // This system defines how we update the player's positions when we receive an input
-pub(crate) fn shared_movement_behaviour(mut position: Mut<PlayerPosition>, input: &Inputs) {
-    const MOVE_SPEED: f32 = 10.0;
+pub(crate) fn shared_movement_behaviour(
+    mut position: Mut<PlayerPosition>,
+    velocity: Option<Mut<PlayerVelocity>>,
+    input: &Inputs,
+    level: &LevelGrid,
+    settings: &SharedSettings,
+) {
+    let mut target = Vec2::ZERO;
     if let Inputs::Direction(direction) = input {
         if direction.up {
-            position.y += MOVE_SPEED;
+            target.y += MOVE_SPEED;
         }
         if direction.down {
-            position.y -= MOVE_SPEED;
+            target.y -= MOVE_SPEED;
         }
         if direction.left {
-            position.x -= MOVE_SPEED;
+            target.x -= MOVE_SPEED;
         }
         if direction.right {
-            position.x += MOVE_SPEED;
+            target.x += MOVE_SPEED;
+        }
+    }
+    let terrain = level.terrain_at(position.0);
+    target *= terrain.speed_factor();
+    let acceleration = match (settings.acceleration, terrain) {
+        (Some(acceleration), _) => Some(acceleration.with_grip(terrain.grip())),
+        // players slide on ice even when they otherwise start and stop at once
+        (None, Cell::Ice) => Some(INSTANT_ACCELERATION.with_grip(terrain.grip())),
+        (None, _) => None,
+    };
+    let (acceleration, mut velocity) = match (acceleration, velocity) {
+        (Some(acceleration), Some(velocity)) => (acceleration, velocity),
+        (_, velocity) => {
+            if let Some(mut velocity) = velocity {
+                // left over from sliding on ice
+                if velocity.0 != Vec2::ZERO {
+                    velocity.0 = Vec2::ZERO;
+                }
+            }
+            if target != Vec2::ZERO {
+                position.0 = move_player(level, position.0, target, settings);
+            }
+            return;
+        }
+    };
+    let mut speed = match input {
+        // stopping on purpose doesn't coast
+        Inputs::Stop => Vec2::ZERO,
+        _ => acceleration.step(velocity.0, target),
+    };
+    if settings.fixed_point_movement {
+        // the velocity is carried over to the next ticks, it must not drift either
+        speed = FixedVec2::from_vec2(speed).to_vec2();
+    }
+    if speed != Vec2::ZERO {
+        let moved = move_player(level, position.0, speed, settings);
+        // running into a wall stops the player along that axis, instead of keeping its momentum
+        if moved.x == position.0.x {
+            speed.x = 0.0;
+        }
+        if moved.y == position.0.y {
+            speed.y = 0.0;
+        }
+        position.0 = moved;
+    }
+    // only written when it changes, like the position
+    if velocity.0 != speed {
+        velocity.0 = speed;
+    }
+}
+
+/// Move a player by `delta`, against the walls and the edges of the level
+fn move_player(level: &LevelGrid, position: Vec2, delta: Vec2, settings: &SharedSettings) -> Vec2 {
+    let edges = settings.world_edges;
+    if settings.fixed_point_movement {
+        let moved = move_and_slide_fixed(
+            level,
+            FixedVec2::from_vec2(position),
+            FixedVec2::from_vec2(delta),
+        );
+        return edges.apply_fixed(moved, level.bounds()).to_vec2();
+    }
+    let moved = move_and_slide(level, position, delta);
+    edges.apply(moved, level.bounds())
+}
+
+/// Share of a knockback left after each tick
+const KNOCKBACK_DECAY: f32 = 0.8;
+
+/// Below this speed (in pixels per tick) a knockback is over
+const KNOCKBACK_SETTLED: f32 = 0.5;
+
+/// Push a player by its knockback, which then decays. Returns whether the knockback is over.
+pub(crate) fn shared_knockback_behaviour(
+    position: &mut PlayerPosition,
+    knockback: &mut Knockback,
+    level: &LevelGrid,
+    settings: &SharedSettings,
+) -> bool {
+    position.0 = move_player(level, position.0, knockback.0, settings);
+    knockback.0 *= KNOCKBACK_DECAY;
+    knockback.length() < KNOCKBACK_SETTLED
+}
+
+pub(crate) fn player_collisions_enabled(settings: Res<SharedSettings>) -> bool {
+    settings.player_collisions
+}
+
+/// Move a player pushed by another one, without pushing it into a wall
+pub(crate) fn shared_push_behaviour(
+    position: &mut PlayerPosition,
+    push: Vec2,
+    level: &LevelGrid,
+    settings: &SharedSettings,
+) {
+    position.0 = move_player(level, position.0, push, settings);
+}
+
+/// What happens to players reaching the edge of the level
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WorldEdges {
+    /// Players stop at the edge
+    #[default]
+    Clamp,
+    /// Players crossing an edge come back from the opposite one
+    Wrap,
+}
+
+impl WorldEdges {
+    pub fn apply(self, position: Vec2, bounds: Rect) -> Vec2 {
+        match self {
+            WorldEdges::Clamp => position.clamp(bounds.min, bounds.max),
+            WorldEdges::Wrap => bounds.min + (position - bounds.min).rem_euclid(bounds.size()),
         }
     }
 }
 
+/// Whether the debug rectangles are drawn, flipped at runtime with [`TOGGLE_DEBUG_GIZMOS_KEY`]
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShowDebugGizmos(pub bool);
+
+impl Default for ShowDebugGizmos {
+    fn default() -> Self {
+        // shown right away while developing, hidden from players unless they ask for them
+        Self(cfg!(debug_assertions))
+    }
+}
+
+pub const TOGGLE_DEBUG_GIZMOS_KEY: KeyCode = KeyCode::F3;
+
+fn toggle_debug_gizmos(keypress: Res<ButtonInput<KeyCode>>, mut show: ResMut<ShowDebugGizmos>) {
+    if keypress.just_pressed(TOGGLE_DEBUG_GIZMOS_KEY) {
+        show.0 = !show.0;
+        info!("Debug gizmos {}", if show.0 { "shown" } else { "hidden" });
+    }
+}
+
 /// System that draws the player's boxes and cursors
-pub fn draw_elements(mut gizmos: Gizmos, players: Query<&PlayerPosition, Without<Confirmed>>) {
-    for position in &players {
+pub fn draw_elements(
+    mut gizmos: Gizmos,
+    palette: Res<DebugPalette>,
+    players: Query<(&PlayerPosition, Has<Predicted>, Has<Interpolated>)>,
+) {
+    for (position, predicted, interpolated) in &players {
+        let color = if predicted {
+            palette.predicted
+        } else if interpolated {
+            palette.interpolated
+        } else {
+            // the server's own entities, or their confirmed copies on the client
+            palette.confirmed
+        };
         gizmos.rect_2d(
             Vec2::new(position.x, position.y),
             0.0,
             Vec2::ONE * 40.0,
-            Color::GREEN,
+            color,
         );
     }
 }
 
+/// Colors of the debug rectangles and name tags, only present when the inspector is enabled.
+/// Whether they are drawn is then controlled by [`ShowDebugGizmos`].
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct DebugPalette {
+    pub predicted: Color,
+    pub interpolated: Color,
+    pub confirmed: Color,
+}
+
+/// Colors of the debug rectangles drawn around players, as hex strings like `"#E69F00"`.
+/// The defaults are taken from the Okabe-Ito palette, which stays readable with the common forms of colorblindness.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DebugColorSettings {
+    /// Our own players, moved ahead of the server
+    pub predicted: String,
+
+    /// Players of other clients, smoothed between server updates
+    pub interpolated: String,
+
+    /// Positions as last confirmed by the server
+    pub confirmed: String,
+}
+
+impl Default for DebugColorSettings {
+    fn default() -> Self {
+        Self {
+            predicted: "#E69F00".to_string(),
+            interpolated: "#56B4E9".to_string(),
+            confirmed: "#CC79A7".to_string(),
+        }
+    }
+}
+
+impl DebugColorSettings {
+    pub fn palette(&self) -> Result<DebugPalette, SettingsError> {
+        let parse = |name: &'static str, hex: &str| {
+            Color::hex(hex).map_err(|_| SettingsError::DebugColor(name, hex.to_string()))
+        };
+        Ok(DebugPalette {
+            predicted: parse("predicted", &self.predicted)?,
+            interpolated: parse("interpolated", &self.interpolated)?,
+            confirmed: parse("confirmed", &self.confirmed)?,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ClientTransports {
     #[cfg(not(target_family = "wasm"))]
     Udp,
     WebSocket,
+    /// `wss://`, for servers whose WebSocket transport has TLS settings
+    #[cfg(not(target_family = "wasm"))]
+    SecureWebSocket {
+        tls: tls::ClientTlsSettings,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ServerTransports {
-    Udp { local_port: u16 },
-    WebSocket { local_port: u16 },
+    Udp {
+        local_port: u16,
+    },
+    WebSocket {
+        local_port: u16,
+        /// Serve `wss://` instead of `ws://` using this certificate
+        #[cfg(not(target_family = "wasm"))]
+        #[serde(default)]
+        tls: Option<tls::TlsSettings>,
+    },
+}
+
+/// What the server does with the players of a client that disconnected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DisconnectBehavior {
+    /// Remove the players right away
+    #[default]
+    Despawn,
+    /// Leave the players in the world as grayed out ghosts for this many seconds.
+    /// Reconnecting before then replaces the ghost.
+    Ghost { linger_secs: u64 },
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Resource, Clone, Debug, Deserialize, Serialize)]
 pub struct ServerSettings {
     /// If true, disable any rendering-related plugins
     pub headless: bool,
@@ -96,9 +399,74 @@ pub struct ServerSettings {
 
     /// Which transport to use
     pub transport: Vec<ServerTransports>,
+
+    /// The interface the transports listen on, all of them by default
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: IpAddr,
+
+    /// How long (in seconds) a disconnected client can take back its player with its reconnect token
+    #[serde(default = "default_reconnect_grace_secs")]
+    pub reconnect_grace_secs: u64,
+
+    /// What happens to the players of a client that disconnected
+    #[serde(default)]
+    pub on_disconnect: DisconnectBehavior,
+
+    /// Generate the level from a seed instead of using the LDtk level
+    #[serde(default)]
+    pub procedural_level: Option<ProceduralLevelSettings>,
+
+    /// Only replicate players to the clients that have a player within this distance (in pixels).
+    /// Everything is replicated to everyone when unset.
+    #[serde(default)]
+    pub interest_radius: Option<f32>,
+
+    /// Disconnect clients that sent no real input for this many seconds, to free their slot.
+    /// Idle clients are kept forever when unset.
+    #[serde(default)]
+    pub inactivity_timeout_secs: Option<u64>,
+
+    /// Most inputs applied to a client's players in a single tick, the others wait for the next ticks.
+    /// Keeps a lagging client from jumping when its inputs arrive together, all are applied right away when unset.
+    #[serde(default = "default_max_steps_per_tick")]
+    pub max_steps_per_tick: Option<u32>,
+
+    /// How many ticks of player positions are kept to check interactions against what the clients saw.
+    /// Interactions from clients lagging further behind are refused, 0 keeps none.
+    #[serde(default = "default_lag_compensation_ticks")]
+    pub lag_compensation_ticks: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProceduralLevelSettings {
+    /// Always generate the same level, a random seed is picked (and logged) when unset
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Width of the level in cells
+    pub width: u32,
+
+    /// Height of the level in cells
+    pub height: u32,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+fn default_max_steps_per_tick() -> Option<u32> {
+    Some(1)
+}
+
+fn default_lag_compensation_ticks() -> u16 {
+    32
+}
+
+fn default_bind_addr() -> IpAddr {
+    Ipv4Addr::UNSPECIFIED.into()
+}
+
+fn default_reconnect_grace_secs() -> u64 {
+    30
+}
+
+#[derive(Resource, Clone, Debug, Deserialize, Serialize)]
 pub struct ClientSettings {
     /// If true, enable bevy_inspector_egui
     pub inspector: bool,
@@ -109,23 +477,740 @@ pub struct ClientSettings {
     /// The client port to listen on
     pub client_port: u16,
 
-    /// The ip address of the server
-    pub server_addr: Ipv4Addr,
+    /// The ip address of the server, IPv4 or IPv6
+    pub server_addr: IpAddr,
 
     /// The port of the server
     pub server_port: u16,
 
     /// Which transport to use
     pub transport: ClientTransports,
+
+    /// Scale applied to the player sprites
+    #[serde(default = "default_sprite_scale")]
+    pub sprite_scale: f32,
+
+    /// Z coordinate of the player sprites, draws them above the level layers below it.
+    /// Depth-sorting of players must not go below this value.
+    #[serde(default = "default_sprite_z")]
+    pub sprite_z: f32,
+
+    /// Where the cameras look relative to the player they follow, in pixels
+    #[serde(default)]
+    pub camera_offset: (f32, f32),
+
+    /// How far the cameras look ahead of their moving player
+    #[serde(default)]
+    pub camera_lookahead: CameraLookaheadSettings,
+
+    /// The sheet containing the player animation frames
+    #[serde(default)]
+    pub sprite_sheet: SpriteSheetSettings,
+
+    /// Which frames of the sprite sheet make up each player animation
+    #[serde(default)]
+    pub animations: AnimationSettings,
+
+    /// How to retry the first connection to the server, e.g. when it is still starting up
+    #[serde(default)]
+    pub connect_retry: ConnectRetrySettings,
+
+    /// Number of players sharing this client, each gets its own part of the window.
+    /// Between 1 and `MAX_LOCAL_PLAYERS`.
+    #[serde(default = "default_local_players")]
+    pub local_players: u8,
+
+    /// How the interpolation delay of remote players follows the connection quality
+    #[serde(default)]
+    pub interpolation_delay: InterpolationDelaySettings,
+
+    /// How frames are presented to the screen, trading latency against tearing
+    #[serde(default)]
+    pub present_mode: PresentModeSetting,
+
+    /// Background color drawn instead of the one of the LDtk levels, as a hex string like `"#1A1A2E"`
+    #[serde(default)]
+    pub clear_color: Option<String>,
+
+    /// Draw our own players where the server confirmed them, interpolated like the other players,
+    /// instead of where they are predicted. To see the authoritative view while debugging: they then
+    /// lag behind the inputs by the round trip to the server plus the interpolation delay.
+    #[serde(default)]
+    pub show_confirmed_player: bool,
+
+    /// Play without a window nor rendering, e.g. to run the networking in CI without a GPU.
+    /// Also enabled by setting [`HEADLESS_CLIENT_ENV`].
+    #[serde(default)]
+    pub headless: bool,
+
+    /// Show the bars rating the connection in the corner of the screen, toggled with F4
+    #[serde(default = "default_show_link_quality")]
+    pub show_link_quality: bool,
+
+    /// Spawn our players once connected and the level is loaded, instead of waiting for the spawn key.
+    /// Only the first connection spawns them, later ones reclaim the players we had.
+    #[serde(default)]
+    pub auto_spawn: bool,
+
+    /// How far from our players (in pixels) we want to see the other players, to save bandwidth on a
+    /// weak device. The server caps it to its `interest_radius`, and uses that one when unset.
+    #[serde(default)]
+    pub view_radius: Option<f32>,
+}
+
+fn default_show_link_quality() -> bool {
+    true
+}
+
+/// Environment variable running the client headless when set, whatever the settings say
+pub const HEADLESS_CLIENT_ENV: &str = "MATRIX_RPG_HEADLESS_CLIENT";
+
+impl ClientSettings {
+    /// The background color overriding the levels' one, if any
+    pub fn clear_color(&self) -> Result<Option<Color>, SettingsError> {
+        self.clear_color
+            .as_deref()
+            .map(|hex| Color::hex(hex).map_err(|_| SettingsError::ClearColor(hex.to_string())))
+            .transpose()
+    }
+}
+
+/// The cameras shift towards where their player is heading, to show more of what lies ahead
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CameraLookaheadSettings {
+    /// Offset at full heading in pixels, 0 keeps the cameras centered
+    pub distance: f32,
+
+    /// How quickly the offset follows a change of heading, higher is snappier. Per second.
+    pub smoothing: f32,
+}
+
+impl Default for CameraLookaheadSettings {
+    fn default() -> Self {
+        Self {
+            distance: 48.0,
+            smoothing: 4.0,
+        }
+    }
+}
+
+/// The presentation modes a player can pick, see [`bevy::window::PresentMode`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PresentModeSetting {
+    /// Vsync when supported, `Fifo` otherwise
+    #[default]
+    AutoVsync,
+    /// No vsync when supported, `Fifo` otherwise
+    AutoNoVsync,
+    /// Vsync, frames wait for the screen so the frame rate is capped
+    Fifo,
+    /// No tearing and an uncapped frame rate, not supported everywhere
+    Mailbox,
+    /// Lowest latency, may tear
+    Immediate,
+}
+
+impl From<PresentModeSetting> for bevy::window::PresentMode {
+    fn from(mode: PresentModeSetting) -> Self {
+        use bevy::window::PresentMode;
+        match mode {
+            PresentModeSetting::AutoVsync => PresentMode::AutoVsync,
+            PresentModeSetting::AutoNoVsync => PresentMode::AutoNoVsync,
+            PresentModeSetting::Fifo => PresentMode::Fifo,
+            PresentModeSetting::Mailbox => PresentMode::Mailbox,
+            PresentModeSetting::Immediate => PresentMode::Immediate,
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+/// Bounds and speed of the automatic tuning of the interpolation delay
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct InterpolationDelaySettings {
+    /// Delay in milliseconds on a perfectly stable connection
+    pub min_delay_ms: u64,
+
+    /// The delay never goes above this, however bad the connection
+    pub max_delay_ms: u64,
+
+    /// How many times the measured jitter is added on top of the minimum delay
+    pub jitter_factor: f32,
+
+    /// Share of the gap to the wanted delay that is closed every second.
+    /// Lower values react more slowly but keep the delay from oscillating.
+    pub adaptation_rate: f32,
+}
+
+impl Default for InterpolationDelaySettings {
+    fn default() -> Self {
+        Self {
+            min_delay_ms: 50,
+            max_delay_ms: 300,
+            jitter_factor: 4.0,
+            adaptation_rate: 0.5,
+        }
+    }
+}
+
+fn default_local_players() -> u8 {
+    1
+}
+
+/// Exponential backoff for the initial connection attempts
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ConnectRetrySettings {
+    /// Seconds to wait for the first attempt before trying again, doubled after each attempt
+    pub initial_delay_secs: f32,
+
+    /// Upper bound for the wait between two attempts
+    pub max_delay_secs: f32,
+
+    /// Give up after that many attempts
+    pub max_attempts: u32,
+}
+
+impl Default for ConnectRetrySettings {
+    fn default() -> Self {
+        Self {
+            initial_delay_secs: 1.0,
+            max_delay_secs: 8.0,
+            max_attempts: 8,
+        }
+    }
+}
+
+impl ConnectRetrySettings {
+    /// How long to wait for the given attempt (starting at 0) before making the next one
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay_secs * 2f32.powi(attempt.min(16) as i32);
+        Duration::from_secs_f32(delay.min(self.max_delay_secs).max(0.0))
+    }
+}
+
+/// A range of frames in the sprite sheet, played in a loop
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AnimationClip {
+    /// Index of the first frame
+    pub first: usize,
+
+    /// Index of the last frame (inclusive)
+    pub last: usize,
+
+    /// Frames per second
+    pub fps: f32,
+}
+
+impl AnimationClip {
+    pub const fn new(first: usize, last: usize, fps: f32) -> Self {
+        Self { first, last, fps }
+    }
+}
+
+/// The player animations, picked depending on whether and in which direction the player moves
+#[derive(Resource, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AnimationSettings {
+    pub idle: AnimationClip,
+    pub walk_down: AnimationClip,
+    pub walk_up: AnimationClip,
+    pub walk_left: AnimationClip,
+    pub walk_right: AnimationClip,
+    /// Standing still after walking in a direction, `idle` when unset
+    #[serde(default)]
+    pub idle_down: Option<AnimationClip>,
+    #[serde(default)]
+    pub idle_up: Option<AnimationClip>,
+    #[serde(default)]
+    pub idle_left: Option<AnimationClip>,
+    #[serde(default)]
+    pub idle_right: Option<AnimationClip>,
+}
+
+impl AnimationSettings {
+    pub fn clips(&self) -> Vec<(&'static str, &AnimationClip)> {
+        let idle_clips = [
+            ("idle_down", &self.idle_down),
+            ("idle_up", &self.idle_up),
+            ("idle_left", &self.idle_left),
+            ("idle_right", &self.idle_right),
+        ];
+        let mut clips = vec![
+            ("idle", &self.idle),
+            ("walk_down", &self.walk_down),
+            ("walk_up", &self.walk_up),
+            ("walk_left", &self.walk_left),
+            ("walk_right", &self.walk_right),
+        ];
+        clips.extend(
+            idle_clips
+                .into_iter()
+                .filter_map(|(name, clip)| Some((name, clip.as_ref()?))),
+        );
+        clips
+    }
+}
+
+impl Default for AnimationSettings {
+    fn default() -> Self {
+        // the sheet only has a single run cycle for now
+        let walk = AnimationClip::new(0, 3, 1.0 / 0.3);
+        Self {
+            idle: AnimationClip::new(0, 0, 1.0),
+            walk_down: walk,
+            walk_up: walk,
+            walk_left: walk,
+            walk_right: walk,
+            idle_down: None,
+            idle_up: None,
+            idle_left: None,
+            idle_right: None,
+        }
+    }
+}
+
+/// Layout of a sprite sheet made of equally sized frames
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SpriteSheetSettings {
+    /// Path of the image, relative to the assets folder
+    pub path: String,
+
+    /// Width of a single frame in pixels
+    pub tile_width: f32,
+
+    /// Height of a single frame in pixels
+    pub tile_height: f32,
+
+    /// Number of frames per row
+    pub columns: usize,
+
+    /// Number of rows of frames
+    pub rows: usize,
+}
+
+impl Default for SpriteSheetSettings {
+    fn default() -> Self {
+        Self {
+            path: "tilesets/user.png".to_string(),
+            tile_width: 16.0,
+            tile_height: 16.0,
+            columns: 8,
+            rows: 8,
+        }
+    }
+}
+
+fn default_sprite_scale() -> f32 {
+    2.0
+}
+
+fn default_sprite_z() -> f32 {
+    17.0
+}
+
+#[derive(Resource, Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct SharedSettings {
     /// An id to identify the protocol version
     pub protocol_id: u64,
 
     /// a 32-byte array to authenticate via the Netcode.io protocol
+    ///
+    /// Both this and the protocol id can be overridden from a key file or the environment, see the `keys` module
     pub private_key: [u8; 32],
+
+    /// Seconds between keepalive packets when there is nothing else to send.
+    /// Must be shorter than `connection_timeout_secs`, ideally by a factor of 10 or more so that a few
+    /// lost keepalives don't end the connection.
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: f64,
+
+    /// Seconds without hearing from the other side after which the connection is dropped.
+    /// A negative value disables the timeout.
+    #[serde(default = "default_connection_timeout_secs")]
+    pub connection_timeout_secs: i32,
+
+    /// Whether players stop at the edges of the level or wrap around to the other side
+    #[serde(default)]
+    pub world_edges: WorldEdges,
+
+    /// If true, players push each other away instead of walking through each other
+    #[serde(default)]
+    pub player_collisions: bool,
+
+    /// If true, the players move on a grid of 1/256th of a pixel using integer math, so that the
+    /// client prediction and the server agree to the bit whatever the platform.
+    /// The level must then fit within 65536 pixels of the origin.
+    #[serde(default)]
+    pub fixed_point_movement: bool,
+
+    /// Make the players speed up and slow down gradually, they move at full speed right away when unset
+    #[serde(default)]
+    pub acceleration: Option<AccelerationSettings>,
+
+    /// How often the server sends updates
+    #[serde(default)]
+    pub replication: ReplicationRates,
+
+    /// Simulate a bad network on the incoming packets, to test how the game copes with it.
+    /// Never applied to the in-process channels of a listen-server.
+    #[serde(default)]
+    pub link_conditioner: Option<LinkConditionerSettings>,
+
+    /// Seed of the [`SessionRng`]s, to reproduce a session while debugging. Random when unset.
+    #[serde(default)]
+    pub rng_seed: Option<u64>,
+
+    /// Compress the large messages of the reliable channels, used only when both ends enable it
+    #[serde(default)]
+    pub compression: Option<CompressionSettings>,
+}
+
+/// Compression of the large messages, both ends must enable it for it to be used
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CompressionSettings {
+    /// Messages up to this size are sent as is, compressing them would cost more than it saves
+    pub threshold_bytes: usize,
+}
+
+/// Players ramp up to their walking speed and coast to a stop, instead of starting and stopping at once
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AccelerationSettings {
+    /// Speed gained each tick while speeding up or turning, in pixels per tick
+    pub acceleration: f32,
+
+    /// Speed lost each tick while slowing down, in pixels per tick
+    pub deceleration: f32,
+}
+
+/// Reaching the walking speed in a single tick, from standing still
+const INSTANT_ACCELERATION: AccelerationSettings = AccelerationSettings {
+    acceleration: MOVE_SPEED,
+    deceleration: MOVE_SPEED,
+};
+
+impl AccelerationSettings {
+    /// The same acceleration on a terrain keeping only `grip` of it
+    fn with_grip(self, grip: f32) -> Self {
+        AccelerationSettings {
+            acceleration: self.acceleration * grip,
+            deceleration: self.deceleration * grip,
+        }
+    }
+
+    /// The velocity after a tick heading for `target`
+    fn step(&self, velocity: Vec2, target: Vec2) -> Vec2 {
+        let rate = if target.length_squared() < velocity.length_squared() {
+            self.deceleration
+        } else {
+            self.acceleration
+        };
+        velocity + (target - velocity).clamp_length_max(rate)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LinkConditionerSettings {
+    /// Added to every incoming packet, in milliseconds
+    pub latency_ms: u64,
+
+    /// Random variation of the latency, in milliseconds
+    pub jitter_ms: u64,
+
+    /// Share of the incoming packets that are dropped, between 0 and 1
+    pub loss: f32,
+}
+
+impl SharedSettings {
+    /// The simulated network conditions for a transport, none for the in-process ones
+    pub(crate) fn link_conditioner(
+        &self,
+        transport: &TransportConfig,
+    ) -> Option<LinkConditionerConfig> {
+        if matches!(
+            transport,
+            TransportConfig::LocalChannel { .. } | TransportConfig::Channels { .. }
+        ) {
+            return None;
+        }
+        self.link_conditioner
+            .map(|conditioner| LinkConditionerConfig {
+                incoming_latency: Duration::from_millis(conditioner.latency_ms),
+                incoming_jitter: Duration::from_millis(conditioner.jitter_ms),
+                incoming_loss: conditioner.loss,
+            })
+    }
+}
+
+/// How often the server replicates changes, depending on how much they matter to the clients.
+///
+/// Components that are only set when the player spawns (`PlayerId`, `PlayerSlot`, `PlayerColor`) are sent
+/// reliably along with the spawn whatever the rates, these only apply to later changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ReplicationRates {
+    /// Milliseconds between two updates of the positions, 40 by default.
+    /// The clients interpolate between them, so this also drives the interpolation delay.
+    pub position_interval_ms: u64,
+
+    /// Milliseconds between two updates of the components that only need to be roughly up to date,
+    /// like the health, 250 by default
+    pub slow_interval_ms: u64,
+}
+
+impl Default for ReplicationRates {
+    fn default() -> Self {
+        Self {
+            position_interval_ms: 40,
+            slow_interval_ms: 250,
+        }
+    }
+}
+
+fn default_keepalive_interval_secs() -> f64 {
+    0.1
+}
+
+fn default_connection_timeout_secs() -> i32 {
+    3
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingsError {
+    /// Keepalives are not sent often enough to keep the connection from timing out
+    Keepalive {
+        interval_secs: f64,
+        timeout_secs: i32,
+    },
+    /// The player sprites would be invisible or mirrored
+    SpriteScale(f32),
+    /// The sprite sheet grid has no frames in it
+    SpriteSheet(SpriteSheetSettings),
+    /// An animation has an empty frame range or doesn't advance
+    Animation(&'static str, AnimationClip),
+    /// An animation uses frames past the end of the sprite sheet
+    AnimationOutsideSheet {
+        name: &'static str,
+        clip: AnimationClip,
+        frames: usize,
+    },
+    /// There is no room for that many split-screen players
+    LocalPlayers(u8),
+    /// A debug color isn't a valid hex color
+    DebugColor(&'static str, String),
+    /// The clear color isn't a valid hex color
+    ClearColor(String),
+    /// The camera would look behind the player, or never catch up with its heading
+    CameraLookahead(CameraLookaheadSettings),
+    /// The players would never start or stop moving
+    Acceleration(AccelerationSettings),
+    /// The interpolation delay bounds are reversed or it can't adapt
+    InterpolationDelay(InterpolationDelaySettings),
+    /// Updates would never be sent
+    ReplicationRates(ReplicationRates),
+    /// The simulated packet loss isn't a share of the packets
+    PacketLoss(f32),
+    /// The area of interest would never contain anything
+    InterestRadius(f32),
+    /// We would never see anyone
+    ViewRadius(f32),
+    /// Every client would be disconnected right away
+    InactivityTimeout,
+    /// Ghosts would be removed as soon as they appear
+    GhostLinger,
+    /// The players would never move
+    MaxStepsPerTick,
+    /// The server can't listen on this address
+    BindAddr(IpAddr),
+    /// Two transports listen on the same port with the same protocol
+    DuplicatePort(Listener, u16),
+    /// A generated level needs room for walls around the spawn point
+    ProceduralLevel { width: u32, height: u32 },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Keepalive {
+                interval_secs,
+                timeout_secs,
+            } => write!(
+                f,
+                "the keepalive interval ({interval_secs}s) must be positive and shorter than the connection timeout ({timeout_secs}s)"
+            ),
+            SettingsError::SpriteScale(scale) => {
+                write!(f, "the sprite scale must be positive, got {scale}")
+            }
+            SettingsError::SpriteSheet(sheet) => write!(
+                f,
+                "the sprite sheet {} must have a positive tile size and at least one row and column",
+                sheet.path
+            ),
+            SettingsError::Animation(name, clip) => write!(
+                f,
+                "the animation {name} must have first <= last and a positive fps, got {clip:?}"
+            ),
+            SettingsError::AnimationOutsideSheet { name, clip, frames } => write!(
+                f,
+                "the animation {name} goes up to frame {}, but the sprite sheet only has frames 0 to {}",
+                clip.last,
+                frames.saturating_sub(1)
+            ),
+            SettingsError::LocalPlayers(count) => write!(
+                f,
+                "the number of local players must be between 1 and {MAX_LOCAL_PLAYERS}, got {count}"
+            ),
+            SettingsError::DebugColor(name, value) => write!(
+                f,
+                "the {name} debug color must be a hex color such as \"#E69F00\", got {value:?}"
+            ),
+            SettingsError::ClearColor(value) => write!(
+                f,
+                "the clear color must be a hex color such as \"#1A1A2E\", got {value:?}"
+            ),
+            SettingsError::CameraLookahead(lookahead) => write!(
+                f,
+                "the camera lookahead distance must not be negative and its smoothing must be positive, got {lookahead:?}"
+            ),
+            SettingsError::Acceleration(acceleration) => write!(
+                f,
+                "the acceleration and deceleration must be positive, got {acceleration:?}"
+            ),
+            SettingsError::InterpolationDelay(delay) => write!(
+                f,
+                "the interpolation delay needs min_delay_ms <= max_delay_ms, a jitter_factor >= 0 and a positive adaptation_rate, got {delay:?}"
+            ),
+            SettingsError::ReplicationRates(rates) => write!(
+                f,
+                "the replication intervals must be positive, got {rates:?}"
+            ),
+            SettingsError::PacketLoss(loss) => write!(
+                f,
+                "the link conditioner loss must be between 0 and 1, got {loss}"
+            ),
+            SettingsError::InterestRadius(radius) => {
+                write!(f, "the interest radius must be positive, got {radius}")
+            }
+            SettingsError::ViewRadius(radius) => {
+                write!(f, "the view radius must be positive, got {radius}")
+            }
+            SettingsError::InactivityTimeout => {
+                write!(f, "the inactivity timeout must be at least a second")
+            }
+            SettingsError::BindAddr(addr) => write!(
+                f,
+                "the server can't listen on {addr}, use 0.0.0.0, :: or the address of an interface"
+            ),
+            SettingsError::DuplicatePort(listener, port) => write!(
+                f,
+                "several server transports listen for {listener} on port {port}, only the first could bind it"
+            ),
+            SettingsError::MaxStepsPerTick => {
+                write!(f, "at least one step must be applied per tick")
+            }
+            SettingsError::GhostLinger => {
+                write!(f, "ghosts must linger for at least a second, use Despawn instead")
+            }
+            SettingsError::ProceduralLevel { width, height } => write!(
+                f,
+                "a generated level must be at least 3x3 cells, got {width}x{height}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Anything that keeps the game from setting up or starting its connections
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The settings file is not valid RON
+    ParseSettings(ron::de::SpannedError),
+    InvalidSettings(SettingsError),
+    #[cfg(not(target_family = "wasm"))]
+    Keys(keys::KeyError),
+    /// The TLS certificate could not be loaded or the TLS port could not be bound
+    Tls(std::io::Error),
+    /// The client could not start connecting to the server
+    Connect(String),
+    /// A server transport could not listen on its address
+    Bind {
+        listener: Listener,
+        addr: SocketAddr,
+        error: std::io::Error,
+    },
+}
+
+/// The kinds of listeners a server can have, see [`ServerTransports`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Listener {
+    Udp,
+    WebSocket,
+    SecureWebSocket,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Udp => write!(f, "UDP"),
+            Listener::WebSocket => write!(f, "ws://"),
+            Listener::SecureWebSocket => write!(f, "wss://"),
+        }
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::ParseSettings(e) => write!(f, "could not parse the settings: {e}"),
+            NetworkError::InvalidSettings(e) => write!(f, "invalid settings: {e}"),
+            #[cfg(not(target_family = "wasm"))]
+            NetworkError::Keys(e) => write!(f, "failed to load the network keys: {e}"),
+            NetworkError::Tls(e) => write!(f, "failed to set up TLS: {e}"),
+            NetworkError::Connect(e) => write!(f, "failed to start connecting: {e}"),
+            NetworkError::Bind {
+                listener,
+                addr,
+                error,
+            } if error.kind() == std::io::ErrorKind::AddrInUse => write!(
+                f,
+                "could not listen for {listener} on {addr}: the port is already in use, is another server running?"
+            ),
+            NetworkError::Bind {
+                listener,
+                addr,
+                error,
+            } => write!(f, "could not listen for {listener} on {addr}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::ParseSettings(e) => Some(e),
+            NetworkError::InvalidSettings(e) => Some(e),
+            #[cfg(not(target_family = "wasm"))]
+            NetworkError::Keys(e) => Some(e),
+            NetworkError::Tls(e) => Some(e),
+            NetworkError::Connect(_) => None,
+            NetworkError::Bind { error, .. } => Some(error),
+        }
+    }
+}
+
+impl From<ron::de::SpannedError> for NetworkError {
+    fn from(e: ron::de::SpannedError) -> Self {
+        NetworkError::ParseSettings(e)
+    }
+}
+
+impl From<SettingsError> for NetworkError {
+    fn from(e: SettingsError) -> Self {
+        NetworkError::InvalidSettings(e)
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl From<keys::KeyError> for NetworkError {
+    fn from(e: keys::KeyError) -> Self {
+        NetworkError::Keys(e)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -133,4 +1218,434 @@ pub struct Settings {
     pub server: ServerSettings,
     pub client: ClientSettings,
     pub shared: SharedSettings,
+    #[serde(default)]
+    pub debug_colors: DebugColorSettings,
+}
+
+impl Settings {
+    /// The settings embedded in the game, for the tests to change what they rely on
+    #[cfg(test)]
+    pub(crate) fn embedded() -> Self {
+        ron::de::from_str(include_str!("../../assets/settings.ron"))
+            .expect("the embedded settings parse")
+    }
+
+    /// Check the values that can be parsed but don't make sense
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let shared = &self.shared;
+        let timeout_enabled = shared.connection_timeout_secs >= 0;
+        if shared.keepalive_interval_secs <= 0.0
+            || (timeout_enabled
+                && shared.keepalive_interval_secs >= shared.connection_timeout_secs as f64)
+        {
+            return Err(SettingsError::Keepalive {
+                interval_secs: shared.keepalive_interval_secs,
+                timeout_secs: shared.connection_timeout_secs,
+            });
+        }
+        let rates = shared.replication;
+        if rates.position_interval_ms == 0 || rates.slow_interval_ms == 0 {
+            return Err(SettingsError::ReplicationRates(rates));
+        }
+        if let Some(conditioner) = shared.link_conditioner {
+            if !(0.0..=1.0).contains(&conditioner.loss) {
+                return Err(SettingsError::PacketLoss(conditioner.loss));
+            }
+        }
+        let sprite_scale = self.client.sprite_scale;
+        if sprite_scale.is_nan() || sprite_scale <= 0.0 {
+            return Err(SettingsError::SpriteScale(sprite_scale));
+        }
+        let sheet = &self.client.sprite_sheet;
+        if !(sheet.tile_width > 0.0
+            && sheet.tile_height > 0.0
+            && sheet.columns > 0
+            && sheet.rows > 0)
+        {
+            return Err(SettingsError::SpriteSheet(sheet.clone()));
+        }
+        for (name, clip) in self.client.animations.clips() {
+            if clip.first > clip.last || clip.fps.is_nan() || clip.fps <= 0.0 {
+                return Err(SettingsError::Animation(name, *clip));
+            }
+            let frames = sheet.columns * sheet.rows;
+            if clip.last >= frames {
+                return Err(SettingsError::AnimationOutsideSheet {
+                    name,
+                    clip: *clip,
+                    frames,
+                });
+            }
+        }
+        let local_players = self.client.local_players;
+        if !(1..=MAX_LOCAL_PLAYERS).contains(&local_players) {
+            return Err(SettingsError::LocalPlayers(local_players));
+        }
+        let delay = &self.client.interpolation_delay;
+        if delay.min_delay_ms > delay.max_delay_ms
+            || delay.jitter_factor.is_nan()
+            || delay.jitter_factor < 0.0
+            || delay.adaptation_rate.is_nan()
+            || delay.adaptation_rate <= 0.0
+        {
+            return Err(SettingsError::InterpolationDelay(delay.clone()));
+        }
+        self.debug_colors.palette()?;
+        self.client.clear_color()?;
+        let lookahead = self.client.camera_lookahead;
+        if !(lookahead.distance >= 0.0
+            && lookahead.smoothing.is_finite()
+            && lookahead.smoothing > 0.0)
+        {
+            return Err(SettingsError::CameraLookahead(lookahead));
+        }
+        if let Some(acceleration) = self.shared.acceleration {
+            let positive = |rate: f32| rate.is_finite() && rate > 0.0;
+            if !positive(acceleration.acceleration) || !positive(acceleration.deceleration) {
+                return Err(SettingsError::Acceleration(acceleration));
+            }
+        }
+        if let Some(radius) = self.client.view_radius {
+            if radius.is_nan() || radius <= 0.0 {
+                return Err(SettingsError::ViewRadius(radius));
+            }
+        }
+        if let Some(radius) = self.server.interest_radius {
+            if radius.is_nan() || radius <= 0.0 {
+                return Err(SettingsError::InterestRadius(radius));
+            }
+        }
+        if self.server.inactivity_timeout_secs == Some(0) {
+            return Err(SettingsError::InactivityTimeout);
+        }
+        let bind_addr = self.server.bind_addr;
+        let broadcast = match bind_addr {
+            IpAddr::V4(addr) => addr.is_broadcast(),
+            IpAddr::V6(_) => false,
+        };
+        if bind_addr.is_multicast() || broadcast {
+            return Err(SettingsError::BindAddr(bind_addr));
+        }
+        // UDP and TCP have their own ports, the WebSocket transports share TCP's
+        let mut ports = HashSet::new();
+        for transport in &self.server.transport {
+            let (listener, port, udp) = match transport {
+                ServerTransports::Udp { local_port } => (Listener::Udp, *local_port, true),
+                #[cfg(not(target_family = "wasm"))]
+                ServerTransports::WebSocket {
+                    local_port,
+                    tls: Some(_),
+                } => (Listener::SecureWebSocket, *local_port, false),
+                ServerTransports::WebSocket { local_port, .. } => {
+                    (Listener::WebSocket, *local_port, false)
+                }
+            };
+            // the OS picks a different free port for each transport asking for 0
+            if port != 0 && !ports.insert((udp, port)) {
+                return Err(SettingsError::DuplicatePort(listener, port));
+            }
+        }
+        if self.server.max_steps_per_tick == Some(0) {
+            return Err(SettingsError::MaxStepsPerTick);
+        }
+        if self.server.on_disconnect == (DisconnectBehavior::Ghost { linger_secs: 0 }) {
+            return Err(SettingsError::GhostLinger);
+        }
+        if let Some(level) = &self.server.procedural_level {
+            if level.width < 3 || level.height < 3 {
+                return Err(SettingsError::ProceduralLevel {
+                    width: level.width,
+                    height: level.height,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::protocol::{PlayerId, PlayerSlot};
+
+    fn test_settings() -> SharedSettings {
+        let mut settings = Settings::embedded().shared;
+        settings.world_edges = WorldEdges::Clamp;
+        settings.fixed_point_movement = false;
+        settings.acceleration = None;
+        settings
+    }
+
+    #[test]
+    fn knockback_moves_then_settles() {
+        let level = LevelGrid::from_rows(&[".........."; 10]);
+        let settings = test_settings();
+        let start = Vec2::splat(160.0);
+        let mut position = PlayerPosition(start);
+        let mut knockback = Knockback(Vec2::new(6.0, 0.0));
+
+        let settled = shared_knockback_behaviour(&mut position, &mut knockback, &level, &settings);
+        assert!(!settled);
+        assert_eq!(position.0, start + Vec2::new(6.0, 0.0));
+
+        let mut ticks = 1;
+        while !shared_knockback_behaviour(&mut position, &mut knockback, &level, &settings) {
+            ticks += 1;
+            assert!(ticks < 100, "the knockback never settles");
+        }
+        // the pushes add up to less than 6 / (1 - KNOCKBACK_DECAY)
+        let pushed = position.0 - start;
+        assert!(pushed.x > 20.0 && pushed.x < 30.0, "pushed by {}", pushed);
+        assert_eq!(pushed.y, 0.0);
+    }
+
+    #[test]
+    fn knockback_stops_at_walls() {
+        let level = LevelGrid::from_rows(&["......#..."; 10]);
+        let settings = test_settings();
+        let mut position = PlayerPosition(Vec2::new(160.0, 160.0));
+        // enough to travel about 50 pixels, the wall starts 20 pixels away
+        let mut knockback = Knockback(Vec2::new(10.0, 0.0));
+        while !shared_knockback_behaviour(&mut position, &mut knockback, &level, &settings) {}
+        let right_edge = position.0.x + crate::collision::PLAYER_HITBOX.x / 2.0;
+        assert!(
+            right_edge <= 192.0 && right_edge > 180.0,
+            "stopped at {}",
+            right_edge
+        );
+    }
+
+    fn walking_right() -> Inputs {
+        Inputs::Direction(protocol::Direction {
+            up: false,
+            down: false,
+            left: false,
+            right: true,
+        })
+    }
+
+    /// Read one input per tick in `app` and return the position and velocity of `player` after each
+    fn record_steps<E: Event>(
+        mut app: App,
+        player: Entity,
+        inputs: &[Inputs],
+        event: impl Fn(Inputs) -> E,
+    ) -> Vec<(Vec2, Vec2)> {
+        inputs
+            .iter()
+            .map(|input| {
+                app.world.send_event(event(input.clone()));
+                app.update();
+                let player = app.world.entity(player);
+                (
+                    player.get::<PlayerPosition>().unwrap().0,
+                    player.get::<PlayerVelocity>().unwrap().0,
+                )
+            })
+            .collect()
+    }
+
+    /// Predict our own player with the client's `player_movement`
+    fn client_steps(
+        level: &LevelGrid,
+        settings: &SharedSettings,
+        start: Vec2,
+        inputs: &[Inputs],
+    ) -> Vec<(Vec2, Vec2)> {
+        let mut app = App::new();
+        app.insert_resource(level.clone());
+        app.insert_resource(*settings);
+        app.add_event::<lightyear::prelude::client::InputEvent<Inputs>>();
+        app.add_systems(Update, client::player_movement);
+        let player = app
+            .world
+            .spawn((
+                Predicted {
+                    confirmed_entity: None,
+                },
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(start),
+                PlayerVelocity::default(),
+                Transform::default(),
+            ))
+            .id();
+        record_steps(app, player, inputs, |input| {
+            lightyear::prelude::client::InputEvent::new(Some(input), ())
+        })
+    }
+
+    /// Move the player of client 1 with the server's `movement`, through its pending moves
+    fn server_steps(
+        level: &LevelGrid,
+        settings: &SharedSettings,
+        start: Vec2,
+        inputs: &[Inputs],
+    ) -> Vec<(Vec2, Vec2)> {
+        let mut app = App::new();
+        app.insert_resource(level.clone());
+        app.insert_resource(*settings);
+        app.insert_resource(Settings::embedded().server);
+        app.insert_resource(TickManager::from_config(TickConfig::new(
+            Duration::from_millis(16),
+        )));
+        app.init_resource::<Time<Real>>();
+        app.init_resource::<server::SpawnGuard>();
+        app.init_resource::<server::LastActivity>();
+        app.init_resource::<server::InputStats>();
+        app.init_resource::<server::PendingMoves>();
+        app.add_event::<lightyear::prelude::server::InputEvent<Inputs>>();
+        app.add_systems(Update, server::movement);
+        let player = app
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(start),
+                PlayerVelocity::default(),
+            ))
+            .id();
+        record_steps(app, player, inputs, |input| {
+            lightyear::prelude::server::InputEvent::new(Some(input), 1)
+        })
+    }
+
+    /// Run the same inputs through the client prediction and the server, which must agree on every tick.
+    /// Returns the position and velocity after each tick.
+    fn steps_on_both_ends(
+        level: &LevelGrid,
+        settings: &SharedSettings,
+        start: Vec2,
+        inputs: &[Inputs],
+    ) -> Vec<(Vec2, Vec2)> {
+        let client = client_steps(level, settings, start, inputs);
+        let server = server_steps(level, settings, start, inputs);
+        assert_eq!(client.len(), inputs.len());
+        for (tick, (client, server)) in client.iter().zip(&server).enumerate() {
+            assert_eq!(
+                client, server,
+                "the client and the server disagree on tick {tick}"
+            );
+        }
+        server
+    }
+
+    #[test]
+    fn acceleration_ramps_up_then_coasts() {
+        let level = LevelGrid::from_rows(&["...................."; 10]);
+        let mut settings = test_settings();
+        settings.acceleration = Some(AccelerationSettings {
+            acceleration: 2.5,
+            deceleration: 5.0,
+        });
+        let start = Vec2::new(100.0, 160.0);
+        let inputs = [vec![walking_right(); 5], vec![Inputs::None; 3]].concat();
+
+        let steps = steps_on_both_ends(&level, &settings, start, &inputs);
+        let speeds: Vec<f32> = steps.iter().map(|(_, velocity)| velocity.x).collect();
+        // letting go slows the player down instead of stopping it at once
+        assert_eq!(
+            speeds,
+            [2.5, 5.0, 7.5, MOVE_SPEED, MOVE_SPEED, 5.0, 0.0, 0.0]
+        );
+        assert_eq!(steps[4].0, start + Vec2::new(35.0, 0.0));
+        assert_eq!(steps[7].0, start + Vec2::new(40.0, 0.0));
+
+        settings.fixed_point_movement = true;
+        steps_on_both_ends(&level, &settings, start, &inputs);
+    }
+
+    #[test]
+    fn water_slows_players_down() {
+        let level = LevelGrid::from_rows(&["~~~~~~~~~~"; 10]);
+        let settings = test_settings();
+        let start = Vec2::new(100.0, 160.0);
+        let steps = steps_on_both_ends(
+            &level,
+            &settings,
+            start,
+            &[walking_right(), walking_right(), walking_right()],
+        );
+        assert_eq!(steps[2].0, start + Vec2::new(3.0 * MOVE_SPEED / 2.0, 0.0));
+    }
+
+    #[test]
+    fn players_slide_on_ice() {
+        let level = LevelGrid::from_rows(&["**********"; 10]);
+        let settings = test_settings();
+        let start = Vec2::new(100.0, 160.0);
+        let inputs = [vec![walking_right(); 3], vec![Inputs::None]].concat();
+        let steps = steps_on_both_ends(&level, &settings, start, &inputs);
+
+        // slow to get going, even without acceleration elsewhere
+        let grip = Cell::Ice.grip() * MOVE_SPEED;
+        for (tick, (_, velocity)) in steps[..3].iter().enumerate() {
+            assert!((velocity.x - grip * (tick + 1) as f32).abs() < 1e-4);
+        }
+        // and slow to stop
+        assert!(steps[3].1.x > 0.0);
+        assert!(steps[3].0.x > steps[2].0.x);
+    }
+
+    #[test]
+    fn floor_moves_at_full_speed_at_once() {
+        let level = LevelGrid::from_rows(&[".........."; 10]);
+        let settings = test_settings();
+        let start = Vec2::new(100.0, 160.0);
+        let steps = steps_on_both_ends(
+            &level,
+            &settings,
+            start,
+            &[walking_right(), walking_right()],
+        );
+        assert_eq!(steps[0], (start + Vec2::new(MOVE_SPEED, 0.0), Vec2::ZERO));
+        assert_eq!(steps[1].0, start + Vec2::new(2.0 * MOVE_SPEED, 0.0));
+    }
+
+    fn client_ids(seed: Option<u64>, instance: u32) -> Vec<ClientId> {
+        let mut rng = SessionRng::new(seed, RngRole::Client, instance);
+        (0..5).map(|_| random_client_id(&mut rng.0)).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_client_ids() {
+        assert_eq!(client_ids(Some(42), 0), client_ids(Some(42), 0));
+        assert_ne!(client_ids(Some(42), 0), client_ids(Some(43), 0));
+        // never one of the ids kept for the bots
+        assert!(client_ids(None, 0).iter().all(|id| !BOT_IDS.contains(id)));
+    }
+
+    #[test]
+    fn seeded_processes_draw_their_own_values() {
+        // two clients started from the same settings
+        assert_ne!(client_ids(Some(42), 0), client_ids(Some(42), 1));
+        // a listen-server and its client
+        let mut server = SessionRng::new(Some(42), RngRole::Server, 0);
+        let mut client = SessionRng::new(Some(42), RngRole::Client, 0);
+        assert_ne!(server.0.gen::<u64>(), client.0.gen::<u64>());
+    }
+
+    #[test]
+    fn ipv6_addresses_parse_from_the_settings() {
+        let settings_str = include_str!("../../assets/settings.ron")
+            .replace(r#"server_addr: "127.0.0.1""#, r#"server_addr: "::1""#)
+            .replace(r#"bind_addr: "0.0.0.0""#, r#"bind_addr: "::""#);
+        let settings: Settings = ron::de::from_str(&settings_str).expect("the settings parse");
+        settings.validate().expect("the settings are valid");
+        assert_eq!(
+            settings.client.server_addr,
+            IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(
+            settings.server.bind_addr,
+            IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)
+        );
+        let server_addr = SocketAddr::new(settings.client.server_addr, settings.client.server_port);
+        assert_eq!(
+            server_addr,
+            format!("[::1]:{}", settings.client.server_port)
+                .parse()
+                .unwrap()
+        );
+    }
 }
@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use lightyear::client::components::Confirmed;
+use lightyear::prelude::client::{Interpolated, Predicted};
+
+use super::protocol::{PlayerId, PlayerPosition};
+use super::{DebugPalette, ShowDebugGizmos};
+
+/// Labels every player entity with its entity id, client id and replication role,
+/// only when the inspector is enabled and the debug gizmos are shown.
+pub struct NameTagPlugin;
+
+impl Plugin for NameTagPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            update_name_tags.run_if(resource_exists::<DebugPalette>),
+        );
+    }
+}
+
+/// Distance between a player's position and its name tag
+const NAME_TAG_OFFSET: Vec3 = Vec3::new(0.0, 32.0, 0.0);
+
+/// Drawn above the players and the level
+const NAME_TAG_Z: f32 = 100.0;
+
+/// The label of the given player entity
+#[derive(Component)]
+struct NameTag(Entity);
+
+/// Which copy of a player an entity is
+fn role(predicted: bool, interpolated: bool, confirmed: bool) -> &'static str {
+    if predicted {
+        "Predicted"
+    } else if interpolated {
+        "Interpolated"
+    } else if confirmed {
+        "Confirmed"
+    } else {
+        // the server's own entities in a listen server
+        "Server"
+    }
+}
+
+/// Keep one tag per player entity, following its position, in the color of its debug rectangle
+#[allow(clippy::type_complexity)]
+fn update_name_tags(
+    mut commands: Commands,
+    palette: Res<DebugPalette>,
+    show: Res<ShowDebugGizmos>,
+    players: Query<(
+        Entity,
+        &PlayerId,
+        &PlayerPosition,
+        Has<Predicted>,
+        Has<Interpolated>,
+        Has<Confirmed>,
+    )>,
+    mut tags: Query<(Entity, &NameTag, &mut Transform, &mut Text)>,
+) {
+    let mut tagged = HashSet::new();
+    for (tag_entity, tag, mut transform, mut text) in tags.iter_mut() {
+        let player = players.get(tag.0).ok().filter(|_| show.0);
+        let Some((entity, player_id, position, predicted, interpolated, confirmed)) = player else {
+            commands.entity(tag_entity).despawn_recursive();
+            continue;
+        };
+        transform.translation = position.0.extend(NAME_TAG_Z) + NAME_TAG_OFFSET;
+        // the role changes when a confirmed entity gets its predicted or interpolated copy
+        let label = label(entity, player_id, predicted, interpolated, confirmed);
+        if text.sections[0].value != label {
+            text.sections[0].value = label;
+        }
+        text.sections[0].style.color = color(&palette, predicted, interpolated);
+        tagged.insert(entity);
+    }
+    if !show.0 {
+        return;
+    }
+    for (entity, player_id, position, predicted, interpolated, confirmed) in players.iter() {
+        if tagged.contains(&entity) {
+            continue;
+        }
+        commands.spawn((
+            Text2dBundle {
+                text: Text::from_section(
+                    label(entity, player_id, predicted, interpolated, confirmed),
+                    TextStyle {
+                        font_size: 12.0,
+                        color: color(&palette, predicted, interpolated),
+                        ..default()
+                    },
+                ),
+                transform: Transform::from_translation(
+                    position.0.extend(NAME_TAG_Z) + NAME_TAG_OFFSET,
+                ),
+                ..default()
+            },
+            NameTag(entity),
+        ));
+    }
+}
+
+fn label(
+    entity: Entity,
+    player_id: &PlayerId,
+    predicted: bool,
+    interpolated: bool,
+    confirmed: bool,
+) -> String {
+    format!(
+        "{entity:?} client {} {}",
+        player_id.0,
+        role(predicted, interpolated, confirmed)
+    )
+}
+
+/// The same colors as [`super::draw_elements`]
+fn color(palette: &DebugPalette, predicted: bool, interpolated: bool) -> Color {
+    if predicted {
+        palette.predicted
+    } else if interpolated {
+        palette.interpolated
+    } else {
+        palette.confirmed
+    }
+}
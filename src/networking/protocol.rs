@@ -1,9 +1,14 @@
+use std::fmt;
+
 use derive_more::{Add, Mul};
 
 use bevy::prelude::*;
+use lightyear::prelude::client::LerpFn;
 use lightyear::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use super::interpolation::SmoothValue;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Direction {
     pub(crate) up: bool,
@@ -16,6 +21,12 @@ impl Direction {
     pub(crate) fn is_none(&self) -> bool {
         !self.up && !self.down && !self.left && !self.right
     }
+
+    /// The pressed directions added up, diagonals aren't normalized
+    pub(crate) fn to_vec2(&self) -> Vec2 {
+        let axis = |positive: bool, negative: bool| positive as i8 as f32 - negative as i8 as f32;
+        Vec2::new(axis(self.right, self.left), axis(self.up, self.down))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -23,22 +34,207 @@ pub enum Inputs {
     Direction(Direction),
     Delete,
     Spawn,
+    /// Inputs of each local player of a split-screen client, indexed by their slot
+    Local(Vec<Inputs>),
+    /// Explicit idle: the stop key is held, which also cancels the moves the server still has queued.
+    /// Unlike [`Inputs::None`] it counts as playing, and overrides the movement keys.
+    Stop,
+    /// Implicit idle: nothing is pressed, or the game is paused.
     // NOTE: we NEED to provide a None input so that the server can distinguish between lost input packets and 'None' inputs
     None,
+    /// A variant added by a newer client, which the server ignores instead of dropping the client.
+    /// Only variants without data can be decoded this way, so new inputs must not carry any
+    /// unless the protocol id changes. Must stay the last variant.
+    #[serde(other)]
+    Unknown,
 }
 impl UserAction for Inputs {}
 
+impl Inputs {
+    /// The input of the local player in the given slot, clients without split-screen only have slot 0
+    pub(crate) fn for_slot(&self, slot: u8) -> Option<&Inputs> {
+        match self {
+            Inputs::Local(inputs) => inputs.get(slot as usize),
+            input if slot == 0 => Some(input),
+            _ => None,
+        }
+    }
+
+    /// Whether the input, or the input of one of the local players, is unknown to this version
+    pub(crate) fn is_unknown(&self) -> bool {
+        match self {
+            Inputs::Unknown => true,
+            Inputs::Local(inputs) => inputs.iter().any(Inputs::is_unknown),
+            _ => false,
+        }
+    }
+
+    /// Whether the player actually did something, as opposed to the inputs sent every tick anyway
+    pub(crate) fn is_active(&self) -> bool {
+        match self {
+            Inputs::Direction(direction) => !direction.is_none(),
+            Inputs::Delete | Inputs::Spawn | Inputs::Stop => true,
+            Inputs::Local(inputs) => inputs.iter().any(Inputs::is_active),
+            Inputs::None | Inputs::Unknown => false,
+        }
+    }
+}
+
 #[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message1(pub usize);
 
+/// Proof that a client owned a player, which lets it take the player back after losing its connection.
+/// Sent by the server to each client, and refreshed before it expires.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReconnectToken {
+    pub client_id: ClientId,
+    /// Unix timestamp (in seconds) after which the token is no longer accepted
+    pub expires_at: u64,
+    pub signature: [u8; 32],
+}
+
+/// Sent by a client after reconnecting to get back the player it had before
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReclaimPlayer(pub ReconnectToken);
+
+/// Sent by the server to every client when the level is generated, so that they all build the same one
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct LevelSeed {
+    pub seed: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Sent by the host of a listen-server when it pauses or resumes the game
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PauseGame(pub bool);
+
+/// A line typed in a client's chat box, see [`super::chat`]
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatRequest {
+    Say(String),
+    Me(String),
+    List,
+    /// A command only the server may know about, by name
+    Unknown(String),
+}
+
+/// A line for the chat boxes of the clients it is sent to
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    pub kind: ChatKind,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatKind {
+    Say(ClientId),
+    Emote(ClientId),
+    /// From the server itself, like the replies to commands
+    System,
+}
+
+/// A private chat message. The server only forwards it to `to`, and echoes it back to `from`.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Whisper {
+    pub to: ClientId,
+    /// Filled in by the server, whatever the client sent
+    pub from: ClientId,
+    pub text: String,
+}
+
+/// Why the server is about to disconnect a client, sent right before it does
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client id is reserved for the server's bots
+    ReservedId,
+    /// The client sent no real input for this many seconds
+    Inactive(u64),
+    /// An admin kicked the client, with an explanation if they gave one
+    Kicked(Option<String>),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ReservedId => write!(f, "Your client id is reserved for bots"),
+            DisconnectReason::Inactive(secs) => {
+                write!(f, "Disconnected after {secs}s without playing")
+            }
+            DisconnectReason::Kicked(None) => write!(f, "Kicked by an admin"),
+            DisconnectReason::Kicked(Some(reason)) => write!(f, "Kicked by an admin: {reason}"),
+        }
+    }
+}
+
+/// The server's current tick, sent every [`super::tick_sync::TICK_BROADCAST_INTERVAL`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ServerTick(pub Tick);
+
+/// Sent by a client once connected when it can unpack [`Compressed`] messages
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AcceptCompression;
+
+/// A message compressed with lz4, see [`super::compression`]
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Compressed(pub Vec<u8>);
+
+/// Share of a client's inputs the server didn't receive in time over the last second, between 0 and 1.
+/// Sent to each client on its own, see [`super::link_quality`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct InputLoss(pub f32);
+
+/// Sent by a client once connected: how far from its players (in pixels) it wants to see the others.
+/// The server doesn't go beyond its own interest radius.
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ViewRadius(pub f32);
+
+/// The players a client can see, sent once on connection so that a late joiner gets their current
+/// state right away, instead of the values last flushed to the replicated components
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorldSnapshot {
+    /// The server tick the snapshot was taken on, replicated updates from later ticks win over it
+    pub tick: Tick,
+    pub players: Vec<PlayerSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerSnapshot {
+    pub client_id: ClientId,
+    pub slot: u8,
+    pub position: Vec2,
+    pub color: Option<Color>,
+    pub health: Option<u32>,
+}
+
 #[message_protocol(protocol = "MatrixRPGGameProto")]
 pub enum Messages {
     Message1(Message1),
+    ReconnectToken(ReconnectToken),
+    ReclaimPlayer(ReclaimPlayer),
+    LevelSeed(LevelSeed),
+    PauseGame(PauseGame),
+    ChatRequest(ChatRequest),
+    ChatLine(ChatLine),
+    Whisper(Whisper),
+    DisconnectReason(DisconnectReason),
+    ServerTick(ServerTick),
+    AcceptCompression(AcceptCompression),
+    Compressed(Compressed),
+    InputLoss(InputLoss),
+    ViewRadius(ViewRadius),
+    WorldSnapshot(WorldSnapshot),
 }
 
 #[derive(Component, Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerId(pub ClientId);
 
+/// Which of the local players of its client controls a player, 0 unless playing split-screen
+#[derive(
+    Component, Message, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash,
+)]
+pub struct PlayerSlot(pub u8);
+
 // `Deref` and `DerefMut` are from bevy
 // `Add` and `Mul` are from the derive_more crate
 #[derive(
@@ -54,17 +250,135 @@ impl std::ops::Mul<f32> for &PlayerPosition {
     }
 }
 
+/// Further than a player can walk between two server updates
+const TELEPORT_DISTANCE: f32 = 128.0;
+
+/// Interpolates positions linearly, except when the player wrapped around the level or got
+/// teleported, where sliding between the two positions would streak across the map
+pub struct PositionLerp;
+
+impl LerpFn<PlayerPosition> for PositionLerp {
+    fn lerp(start: &PlayerPosition, other: &PlayerPosition, t: f32) -> PlayerPosition {
+        if start.distance(other.0) > TELEPORT_DISTANCE {
+            return other.clone();
+        }
+        PlayerPosition(start.lerp(other.0, t))
+    }
+}
+
+/// An impulse pushing a player, in pixels per tick. Added by the server, it moves the player every tick
+/// while decaying, and is removed once it has settled.
+#[derive(
+    Component, Message, Serialize, Deserialize, Clone, Debug, PartialEq, Deref, DerefMut, Add, Mul,
+)]
+pub struct Knockback(pub Vec2);
+
+impl std::ops::Mul<f32> for &Knockback {
+    type Output = Knockback;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Knockback(self.0 * rhs)
+    }
+}
+
+/// How fast a player is moving, in pixels per tick.
+/// Only used when the players accelerate, see [`crate::networking::AccelerationSettings`].
+#[derive(
+    Component,
+    Message,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Deref,
+    DerefMut,
+    Add,
+    Mul,
+)]
+pub struct PlayerVelocity(pub Vec2);
+
+impl std::ops::Mul<f32> for &PlayerVelocity {
+    type Output = PlayerVelocity;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        PlayerVelocity(self.0 * rhs)
+    }
+}
+
+/// How much damage a player can still take, the server sends it back to the spawn point when it runs out
+#[derive(Component, Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlayerHealth(pub u32);
+
+impl PlayerHealth {
+    pub const MAX: u32 = 100;
+
+    /// Take damage, returns whether the player ran out of health
+    pub fn damage(&mut self, amount: u32) -> bool {
+        self.0 = self.0.saturating_sub(amount);
+        self.0 == 0
+    }
+}
+
+impl SmoothValue for PlayerHealth {
+    fn value(&self) -> f32 {
+        self.0 as f32
+    }
+}
+
+impl Default for PlayerHealth {
+    fn default() -> Self {
+        Self(Self::MAX)
+    }
+}
+
+/// The color assigned to a player by the server
+#[derive(Component, Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlayerColor(pub Color);
+
+/// Marks the player of a client that disconnected, left in the world for a while.
+/// See [`super::DisconnectBehavior`].
+#[derive(Component, Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Ghost;
+
 #[component_protocol(protocol = "MatrixRPGGameProto")]
 pub enum Components {
     #[sync(once)]
     PlayerId(PlayerId),
-    #[sync(full)]
+    #[sync(once)]
+    PlayerSlot(PlayerSlot),
+    #[sync(full, lerp = "PositionLerp")]
     PlayerPosition(PlayerPosition),
+    #[sync(simple)]
+    PlayerColor(PlayerColor),
+    #[sync(simple)]
+    PlayerHealth(PlayerHealth),
+    // predicted like the position it moves
+    #[sync(full)]
+    Knockback(Knockback),
+    // predicted like the position it moves
+    #[sync(full)]
+    PlayerVelocity(PlayerVelocity),
+    #[sync(simple)]
+    Ghost(Ghost),
 }
 
 #[derive(Channel)]
 pub struct Channel1;
 
+/// For the large messages sent once in a while, like what a client needs when joining.
+/// They are split in fragments to fit in the packets, and having their own channel keeps them
+/// from holding back the small messages of [`Channel1`] until every fragment arrived.
+#[derive(Channel)]
+pub struct BulkChannel;
+
+/// For the messages only worth their latest value, a late one is dropped rather than delivered
+/// after a newer one or holding the others back
+#[derive(Channel)]
+pub struct TickChannel;
+
 protocolize! {
     Self = MatrixRPGGameProto,
     Message = Messages,
@@ -78,5 +392,76 @@ pub(crate) fn protocol() -> MatrixRPGGameProto {
         mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
         ..default()
     });
+    protocol.add_channel::<BulkChannel>(ChannelSettings {
+        mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+        direction: ChannelDirection::ServerToClient,
+        ..default()
+    });
+    protocol.add_channel::<TickChannel>(ChannelSettings {
+        mode: ChannelMode::SequencedUnreliable,
+        direction: ChannelDirection::ServerToClient,
+        ..default()
+    });
     protocol
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walking_is_interpolated() {
+        let start = PlayerPosition(Vec2::ZERO);
+        let end = PlayerPosition(Vec2::new(10.0, 0.0));
+        assert_eq!(
+            PositionLerp::lerp(&start, &end, 0.5),
+            PlayerPosition(Vec2::new(5.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn teleports_snap_to_the_new_position() {
+        // e.g. an admin `tp`, the player shows up there right away instead of sliding across the map
+        let start = PlayerPosition(Vec2::ZERO);
+        let end = PlayerPosition(Vec2::new(TELEPORT_DISTANCE * 4.0, 0.0));
+        assert_eq!(PositionLerp::lerp(&start, &end, 0.1), end);
+    }
+
+    /// [`Inputs`] as a newer client could send them, with variants this version doesn't have
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum NewerInputs {
+        Direction(Direction),
+        Delete,
+        Spawn,
+        Local(Vec<NewerInputs>),
+        Stop,
+        None,
+        Dash,
+        Emote,
+        Unknown,
+    }
+
+    #[test]
+    fn unknown_inputs_decode_to_the_fallback() {
+        let bytes = bitcode::serialize(&NewerInputs::Emote).unwrap();
+        let input: Inputs = bitcode::deserialize(&bytes).unwrap();
+        assert_eq!(input, Inputs::Unknown);
+
+        let bytes = bitcode::serialize(&NewerInputs::Local(vec![
+            NewerInputs::Spawn,
+            NewerInputs::Dash,
+        ]))
+        .unwrap();
+        let input: Inputs = bitcode::deserialize(&bytes).unwrap();
+        assert_eq!(input, Inputs::Local(vec![Inputs::Spawn, Inputs::Unknown]));
+        assert!(input.is_unknown());
+    }
+
+    #[test]
+    fn known_inputs_still_decode() {
+        let bytes = bitcode::serialize(&NewerInputs::Stop).unwrap();
+        let input: Inputs = bitcode::deserialize(&bytes).unwrap();
+        assert_eq!(input, Inputs::Stop);
+    }
+}
@@ -0,0 +1,206 @@
+//! Reconnect tokens, which let a client that lost its connection take back its player.
+//!
+//! The server hands every client a token signed with a secret drawn at startup, which never leaves
+//! the server (unlike the Netcode private key, which every client holds). After a disconnection
+//! the player's state is kept for a grace period, during which a client presenting a valid token
+//! for the same client id gets it back. Knowing the client id alone is not enough to take over a
+//! player.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use hmac::{Hmac, Mac};
+use lightyear::connection::netcode::ClientId;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+use super::protocol::ReconnectToken;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Current unix time in seconds
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TokenError {
+    /// The token was issued to another client
+    WrongClient,
+    /// The token is past its expiration date
+    Expired,
+    /// The signature doesn't match, the token wasn't issued by this server
+    Forged,
+}
+
+/// Issues and validates the reconnect tokens
+#[derive(Resource)]
+pub(crate) struct ReconnectTokens {
+    key: [u8; 32],
+    lifetime: Duration,
+    /// When the token last sent to each connected client expires
+    expirations: HashMap<ClientId, u64>,
+}
+
+impl ReconnectTokens {
+    pub(crate) fn new(key: [u8; 32], lifetime: Duration) -> Self {
+        Self {
+            key,
+            lifetime,
+            expirations: HashMap::default(),
+        }
+    }
+
+    /// Sign with a fresh random key, the tokens don't outlive the server anyway
+    pub(crate) fn with_random_key(lifetime: Duration) -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self::new(key, lifetime)
+    }
+
+    fn mac(&self, client_id: ClientId, expires_at: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(b"matrix-rpg reconnect token");
+        mac.update(&client_id.to_le_bytes());
+        mac.update(&expires_at.to_le_bytes());
+        mac
+    }
+
+    /// Create a new token for the client, valid for the configured lifetime starting at `now`
+    pub(crate) fn issue(&mut self, client_id: ClientId, now: u64) -> ReconnectToken {
+        let expires_at = now + self.lifetime.as_secs();
+        self.expirations.insert(client_id, expires_at);
+        ReconnectToken {
+            client_id,
+            expires_at,
+            signature: self
+                .mac(client_id, expires_at)
+                .finalize()
+                .into_bytes()
+                .into(),
+        }
+    }
+
+    /// Check that the token was issued by us to `client_id` and is still valid at `now`
+    pub(crate) fn verify(
+        &self,
+        token: &ReconnectToken,
+        client_id: ClientId,
+        now: u64,
+    ) -> Result<(), TokenError> {
+        self.mac(token.client_id, token.expires_at)
+            .verify_slice(&token.signature)
+            .map_err(|_| TokenError::Forged)?;
+        if token.client_id != client_id {
+            return Err(TokenError::WrongClient);
+        }
+        if token.expires_at < now {
+            return Err(TokenError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Clients whose token has used up more than half of its lifetime and should get a new one
+    pub(crate) fn needs_refresh(&self, now: u64) -> Vec<ClientId> {
+        let half_lifetime = self.lifetime.as_secs() / 2;
+        self.expirations
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now + half_lifetime)
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+
+    /// Stop refreshing the token of a client that disconnected
+    pub(crate) fn forget(&mut self, client_id: ClientId) {
+        self.expirations.remove(&client_id);
+    }
+}
+
+/// State of players whose client disconnected, kept around for the grace period
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ReclaimablePlayers {
+    players: HashMap<ClientId, (Vec2, u64)>,
+    /// Positions reclaimed by reconnected clients, applied once their new player gets replicated
+    pending: HashMap<ClientId, Vec2>,
+}
+
+impl ReclaimablePlayers {
+    pub(crate) fn remember(&mut self, client_id: ClientId, position: Vec2, now: u64) {
+        self.players.insert(client_id, (position, now));
+    }
+
+    /// Move the player of `client_id` to the pending reclaims, if it disconnected less than `grace` ago
+    pub(crate) fn reclaim(
+        &mut self,
+        client_id: ClientId,
+        now: u64,
+        grace: Duration,
+    ) -> Option<Vec2> {
+        let (position, disconnected_at) = self.players.remove(&client_id)?;
+        if disconnected_at + grace.as_secs() < now {
+            return None;
+        }
+        self.pending.insert(client_id, position);
+        Some(position)
+    }
+
+    /// Take the reclaimed position for a newly spawned player
+    pub(crate) fn take_pending(&mut self, client_id: ClientId) -> Option<Vec2> {
+        self.pending.remove(&client_id)
+    }
+
+    /// Drop the players that weren't reclaimed in time
+    pub(crate) fn expire(&mut self, now: u64, grace: Duration) {
+        self.players
+            .retain(|_, (_, disconnected_at)| *disconnected_at + grace.as_secs() >= now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIFETIME: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn valid_token_is_accepted() {
+        let mut tokens = ReconnectTokens::new([1; 32], LIFETIME);
+        let token = tokens.issue(7, 1000);
+        assert_eq!(tokens.verify(&token, 7, 1030), Ok(()));
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        let mut tokens = ReconnectTokens::new([1; 32], LIFETIME);
+        let token = tokens.issue(7, 1000);
+        assert_eq!(tokens.verify(&token, 7, 1061), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn token_of_another_client_is_refused() {
+        let mut tokens = ReconnectTokens::new([1; 32], LIFETIME);
+        let token = tokens.issue(7, 1000);
+        assert_eq!(tokens.verify(&token, 8, 1000), Err(TokenError::WrongClient));
+    }
+
+    #[test]
+    fn forged_token_is_refused() {
+        let mut tokens = ReconnectTokens::new([1; 32], LIFETIME);
+        let mut token = tokens.issue(7, 1000);
+        token.client_id = 8;
+        assert_eq!(tokens.verify(&token, 8, 1000), Err(TokenError::Forged));
+    }
+
+    #[test]
+    fn token_signed_with_another_key_is_refused() {
+        let mut minted = ReconnectTokens::new([2; 32], LIFETIME);
+        let token = minted.issue(7, 1000);
+        let tokens = ReconnectTokens::with_random_key(LIFETIME);
+        assert_eq!(tokens.verify(&token, 7, 1000), Err(TokenError::Forged));
+    }
+}
@@ -1,13 +1,30 @@
+use std::collections::VecDeque;
+
 use bevy::app::PluginGroupBuilder;
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
 use bevy::prelude::*;
-use bevy::utils::Duration;
+use bevy::render::RenderPlugin;
+use bevy::utils::{Duration, HashMap, HashSet};
 
+use lightyear::connection::netcode::ClientId;
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 
-use crate::networking::shared_movement_behaviour;
+use crate::collision::{nearest_free_position, push_out, PLAYER_HITBOX};
+use crate::level::{Cell, LevelGrid};
+use crate::networking::{
+    player_collisions_enabled, shared_knockback_behaviour, shared_movement_behaviour,
+    shared_push_behaviour,
+};
+
+use super::bandwidth::CountedServer;
+use super::bots::is_bot;
+use super::reconnect::{unix_now, ReclaimablePlayers, ReconnectTokens};
 
-use super::{protocol::*, shared_config, SharedSettings};
+use super::{
+    protocol::*, shared_config, DisconnectBehavior, ServerSettings, SessionRng, SharedSettings,
+    MAX_LOCAL_PLAYERS,
+};
 
 // Plugin group to add all server-related plugins
 pub struct ServerPluginGroup {
@@ -20,25 +37,27 @@ impl ServerPluginGroup {
         shared_settings: SharedSettings,
     ) -> ServerPluginGroup {
         // Step 1: create the io (transport + link conditioner)
-        let link_conditioner = LinkConditionerConfig {
-            incoming_latency: Duration::from_millis(200),
-            incoming_jitter: Duration::from_millis(20),
-            incoming_loss: 0.05,
-        };
         let mut net_configs = vec![];
         for transport_config in transport_configs {
+            let link_conditioner = shared_settings.link_conditioner(&transport_config);
+            let mut io = IoConfig::from_transport(transport_config);
+            if let Some(link_conditioner) = link_conditioner {
+                io = io.with_conditioner(link_conditioner);
+            }
             net_configs.push(NetConfig::Netcode {
-                config: NetcodeConfig::default()
-                    .with_protocol_id(shared_settings.protocol_id)
-                    .with_key(shared_settings.private_key),
-                io: IoConfig::from_transport(transport_config)
-                    .with_conditioner(link_conditioner.clone()),
+                config: NetcodeConfig {
+                    keepalive_packet_send_rate: shared_settings.keepalive_interval_secs,
+                    ..default()
+                }
+                .with_protocol_id(shared_settings.protocol_id)
+                .with_key(shared_settings.private_key),
+                io,
             });
         }
 
         // Step 2: define the server configuration
         let config = ServerConfig {
-            shared: shared_config(),
+            shared: shared_config(&shared_settings),
             net: net_configs,
             ..default()
         };
@@ -56,6 +75,9 @@ impl PluginGroup for ServerPluginGroup {
         PluginGroupBuilder::start::<Self>()
             .add(self.lightyear)
             .add(MatrixRPGServerPlugin)
+            .add(super::console::AdminConsolePlugin)
+            .add(super::bandwidth::BandwidthPlugin)
+            .add(super::lag_compensation::LagCompensationPlugin)
             .add(super::SharedPlugin)
     }
 }
@@ -65,70 +87,1236 @@ pub struct MatrixRPGServerPlugin;
 
 impl Plugin for MatrixRPGServerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, init);
+        app.init_resource::<ConnectedPlayers>();
+        app.init_resource::<ColorAssignments>();
+        app.init_resource::<RejectedConnections>();
+        app.init_resource::<ReclaimablePlayers>();
+        app.init_resource::<SpawnGuard>();
+        app.init_resource::<LastActivity>();
+        app.init_resource::<Ghosts>();
+        app.init_resource::<InputStats>();
+        app.init_resource::<PendingMoves>();
+        app.init_resource::<WorldPaused>();
+        app.init_resource::<PendingDisconnects>();
+        app.init_resource::<ViewRadii>();
+        app.init_resource::<super::compression::CompressingClients>();
+        app.register_diagnostic(Diagnostic::new(MISSED_INPUTS).with_suffix("%"));
+        app.add_systems(
+            Startup,
+            (init, init_reconnect_tokens, init_procedural_level),
+        );
         // Re-adding Replicate components to client-replicated entities must be done in this set for proper handling.
         app.add_systems(
             PreUpdate,
             (replicate_players).in_set(MainSet::ClientReplication),
         );
         // the physics/FixedUpdates systems that consume inputs should be run in this set
-        app.add_systems(FixedUpdate, movement);
+        app.add_systems(
+            FixedUpdate,
+            (
+                movement,
+                knockback,
+                separate_players.run_if(player_collisions_enabled),
+                hazard_damage,
+            )
+                .chain()
+                .run_if(resource_equals(WorldPaused(false))),
+        );
+        if app.is_plugin_added::<RenderPlugin>() {
+            app.add_systems(
+                Update,
+                update_player_count_text.run_if(resource_changed::<ConnectedPlayers>),
+            );
+        }
+        app.add_systems(
+            Update,
+            send_level_seed.run_if(resource_exists::<ProceduralLevel>),
+        );
+        app.add_systems(
+            Update,
+            (receive_view_radii, update_areas_of_interest)
+                .chain()
+                .run_if(interest_radius_set),
+        );
+        app.add_systems(Update, flush_throttled::<PlayerHealth>);
+        app.add_systems(Update, (report_input_loss, measure_missed_inputs));
+        app.add_systems(Update, super::tick_sync::broadcast_server_tick);
+        app.add_systems(Update, handle_pause_requests);
+        app.add_systems(
+            Update,
+            (
+                super::compression::register_compressing_clients,
+                (
+                    super::chat::handle_chat_requests,
+                    super::chat::forward_whispers,
+                ),
+            )
+                .chain(),
+        );
+        app.add_systems(
+            Update,
+            disconnect_idle_clients
+                .run_if(inactivity_timeout_set)
+                .before(handle_disconnections),
+        );
+        app.add_systems(Update, finish_disconnects.before(handle_disconnections));
         //app.add_systems(Update, send_message);
-        app.add_systems(Update, handle_disconnections);
+        app.add_systems(
+            Update,
+            (
+                enforce_single_player,
+                track_connected_players,
+                // a client reconnecting in the same frame its previous connection dropped must
+                // not find its old player still alive
+                handle_disconnections,
+                handle_connections,
+                send_world_snapshot,
+                send_reconnect_tokens,
+                handle_reclaims,
+                despawn_ghosts,
+            )
+                .chain(),
+        );
     }
 }
 
+/// Marker for the text showing how many players are connected
+#[derive(Component)]
+pub(crate) struct PlayerCountText;
+
+fn player_count_label(count: usize) -> String {
+    format!("Server — {} players", count)
+}
+
 pub(crate) fn init(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
-    commands.spawn(TextBundle::from_section(
-        "Server",
-        TextStyle {
-            font_size: 30.0,
-            color: Color::WHITE,
-            ..default()
-        },
+    commands.spawn((
+        TextBundle::from_section(
+            player_count_label(0),
+            TextStyle {
+                font_size: 30.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        ),
+        PlayerCountText,
     ));
 }
 
-/// Server disconnection system, delete all player entities upon disconnection
+/// Show the live number of connected players
+pub(crate) fn update_player_count_text(
+    connected_players: Res<ConnectedPlayers>,
+    mut texts: Query<&mut Text, With<PlayerCountText>>,
+) {
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = player_count_label(connected_players.count());
+    }
+}
+
+/// The clients that are currently connected to the server, in the order they joined
+#[derive(Resource, Default, Debug)]
+pub struct ConnectedPlayers {
+    client_ids: Vec<ClientId>,
+}
+
+impl ConnectedPlayers {
+    /// Clients that connected in this order, for the tests
+    #[cfg(test)]
+    pub(crate) fn from_ids(client_ids: &[ClientId]) -> Self {
+        ConnectedPlayers {
+            client_ids: client_ids.to_vec(),
+        }
+    }
+
+    /// Number of connected clients
+    pub fn count(&self) -> usize {
+        self.client_ids.len()
+    }
+
+    pub fn client_ids(&self) -> &[ClientId] {
+        &self.client_ids
+    }
+
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.client_ids.contains(&client_id)
+    }
+
+    fn connect(&mut self, client_id: ClientId) {
+        if !self.contains(client_id) {
+            self.client_ids.push(client_id);
+        }
+    }
+
+    fn disconnect(&mut self, client_id: ClientId) {
+        // the client may disconnect before we saw it connect, in which case there is nothing to do
+        self.client_ids.retain(|id| *id != client_id);
+    }
+}
+
+/// Keep the [`ConnectedPlayers`] up to date
+pub(crate) fn track_connected_players(
+    mut connections: EventReader<ConnectEvent>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut connected_players: ResMut<ConnectedPlayers>,
+) {
+    // a client id can't be connected twice, so when a connected client shows up in both the
+    // connections and the disconnections of this frame, it disconnected first
+    let mut disconnected: Vec<ClientId> = disconnections.read().map(|d| *d.context()).collect();
+    disconnected.retain(|client_id| {
+        let was_connected = connected_players.contains(*client_id);
+        connected_players.disconnect(*client_id);
+        !was_connected
+    });
+    for connection in connections.read() {
+        connected_players.connect(*connection.context());
+    }
+    for client_id in disconnected {
+        connected_players.disconnect(client_id);
+    }
+}
+
+/// Colors handed out to players, chosen to be easy to tell apart from each other
+const PLAYER_PALETTE: [Color; 12] = [
+    Color::rgb(0.90, 0.10, 0.29),
+    Color::rgb(0.24, 0.71, 0.29),
+    Color::rgb(1.00, 0.88, 0.10),
+    Color::rgb(0.26, 0.39, 0.85),
+    Color::rgb(0.96, 0.51, 0.19),
+    Color::rgb(0.57, 0.12, 0.71),
+    Color::rgb(0.27, 0.94, 0.94),
+    Color::rgb(0.94, 0.20, 0.90),
+    Color::rgb(0.74, 0.96, 0.05),
+    Color::rgb(0.98, 0.75, 0.83),
+    Color::rgb(0.00, 0.50, 0.50),
+    Color::rgb(0.67, 0.43, 0.16),
+];
+
+/// Keeps track of which palette color is used by which player, so that every connected player
+/// gets a distinct color as long as there are enough colors in the palette
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ColorAssignments {
+    assigned: HashMap<(ClientId, PlayerSlot), usize>,
+}
+
+impl ColorAssignments {
+    /// Get the color of a player, assigning the first free palette color if it doesn't have one yet.
+    /// Once the palette is exhausted, the color is derived from the client id instead.
+    pub(crate) fn assign(&mut self, client_id: ClientId, slot: PlayerSlot) -> Color {
+        if let Some(index) = self.assigned.get(&(client_id, slot)) {
+            return PLAYER_PALETTE[*index];
+        }
+        let free =
+            (0..PLAYER_PALETTE.len()).find(|i| !self.assigned.values().any(|used| used == i));
+        match free {
+            Some(index) => {
+                self.assigned.insert((client_id, slot), index);
+                PLAYER_PALETTE[index]
+            }
+            None => hashed_color(client_id, slot),
+        }
+    }
+
+    /// Free the colors of all the players of a client so that they can be reused by new players
+    pub(crate) fn release(&mut self, client_id: ClientId) {
+        self.assigned.retain(|(id, _), _| *id != client_id);
+    }
+}
+
+/// Fallback color for when every palette color is in use
+pub(crate) fn hashed_color(client_id: ClientId, slot: PlayerSlot) -> Color {
+    // spread consecutive ids over the hue circle using the golden ratio
+    let hash = (client_id ^ (u64::from(slot.0) << 56)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let hue = (hash >> 40) as f32 / (1u64 << 24) as f32 * 360.0;
+    Color::hsl(hue, 0.8, 0.6)
+}
+
+/// Clients whose connection was refused because their id is reserved for bots.
+/// Their disconnection must not remove the bot's players.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct RejectedConnections(HashSet<ClientId>);
+
+/// Server connection system, replace the players left behind by a previous connection with the same id.
+/// Netcode ignores a client connecting with the id of a live connection, so such players always
+/// belong to a connection that is gone, e.g. one that reconnected before its disconnection was handled.
+pub(crate) fn handle_connections(
+    mut connections: EventReader<ConnectEvent>,
+    mut commands: Commands,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut rejected: ResMut<RejectedConnections>,
+    mut reclaimable: ResMut<ReclaimablePlayers>,
+    mut ghosts: ResMut<Ghosts>,
+    player_entities: Query<(Entity, &PlayerId, &PlayerSlot, &PlayerPosition), Without<Ghost>>,
+) {
+    for connection in connections.read() {
+        let client_id = *connection.context();
+        if is_bot(client_id) {
+            warn!(
+                "Rejecting connection from client {}: the id is reserved for bots",
+                client_id
+            );
+            rejected.0.insert(client_id);
+            pending_disconnects.schedule(client_id, DisconnectReason::ReservedId);
+            continue;
+        }
+        // the ghost steps aside, the reconnect token brings its position back to the new player
+        for entity in ghosts.remove(client_id) {
+            commands.entity(entity).despawn();
+        }
+        for (entity, player_id, slot, position) in player_entities.iter() {
+            if player_id.0 != client_id {
+                continue;
+            }
+            warn!(
+                "Client {} reconnected while its previous player was still alive, replacing it",
+                client_id
+            );
+            // the new connection can still take the player back with its reconnect token,
+            // split-screen players other than the first one just spawn again
+            if *slot == PlayerSlot(0) {
+                reclaimable.remember(client_id, position.0, unix_now());
+            }
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Server disconnection system, delete all player entities upon disconnection,
+/// or leave them as ghosts depending on [`DisconnectBehavior`]
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn handle_disconnections(
     mut disconnections: EventReader<DisconnectEvent>,
     mut commands: Commands,
-    player_entities: Query<(Entity, &PlayerId)>,
+    time: Res<Time<Real>>,
+    settings: Res<ServerSettings>,
+    mut ghosts: ResMut<Ghosts>,
+    mut color_assignments: ResMut<ColorAssignments>,
+    mut rejected: ResMut<RejectedConnections>,
+    mut reconnect_tokens: ResMut<ReconnectTokens>,
+    mut reclaimable: ResMut<ReclaimablePlayers>,
+    mut spawn_guard: ResMut<SpawnGuard>,
+    mut last_activity: ResMut<LastActivity>,
+    mut input_stats: ResMut<InputStats>,
+    mut pending_moves: ResMut<PendingMoves>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut view_radii: ResMut<ViewRadii>,
+    player_entities: Query<(Entity, &PlayerId, &PlayerSlot, &PlayerPosition)>,
 ) {
     for disconnection in disconnections.read() {
         let client_id = disconnection.context();
-        for (entity, player_id) in player_entities.iter() {
+        pending_disconnects.cancel(*client_id);
+        if rejected.0.remove(client_id) {
+            // the players with this id belong to a bot
+            continue;
+        }
+        color_assignments.release(*client_id);
+        reconnect_tokens.forget(*client_id);
+        spawn_guard.forget(*client_id);
+        last_activity.0.remove(client_id);
+        let inputs = input_stats.get(*client_id);
+        if inputs.missed > 0 {
+            info!(
+                "Client {} disconnected after missing {} of {} inputs",
+                client_id,
+                inputs.missed,
+                inputs.received + inputs.missed
+            );
+        }
+        input_stats.forget(*client_id);
+        pending_moves.forget(*client_id);
+        view_radii.0.remove(client_id);
+        for (entity, player_id, slot, position) in player_entities.iter() {
             if player_id.0 == *client_id {
-                commands.entity(entity).despawn();
+                if *slot == PlayerSlot(0) {
+                    reclaimable.remember(*client_id, position.0, unix_now());
+                }
+                match settings.on_disconnect {
+                    DisconnectBehavior::Despawn => commands.entity(entity).despawn(),
+                    DisconnectBehavior::Ghost { linger_secs } => {
+                        let despawn_at = time.elapsed() + Duration::from_secs(linger_secs);
+                        ghosts.add(*client_id, entity, despawn_at);
+                        // the ghost stays where it was left
+                        commands.entity(entity).insert(Ghost).remove::<Knockback>();
+                    }
+                }
             }
         }
     }
 }
 
-/// Read client inputs and move players
+/// Players of disconnected clients left in the world, with when they get removed, in real time since startup
+#[derive(Resource, Default, Debug)]
+pub(crate) struct Ghosts(HashMap<ClientId, (Vec<Entity>, Duration)>);
+
+impl Ghosts {
+    fn add(&mut self, client_id: ClientId, entity: Entity, despawn_at: Duration) {
+        let (entities, at) = self.0.entry(client_id).or_default();
+        entities.push(entity);
+        *at = despawn_at;
+    }
+
+    /// Forget the ghosts of a client, returns them so they can be despawned
+    pub(crate) fn remove(&mut self, client_id: ClientId) -> Vec<Entity> {
+        self.0
+            .remove(&client_id)
+            .map(|(entities, _)| entities)
+            .unwrap_or_default()
+    }
+
+    /// Forget the ghosts that lingered long enough, returns them so they can be despawned
+    pub(crate) fn expire(&mut self, now: Duration) -> Vec<Entity> {
+        let expired: Vec<ClientId> = self
+            .0
+            .iter()
+            .filter(|(_, (_, despawn_at))| *despawn_at <= now)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        expired
+            .into_iter()
+            .flat_map(|client_id| self.remove(client_id))
+            .collect()
+    }
+}
+
+pub(crate) fn despawn_ghosts(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    mut ghosts: ResMut<Ghosts>,
+) {
+    for entity in ghosts.expire(time.elapsed()) {
+        if let Some(mut entity) = commands.get_entity(entity) {
+            entity.despawn();
+        }
+    }
+}
+
+pub(crate) fn init_reconnect_tokens(mut commands: Commands, settings: Res<ServerSettings>) {
+    // tokens are refreshed at half their lifetime, so a disconnected client always holds
+    // a token that outlives the grace period
+    let lifetime = Duration::from_secs(2 * settings.reconnect_grace_secs);
+    // the clients hold the Netcode private key, they could sign tokens for any client id with it
+    commands.insert_resource(ReconnectTokens::with_random_key(lifetime));
+}
+
+/// Give newly connected clients a reconnect token, and refresh the tokens before they expire
+pub(crate) fn send_reconnect_tokens(
+    mut connections: EventReader<ConnectEvent>,
+    mut server: CountedServer,
+    mut reconnect_tokens: ResMut<ReconnectTokens>,
+    mut reclaimable: ResMut<ReclaimablePlayers>,
+    settings: Res<ServerSettings>,
+) {
+    let now = unix_now();
+    let mut recipients: Vec<ClientId> = connections.read().map(|c| *c.context()).collect();
+    recipients.extend(reconnect_tokens.needs_refresh(now));
+    for client_id in recipients {
+        let token = reconnect_tokens.issue(client_id, now);
+        server
+            .send_message_to_target::<Channel1, ReconnectToken>(
+                token,
+                NetworkTarget::Only(vec![client_id]),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to send reconnect token: {:?}", e);
+            });
+    }
+    reclaimable.expire(now, Duration::from_secs(settings.reconnect_grace_secs));
+}
+
+/// Give back their previous player to clients that present a valid reconnect token
+pub(crate) fn handle_reclaims(
+    mut reclaims: EventReader<MessageEvent<ReclaimPlayer>>,
+    reconnect_tokens: Res<ReconnectTokens>,
+    mut reclaimable: ResMut<ReclaimablePlayers>,
+    settings: Res<ServerSettings>,
+    mut player_entities: Query<(&PlayerId, &PlayerSlot, &mut PlayerPosition)>,
+) {
+    let now = unix_now();
+    for reclaim in reclaims.read() {
+        let client_id = *reclaim.context();
+        let token = &reclaim.message().0;
+        if let Err(e) = reconnect_tokens.verify(token, client_id, now) {
+            warn!(
+                "Client {} presented an invalid reconnect token: {:?}",
+                client_id, e
+            );
+            continue;
+        }
+        let grace = Duration::from_secs(settings.reconnect_grace_secs);
+        let Some(position) = reclaimable.reclaim(client_id, now, grace) else {
+            debug!("Client {} has no player left to reclaim", client_id);
+            continue;
+        };
+        info!("Client {} reclaimed its player at {}", client_id, position);
+        // the client may already have spawned its new player, otherwise it is moved when it gets replicated
+        for (player_id, slot, mut player_position) in player_entities.iter_mut() {
+            if player_id.0 == client_id && *slot == PlayerSlot(0) {
+                player_position.0 = position;
+                reclaimable.take_pending(client_id);
+            }
+        }
+    }
+}
+
+/// Make sure that no client ever ends up with more than one player per local player slot,
+/// whatever race led to it.
+/// The player that is already being replicated is kept, the extra ones are despawned.
+pub(crate) fn enforce_single_player(
+    mut commands: Commands,
+    players: Query<
+        (Entity, &PlayerId, &PlayerSlot, Has<Replicate>),
+        (With<PlayerPosition>, Without<Ghost>),
+    >,
+) {
+    let mut by_slot: HashMap<(ClientId, PlayerSlot), Vec<(Entity, bool)>> = HashMap::default();
+    for (entity, player_id, slot, replicated) in players.iter() {
+        by_slot
+            .entry((player_id.0, *slot))
+            .or_default()
+            .push((entity, replicated));
+    }
+    for ((client_id, slot), mut entities) in by_slot {
+        if entities.len() < 2 {
+            continue;
+        }
+        // replicated entities first, then oldest first
+        entities.sort_by_key(|(entity, replicated)| (!replicated, *entity));
+        warn!(
+            "Client {} has {} players in slot {}, keeping {:?}",
+            client_id,
+            entities.len(),
+            slot.0,
+            entities[0].0
+        );
+        for (entity, _) in entities.into_iter().skip(1) {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// The seed of the generated level, only present when not using the LDtk level
+#[derive(Resource, Clone, Copy, Debug)]
+pub(crate) struct ProceduralLevel(pub LevelSeed);
+
+pub(crate) fn init_procedural_level(
+    mut commands: Commands,
+    settings: Res<ServerSettings>,
+    mut rng: ResMut<SessionRng>,
+) {
+    let Some(procedural_level) = &settings.procedural_level else {
+        return;
+    };
+    let seed = procedural_level
+        .seed
+        .unwrap_or_else(|| rand::Rng::gen(&mut rng.0));
+    info!(
+        "Generating a {}x{} level from seed {}, set it in the settings to get the same level again",
+        procedural_level.width, procedural_level.height, seed
+    );
+    let level = LevelSeed {
+        seed,
+        width: procedural_level.width,
+        height: procedural_level.height,
+    };
+    commands.insert_resource(LevelGrid::generate(level.seed, level.width, level.height));
+    commands.insert_resource(ProceduralLevel(level));
+}
+
+/// Tell newly connected clients which level to build
+pub(crate) fn send_level_seed(
+    mut connections: EventReader<ConnectEvent>,
+    mut server: CountedServer,
+    level: Res<ProceduralLevel>,
+) {
+    for connection in connections.read() {
+        server
+            .send_message_to_target::<BulkChannel, LevelSeed>(
+                level.0,
+                NetworkTarget::Only(vec![*connection.context()]),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to send level seed: {:?}", e);
+            });
+    }
+}
+
+/// Send each new client the players it can see as they are now, health included even when its
+/// replicated value is only flushed later. Runs after the players replaced by the connection are gone.
+pub(crate) fn send_world_snapshot(
+    mut connections: EventReader<ConnectEvent>,
+    mut server: CountedServer,
+    tick_manager: Res<TickManager>,
+    rejected: Res<RejectedConnections>,
+    players: Query<(
+        &PlayerId,
+        &PlayerSlot,
+        &PlayerPosition,
+        Option<&PlayerColor>,
+        Option<&Throttled<PlayerHealth>>,
+        &Replicate,
+    )>,
+) {
+    for connection in connections.read() {
+        let client_id = *connection.context();
+        if rejected.0.contains(&client_id) {
+            continue;
+        }
+        let snapshot = WorldSnapshot {
+            tick: tick_manager.tick(),
+            players: players
+                .iter()
+                .filter(|(_, _, _, _, _, replicate)| {
+                    replicate.replication_target.should_send_to(&client_id)
+                })
+                .map(
+                    |(player_id, slot, position, color, health, _)| PlayerSnapshot {
+                        client_id: player_id.0,
+                        slot: slot.0,
+                        position: position.0,
+                        color: color.map(|color| color.0),
+                        health: health.map(|health| health.0 .0),
+                    },
+                )
+                .collect(),
+        };
+        if snapshot.players.is_empty() {
+            continue;
+        }
+        server
+            .send_message_to_target::<BulkChannel, WorldSnapshot>(
+                snapshot,
+                NetworkTarget::Only(vec![client_id]),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to send world snapshot: {:?}", e);
+            });
+    }
+}
+
+/// Minimum time between two spawns or deletions of the same player
+const SPAWN_TOGGLE_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Rate-limits how often clients can spawn and delete their players
+#[derive(Resource, Default, Debug)]
+pub(crate) struct SpawnGuard {
+    last_toggle: HashMap<(ClientId, PlayerSlot), Duration>,
+    ignored: HashMap<ClientId, u32>,
+}
+
+impl SpawnGuard {
+    /// Whether the client may spawn or delete the player in `slot` at time `now`, recording it if so
+    pub(crate) fn allow(&mut self, client_id: ClientId, slot: PlayerSlot, now: Duration) -> bool {
+        if let Some(last_toggle) = self.last_toggle.get(&(client_id, slot)) {
+            if now.saturating_sub(*last_toggle) < SPAWN_TOGGLE_COOLDOWN {
+                return false;
+            }
+        }
+        self.last_toggle.insert((client_id, slot), now);
+        true
+    }
+
+    /// Record a refused spawn or deletion, returns how many were refused for this client so far
+    pub(crate) fn ignore(&mut self, client_id: ClientId) -> u32 {
+        let ignored = self.ignored.entry(client_id).or_default();
+        *ignored += 1;
+        *ignored
+    }
+
+    pub(crate) fn forget(&mut self, client_id: ClientId) {
+        self.last_toggle.retain(|(id, _), _| *id != client_id);
+        self.ignored.remove(&client_id);
+    }
+}
+
+/// Inputs received from each client that weren't applied yet, see [`ServerSettings::max_steps_per_tick`]
+#[derive(Resource, Default, Debug)]
+pub(crate) struct PendingMoves(HashMap<ClientId, VecDeque<Inputs>>);
+
+/// Inputs kept for a client that sends faster than they are applied, the oldest ones are dropped beyond that
+const MAX_PENDING_MOVES: usize = 16;
+
+impl PendingMoves {
+    fn push(&mut self, client_id: ClientId, input: Inputs) {
+        let pending = self.0.entry(client_id).or_default();
+        if pending.len() == MAX_PENDING_MOVES {
+            pending.pop_front();
+        }
+        pending.push_back(input);
+    }
+
+    /// The oldest inputs of each client, at most `max_steps` per client
+    fn take(&mut self, max_steps: usize) -> Vec<(ClientId, Inputs)> {
+        let mut inputs = vec![];
+        for (client_id, pending) in self.0.iter_mut() {
+            let steps = pending.len().min(max_steps);
+            inputs.extend(pending.drain(..steps).map(|input| (*client_id, input)));
+        }
+        self.0.retain(|_, pending| !pending.is_empty());
+        inputs
+    }
+
+    /// Drop the queued movements of a player that stopped on purpose, its other inputs are kept
+    fn cancel_moves(&mut self, client_id: ClientId, slot: u8) {
+        let Some(pending) = self.0.get_mut(&client_id) else {
+            return;
+        };
+        for input in pending.iter_mut() {
+            let input = match input {
+                Inputs::Local(inputs) => inputs.get_mut(slot as usize),
+                input if slot == 0 => Some(input),
+                _ => None,
+            };
+            if let Some(input) = input.filter(|input| matches!(input, Inputs::Direction(_))) {
+                *input = Inputs::None;
+            }
+        }
+    }
+
+    pub(crate) fn forget(&mut self, client_id: ClientId) {
+        self.0.remove(&client_id);
+    }
+}
+
+/// Read client inputs and move players. Inputs arriving together after a lag spike are spread
+/// over the next ticks, instead of making the player jump.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn movement(
-    mut position_query: Query<(&mut PlayerPosition, &PlayerId)>,
+    mut commands: Commands,
+    mut spawn_guard: ResMut<SpawnGuard>,
+    mut last_activity: ResMut<LastActivity>,
+    mut input_stats: ResMut<InputStats>,
+    mut pending_moves: ResMut<PendingMoves>,
+    settings: Res<ServerSettings>,
+    time: Res<Time<Real>>,
+    mut position_query: Query<(
+        Entity,
+        &mut PlayerPosition,
+        Option<&mut PlayerVelocity>,
+        &PlayerId,
+        &PlayerSlot,
+    )>,
     mut input_reader: EventReader<InputEvent<Inputs>>,
     tick_manager: Res<TickManager>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+    mut newer_clients: Local<HashSet<ClientId>>,
 ) {
     for input in input_reader.read() {
         let client_id = input.context();
-        if let Some(input) = input.input() {
+        // clients send an input every tick, even `Inputs::None`, so a missing one was lost on the way
+        let Some(input) = input.input() else {
+            let missed = input_stats.miss(*client_id);
             debug!(
-                "Receiving input: {:?} from client: {:?} on tick: {:?}",
-                input,
+                "No input from client {} on tick {:?} ({} missed so far)",
                 client_id,
-                tick_manager.tick()
+                tick_manager.tick(),
+                missed
             );
+            continue;
+        };
+        input_stats.receive(*client_id);
+        if input.is_unknown() && newer_clients.insert(*client_id) {
+            warn!(
+                "Client {} sends inputs this server doesn't know, it is probably newer. They are ignored",
+                client_id
+            );
+        }
+        debug!(
+            "Receiving input: {:?} from client: {:?} on tick: {:?}",
+            input,
+            client_id,
+            tick_manager.tick()
+        );
+        last_activity.record(*client_id, input.is_active(), time.elapsed());
+        for slot in 0..MAX_LOCAL_PLAYERS {
+            if input.for_slot(slot) == Some(&Inputs::Stop) {
+                pending_moves.cancel_moves(*client_id, slot);
+            }
+        }
+        pending_moves.push(*client_id, input.clone());
+    }
 
-            for (position, player_id) in position_query.iter_mut() {
-                if player_id.0 == *client_id {
-                    // NOTE: be careful to directly pass Mut<PlayerPosition>
-                    // getting a mutable reference triggers change detection, unless you use `as_deref_mut()`
-                    shared_movement_behaviour(position, input);
+    let max_steps = settings
+        .max_steps_per_tick
+        .map_or(usize::MAX, |steps| steps as usize);
+    for (client_id, input) in pending_moves.take(max_steps) {
+        for (entity, position, velocity, player_id, slot) in position_query.iter_mut() {
+            if player_id.0 != client_id {
+                continue;
+            }
+            let Some(input) = input.for_slot(slot.0) else {
+                continue;
+            };
+            if input == &Inputs::Delete {
+                if spawn_guard.allow(client_id, *slot, time.elapsed()) {
+                    // the client already removed its predicted player, remove the confirmed one
+                    commands.entity(entity).despawn();
+                } else {
+                    let ignored = spawn_guard.ignore(client_id);
+                    warn!(
+                        "Ignoring player deletion from client {} ({} ignored so far)",
+                        client_id, ignored
+                    );
                 }
+                continue;
             }
+            // NOTE: be careful to directly pass Mut<PlayerPosition>
+            // getting a mutable reference triggers change detection, unless you use `as_deref_mut()`
+            shared_movement_behaviour(position, velocity, input, &level, &shared_settings);
+        }
+    }
+}
+
+/// The client playing in the same process as a listen-server, the only one allowed to pause the game
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostClient(pub ClientId);
+
+/// Whether the gameplay is frozen because the host paused the game.
+/// The connections keep running, so that nobody times out while the game is paused.
+#[derive(Resource, Default, Debug, PartialEq, Eq)]
+pub(crate) struct WorldPaused(pub bool);
+
+/// Pause or resume the game when the host asks for it, other clients only pause their own view
+pub(crate) fn handle_pause_requests(
+    mut requests: EventReader<MessageEvent<PauseGame>>,
+    host: Option<Res<HostClient>>,
+    mut paused: ResMut<WorldPaused>,
+) {
+    for request in requests.read() {
+        let client_id = *request.context();
+        if host.as_ref().map(|host| host.0) != Some(client_id) {
+            warn!("Ignoring pause request from client {}", client_id);
+            continue;
+        }
+        let PauseGame(pause) = *request.message();
+        info!(
+            "Game {} by the host",
+            if pause { "paused" } else { "resumed" }
+        );
+        paused.set_if_neq(WorldPaused(pause));
+    }
+}
+
+/// Share of the expected inputs that never arrived, over all the clients
+pub const MISSED_INPUTS: DiagnosticPath = DiagnosticPath::const_new("missed_inputs");
+
+/// How many inputs were received from a client, and how many ticks had none
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct InputCounters {
+    pub(crate) received: u64,
+    pub(crate) missed: u64,
+}
+
+/// Input counters of each connected client, to see how much input gets lost
+#[derive(Resource, Default, Debug)]
+pub(crate) struct InputStats {
+    clients: HashMap<ClientId, InputCounters>,
+    /// Totals since the last measurement of [`MISSED_INPUTS`]
+    pending: InputCounters,
+}
+
+impl InputStats {
+    pub(crate) fn receive(&mut self, client_id: ClientId) {
+        self.clients.entry(client_id).or_default().received += 1;
+        self.pending.received += 1;
+    }
+
+    /// Count a tick without input from the client, returns how many it missed so far
+    pub(crate) fn miss(&mut self, client_id: ClientId) -> u64 {
+        self.pending.missed += 1;
+        let counters = self.clients.entry(client_id).or_default();
+        counters.missed += 1;
+        counters.missed
+    }
+
+    pub(crate) fn get(&self, client_id: ClientId) -> InputCounters {
+        self.clients.get(&client_id).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn forget(&mut self, client_id: ClientId) {
+        self.clients.remove(&client_id);
+    }
+}
+
+/// How often each client is told how many of its inputs were missed
+const INPUT_LOSS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tell each client the share of its inputs missed since the previous report
+pub(crate) fn report_input_loss(
+    time: Res<Time<Real>>,
+    input_stats: Res<InputStats>,
+    connected_players: Res<ConnectedPlayers>,
+    mut server: CountedServer,
+    mut timer: Local<Option<Timer>>,
+    mut reported: Local<HashMap<ClientId, InputCounters>>,
+) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(INPUT_LOSS_REPORT_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    reported.retain(|client_id, _| connected_players.contains(*client_id));
+    for client_id in connected_players.client_ids() {
+        let now = input_stats.get(*client_id);
+        let before = reported.insert(*client_id, now).unwrap_or_default();
+        let received = now.received.saturating_sub(before.received);
+        let missed = now.missed.saturating_sub(before.missed);
+        if received + missed == 0 {
+            continue;
+        }
+        let loss = missed as f32 / (received + missed) as f32;
+        server
+            .send_message_to_target::<TickChannel, InputLoss>(
+                InputLoss(loss),
+                NetworkTarget::Only(vec![*client_id]),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to send the input loss: {:?}", e);
+            });
+    }
+}
+
+pub(crate) fn measure_missed_inputs(
+    mut input_stats: ResMut<InputStats>,
+    mut diagnostics: Diagnostics,
+) {
+    let InputCounters { received, missed } = std::mem::take(&mut input_stats.pending);
+    if received + missed == 0 {
+        return;
+    }
+    diagnostics.add_measurement(&MISSED_INPUTS, || {
+        missed as f64 / (received + missed) as f64 * 100.0
+    });
+}
+
+/// Knock a player back, starting at `impulse` pixels per tick and slowing down every tick.
+/// Replaces any knockback the player was still under.
+pub(crate) fn apply_knockback(commands: &mut Commands, entity: Entity, impulse: Vec2) {
+    commands.entity(entity).insert(Knockback(impulse));
+}
+
+/// Push the players that got knocked back, the knockback is removed once it has settled
+pub(crate) fn knockback(
+    mut commands: Commands,
+    mut players: Query<(Entity, &mut PlayerPosition, &mut Knockback)>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+) {
+    for (entity, mut position, mut knockback) in players.iter_mut() {
+        let settled =
+            shared_knockback_behaviour(&mut position, &mut knockback, &level, &shared_settings);
+        if settled {
+            commands.entity(entity).remove::<Knockback>();
+        }
+    }
+}
+
+/// When each client last sent an input that wasn't a no-op, in real time since startup
+#[derive(Resource, Default, Debug)]
+pub(crate) struct LastActivity(HashMap<ClientId, Duration>);
+
+impl LastActivity {
+    /// Clients count as active from their first input, even a no-op one
+    pub(crate) fn record(&mut self, client_id: ClientId, active: bool, now: Duration) {
+        let last = self.0.entry(client_id).or_insert(now);
+        if active {
+            *last = now;
+        }
+    }
+
+    /// Count every client as active right now
+    pub(crate) fn refresh_all(&mut self, now: Duration) {
+        for last in self.0.values_mut() {
+            *last = now;
+        }
+    }
+
+    /// The clients that have been idle for longer than `timeout`
+    pub(crate) fn idle(&self, now: Duration, timeout: Duration) -> Vec<ClientId> {
+        self.0
+            .iter()
+            .filter(|(_, last)| now.saturating_sub(**last) > timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    }
+}
+
+fn inactivity_timeout_set(settings: Res<ServerSettings>) -> bool {
+    settings.inactivity_timeout_secs.is_some()
+}
+
+/// Disconnect the clients that haven't played for a while, their players are then removed
+/// by [`handle_disconnections`] like for any other disconnection
+pub(crate) fn disconnect_idle_clients(
+    time: Res<Time<Real>>,
+    settings: Res<ServerSettings>,
+    paused: Res<WorldPaused>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut last_activity: ResMut<LastActivity>,
+) {
+    let Some(timeout) = settings.inactivity_timeout_secs else {
+        return;
+    };
+    if paused.0 {
+        // nobody can move while the host has the game paused, the idle time starts over on resume
+        last_activity.refresh_all(time.elapsed());
+        return;
+    }
+    for client_id in last_activity.idle(time.elapsed(), Duration::from_secs(timeout)) {
+        // only try once, the entry is gone for good when the disconnection goes through
+        last_activity.0.remove(&client_id);
+        pending_disconnects.schedule(client_id, DisconnectReason::Inactive(timeout));
+    }
+}
+
+/// How long a client has to receive its [`DisconnectReason`] before it gets disconnected
+const DISCONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Clients told why they are being disconnected, disconnected once the reason had time to reach them
+#[derive(Resource, Default, Debug)]
+pub(crate) struct PendingDisconnects {
+    /// Reasons not sent yet
+    reasons: Vec<(ClientId, DisconnectReason)>,
+    timers: HashMap<ClientId, Timer>,
+}
+
+impl PendingDisconnects {
+    /// Tell a client why it gets disconnected, and disconnect it a moment later
+    pub(crate) fn schedule(&mut self, client_id: ClientId, reason: DisconnectReason) {
+        info!("Disconnecting client {}: {}", client_id, reason);
+        self.reasons.push((client_id, reason));
+        self.timers
+            .entry(client_id)
+            .or_insert_with(|| Timer::new(DISCONNECT_DELAY, TimerMode::Once));
+    }
+
+    fn cancel(&mut self, client_id: ClientId) {
+        self.reasons.retain(|(id, _)| *id != client_id);
+        self.timers.remove(&client_id);
+    }
+}
+
+pub(crate) fn finish_disconnects(
+    time: Res<Time<Real>>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut connection_manager: CountedServer,
+    mut server: ResMut<ServerConnections>,
+) {
+    for (client_id, reason) in pending_disconnects.reasons.drain(..) {
+        connection_manager
+            .send_message_to_target::<Channel1, DisconnectReason>(
+                reason,
+                NetworkTarget::Only(vec![client_id]),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to send disconnect reason: {:?}", e);
+            });
+    }
+    pending_disconnects.timers.retain(|client_id, timer| {
+        if !timer.tick(time.delta()).finished() {
+            return true;
+        }
+        if let Err(e) = server.disconnect(*client_id) {
+            error!("Failed to disconnect client {}: {:?}", client_id, e);
+        }
+        false
+    });
+}
+
+fn interest_radius_set(settings: Res<ServerSettings>) -> bool {
+    settings.interest_radius.is_some()
+}
+
+/// The view radius each client asked for, already capped to the interest radius
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ViewRadii(HashMap<ClientId, f32>);
+
+pub(crate) fn receive_view_radii(
+    mut requests: EventReader<MessageEvent<ViewRadius>>,
+    settings: Res<ServerSettings>,
+    rejected: Res<RejectedConnections>,
+    mut view_radii: ResMut<ViewRadii>,
+) {
+    let Some(max_radius) = settings.interest_radius else {
+        return;
+    };
+    for request in requests.read() {
+        let client_id = *request.context();
+        // the id belongs to a bot
+        if rejected.0.contains(&client_id) {
+            continue;
+        }
+        let ViewRadius(radius) = *request.message();
+        if radius.is_nan() || radius <= 0.0 {
+            warn!(
+                "Client {} asked for an invalid view radius {}",
+                client_id, radius
+            );
+            continue;
+        }
+        view_radii.0.insert(client_id, radius.min(max_radius));
+    }
+}
+
+/// Only replicate each player to its owner and to the clients with a player close enough to it.
+/// Clients get the players entering their area spawned, and the ones leaving it despawned.
+/// A client without any player doesn't see anyone.
+pub(crate) fn update_areas_of_interest(
+    settings: Res<ServerSettings>,
+    view_radii: Res<ViewRadii>,
+    mut players: Query<(&PlayerId, &PlayerPosition, &mut Replicate)>,
+) {
+    let Some(radius) = settings.interest_radius else {
+        return;
+    };
+    // bots don't look at anything
+    let viewers: Vec<(ClientId, Vec2, f32)> = players
+        .iter()
+        .filter(|(player_id, _, _)| !is_bot(player_id.0))
+        .map(|(player_id, position, _)| {
+            let radius = view_radii.0.get(&player_id.0).copied().unwrap_or(radius);
+            (player_id.0, position.0, radius)
+        })
+        .collect();
+    for (player_id, position, mut replicate) in players.iter_mut() {
+        let mut clients: Vec<ClientId> = viewers
+            .iter()
+            .filter(|(client_id, viewer, radius)| {
+                *client_id == player_id.0 || viewer.distance(position.0) <= *radius
+            })
+            .map(|(client_id, _, _)| *client_id)
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        let target = NetworkTarget::Only(clients);
+        // only touch the component when the area changed, every change gets sent to the clients
+        if replicate.replication_target != target {
+            replicate.replication_target = target;
+        }
+    }
+}
+
+/// The up-to-date value of a replicated component that is only sent every `slow_interval_ms`.
+/// Server systems change this one, the replicated component is updated from it by [`flush_throttled`].
+#[derive(Component, Debug)]
+pub(crate) struct Throttled<C>(pub C);
+
+/// Copy the throttled values to the replicated components, which gets them sent to the clients
+pub(crate) fn flush_throttled<C: Component + Clone + PartialEq>(
+    time: Res<Time>,
+    shared_settings: Res<SharedSettings>,
+    mut timer: Local<Timer>,
+    mut components: Query<(&Throttled<C>, &mut C)>,
+) {
+    let interval = Duration::from_millis(shared_settings.replication.slow_interval_ms);
+    if timer.duration() != interval {
+        *timer = Timer::new(interval, TimerMode::Repeating);
+    }
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (throttled, mut component) in components.iter_mut() {
+        // writing the same value would still count as a change and get sent
+        component.set_if_neq(throttled.0.clone());
+    }
+}
+
+/// Damage dealt by a hazard cell every [`HAZARD_COOLDOWN`]
+const HAZARD_DAMAGE: u32 = 10;
+
+/// Minimum time between two damages from hazards, so that standing on one doesn't drain all health at once
+const HAZARD_COOLDOWN: Duration = Duration::from_millis(500);
+
+/// Speed (in pixels per tick) at which a hazard throws back the players it hurts
+const HAZARD_KNOCKBACK: f32 = 6.0;
+
+/// Time left before a player can get hurt by a hazard again
+#[derive(Component, Deref, DerefMut)]
+pub(crate) struct HazardCooldown(Timer);
+
+/// Hurt the players standing on hazard cells and throw them back off the hazards,
+/// sending the ones that run out of health back to the spawn point
+pub(crate) fn hazard_damage(
+    mut commands: Commands,
+    time: Res<Time>,
+    level: Res<LevelGrid>,
+    mut pending_moves: ResMut<PendingMoves>,
+    mut players: Query<(
+        Entity,
+        &PlayerId,
+        &PlayerSlot,
+        &mut PlayerPosition,
+        Option<&mut PlayerVelocity>,
+        &mut Throttled<PlayerHealth>,
+        Option<&mut HazardCooldown>,
+    )>,
+) {
+    for (entity, player_id, slot, mut position, velocity, mut health, cooldown) in
+        players.iter_mut()
+    {
+        if let Some(mut cooldown) = cooldown {
+            if !cooldown.tick(time.delta()).finished() {
+                continue;
+            }
+        }
+        let hazards: Vec<Vec2> = level
+            .overlapped_cells(position.0, PLAYER_HITBOX, Cell::Hazard)
+            .collect();
+        if hazards.is_empty() {
+            continue;
+        }
+        if health.0.damage(HAZARD_DAMAGE) {
+            info!("A player of client {} died on a hazard", player_id.0);
+            // the spawn point is corrected like the spawns sent by the clients
+            match nearest_free_position(&level, Vec2::ZERO) {
+                Some(spawn) => position.0 = spawn,
+                None => warn!("There is no room left in the level to respawn the player"),
+            }
+            health.0 = PlayerHealth::default();
+            // nothing the player was doing carries over to the new life
+            if let Some(mut velocity) = velocity {
+                velocity.0 = Vec2::ZERO;
+            }
+            pending_moves.cancel_moves(player_id.0, slot.0);
+            commands
+                .entity(entity)
+                .remove::<(Knockback, HazardCooldown)>();
+            continue;
+        }
+        commands
+            .entity(entity)
+            .insert(HazardCooldown(Timer::new(HAZARD_COOLDOWN, TimerMode::Once)));
+        // away from the hazards it stands on, a player right in the middle of them isn't pushed
+        let center = hazards.iter().sum::<Vec2>() / hazards.len() as f32;
+        let away = (position.0 - center).normalize_or_zero();
+        if away != Vec2::ZERO {
+            apply_knockback(&mut commands, entity, away * HAZARD_KNOCKBACK);
+        }
+    }
+}
+
+/// Push overlapping players apart, each one moving by half of the overlap
+pub(crate) fn separate_players(
+    mut players: Query<(Entity, &mut PlayerPosition)>,
+    level: Res<LevelGrid>,
+    shared_settings: Res<SharedSettings>,
+) {
+    let positions: Vec<(Entity, Vec2)> = players
+        .iter()
+        .map(|(entity, position)| (entity, position.0))
+        .collect();
+    let mut pushes: HashMap<Entity, Vec2> = HashMap::default();
+    for (i, (a, a_position)) in positions.iter().enumerate() {
+        for (b, b_position) in &positions[i + 1..] {
+            let push = push_out(*a_position, *b_position) / 2.0;
+            if push != Vec2::ZERO {
+                *pushes.entry(*a).or_default() += push;
+                *pushes.entry(*b).or_default() -= push;
+            }
+        }
+    }
+    for (entity, push) in pushes {
+        if let Ok((_, mut position)) = players.get_mut(entity) {
+            shared_push_behaviour(&mut position, push, &level, &shared_settings);
         }
     }
 }
@@ -157,14 +1345,55 @@ pub(crate) fn movement(
 // Replicate the pre-spawned entities back to the client
 // Note that this needs to run before FixedUpdate, since we handle client inputs in the FixedUpdate schedule (subject to change)
 // And we want to handle deletion properly
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn replicate_players(
     mut commands: Commands,
+    mut color_assignments: ResMut<ColorAssignments>,
+    mut reclaimable: ResMut<ReclaimablePlayers>,
+    mut spawn_guard: ResMut<SpawnGuard>,
+    time: Res<Time<Real>>,
+    level: Res<LevelGrid>,
+    players: Query<(&PlayerId, &PlayerSlot), With<Replicate>>,
+    spawns: Query<(Option<&PlayerSlot>, &PlayerPosition)>,
     mut player_spawn_reader: EventReader<ComponentInsertEvent<PlayerPosition>>,
 ) {
+    let mut spawned = HashSet::new();
     for event in player_spawn_reader.read() {
         debug!("received player spawn event: {:?}", event);
         let client_id = event.context();
         let entity = event.entity();
+        let Ok((slot, position)) = spawns.get(entity) else {
+            continue;
+        };
+        let slot = slot.copied().unwrap_or_default();
+
+        // a client only ever gets one player per local player, and can't spawn/delete it in quick succession
+        let already_spawned = spawned.contains(&(*client_id, slot))
+            || players
+                .iter()
+                .any(|(player, player_slot)| player.0 == *client_id && *player_slot == slot);
+        if slot.0 >= MAX_LOCAL_PLAYERS
+            || already_spawned
+            || !spawn_guard.allow(*client_id, slot, time.elapsed())
+        {
+            let ignored = spawn_guard.ignore(*client_id);
+            warn!(
+                "Ignoring player spawn in slot {} from client {} ({} ignored so far)",
+                slot.0, client_id, ignored
+            );
+            commands.entity(entity).despawn();
+            continue;
+        }
+        // the client picked where its player spawns, which may be off the map or in a wall
+        let Some(corrected) = nearest_free_position(&level, position.0) else {
+            warn!(
+                "Ignoring player spawn from client {}, there is no room left in the level",
+                client_id
+            );
+            commands.entity(entity).despawn();
+            continue;
+        };
+        spawned.insert((*client_id, slot));
 
         // for all cursors we have received, add a Replicate component so that we can start replicating it
         // to other clients
@@ -176,10 +1405,644 @@ pub(crate) fn replicate_players(
                 // NOTE: Be careful to not override the pre-spawned prediction! we do not need to enable prediction
                 //  because there is a pre-spawned predicted entity
                 prediction_target: NetworkTarget::Only(vec![*client_id]),
-                // we want the other clients to apply interpolation for the player
-                interpolation_target: NetworkTarget::AllExcept(vec![*client_id]),
+                // we want the other clients to apply interpolation for the player, the owner
+                // only draws its interpolated copy with `ClientSettings::show_confirmed_player`
+                interpolation_target: NetworkTarget::All,
                 ..default()
             });
+            e.insert(PlayerColor(color_assignments.assign(*client_id, slot)));
+            e.insert((PlayerHealth::default(), Throttled(PlayerHealth::default())));
+            // players start standing still, whatever the client says
+            e.insert(PlayerVelocity::default());
+            if corrected != position.0 {
+                warn!(
+                    "Moved the player spawned by client {} from {} to {}, where it fits in the level",
+                    client_id, position.0, corrected
+                );
+                // the client's prediction rolls back to the corrected position
+                e.insert(PlayerPosition(corrected));
+            }
+            if slot != PlayerSlot(0) {
+                continue;
+            }
+            if let Some(position) = reclaimable.take_pending(*client_id) {
+                // the client reconnected, put the player back where it was
+                e.insert(PlayerPosition(position));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::Settings;
+
+    fn connection_tracking_app() -> App {
+        let mut app = App::new();
+        app.add_event::<ConnectEvent>();
+        app.add_event::<DisconnectEvent>();
+        app.init_resource::<ConnectedPlayers>();
+        app.add_systems(Update, track_connected_players);
+        app
+    }
+
+    #[test]
+    fn reconnecting_in_the_same_frame_stays_connected() {
+        let mut app = connection_tracking_app();
+        app.world.send_event(ConnectEvent::new(1));
+        app.update();
+
+        // the old connection drops and the new one comes in with the same id
+        app.world.send_event(ConnectEvent::new(1));
+        app.world.send_event(DisconnectEvent::new(1));
+        app.update();
+
+        let connected_players = app.world.resource::<ConnectedPlayers>();
+        assert_eq!(connected_players.client_ids(), &[1]);
+    }
+
+    fn connections_app() -> App {
+        let mut app = connection_tracking_app();
+        let mut settings = Settings::embedded().server;
+        settings.on_disconnect = DisconnectBehavior::Despawn;
+        app.insert_resource(settings);
+        app.insert_resource(ReconnectTokens::with_random_key(Duration::from_secs(60)));
+        app.init_resource::<Time<Real>>();
+        app.init_resource::<PendingDisconnects>();
+        app.init_resource::<RejectedConnections>();
+        app.init_resource::<ReclaimablePlayers>();
+        app.init_resource::<Ghosts>();
+        app.init_resource::<ColorAssignments>();
+        app.init_resource::<SpawnGuard>();
+        app.init_resource::<LastActivity>();
+        app.init_resource::<InputStats>();
+        app.init_resource::<PendingMoves>();
+        app.init_resource::<ViewRadii>();
+        app.add_systems(
+            Update,
+            (handle_disconnections, handle_connections)
+                .chain()
+                .after(track_connected_players),
+        );
+        app
+    }
+
+    fn spawn_player(app: &mut App, client_id: ClientId) -> Entity {
+        app.world
+            .spawn((
+                PlayerId(client_id),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::new(10.0, 20.0)),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn reconnecting_in_the_same_frame_replaces_the_player() {
+        let mut app = connections_app();
+        app.world.send_event(ConnectEvent::new(1));
+        app.update();
+        let player = spawn_player(&mut app, 1);
+
+        app.world.send_event(ConnectEvent::new(1));
+        app.world.send_event(DisconnectEvent::new(1));
+        app.update();
+
+        assert!(app.world.resource::<PendingDisconnects>().timers.is_empty());
+        assert!(app.world.get_entity(player).is_none());
+        assert!(app.world.resource::<ConnectedPlayers>().contains(1));
+        // the new connection can still take its position back
+        let reclaimed = app.world.resource_mut::<ReclaimablePlayers>().reclaim(
+            1,
+            unix_now(),
+            Duration::from_secs(60),
+        );
+        assert_eq!(reclaimed, Some(Vec2::new(10.0, 20.0)));
+    }
+
+    fn idle_clients_app(paused: bool) -> App {
+        let mut app = App::new();
+        let mut settings = Settings::embedded().server;
+        settings.inactivity_timeout_secs = Some(60);
+        app.insert_resource(settings);
+        app.insert_resource(WorldPaused(paused));
+        app.init_resource::<Time<Real>>();
+        app.init_resource::<PendingDisconnects>();
+        app.init_resource::<LastActivity>();
+        app.add_systems(Update, disconnect_idle_clients);
+        app.world
+            .resource_mut::<LastActivity>()
+            .record(1, true, Duration::ZERO);
+        app.world
+            .resource_mut::<Time<Real>>()
+            .advance_by(Duration::from_secs(90));
+        app
+    }
+
+    #[test]
+    fn idle_host_is_kicked_while_playing() {
+        let mut app = idle_clients_app(false);
+        app.update();
+        assert!(app
+            .world
+            .resource::<PendingDisconnects>()
+            .timers
+            .contains_key(&1));
+    }
+
+    #[test]
+    fn host_is_not_kicked_while_the_game_is_paused() {
+        let mut app = idle_clients_app(true);
+        app.update();
+        assert!(app.world.resource::<PendingDisconnects>().timers.is_empty());
+
+        // resuming doesn't kick right away either
+        app.insert_resource(WorldPaused(false));
+        app.world
+            .resource_mut::<Time<Real>>()
+            .advance_by(Duration::from_secs(30));
+        app.update();
+        assert!(app.world.resource::<PendingDisconnects>().timers.is_empty());
+    }
+
+    #[test]
+    fn connecting_and_dropping_in_the_same_frame_is_not_connected() {
+        let mut app = connection_tracking_app();
+        app.world.send_event(ConnectEvent::new(1));
+        app.world.send_event(DisconnectEvent::new(1));
+        app.update();
+
+        assert_eq!(app.world.resource::<ConnectedPlayers>().count(), 0);
+    }
+
+    #[test]
+    fn count_follows_connections_and_disconnections() {
+        let mut app = connection_tracking_app();
+        for client_id in [1, 2, 3] {
+            app.world.send_event(ConnectEvent::new(client_id));
+        }
+        app.update();
+        assert_eq!(app.world.resource::<ConnectedPlayers>().count(), 3);
+
+        app.world.send_event(DisconnectEvent::new(2));
+        // dropped during the handshake, before its connection was seen
+        app.world.send_event(DisconnectEvent::new(4));
+        app.update();
+        let connected_players = app.world.resource::<ConnectedPlayers>();
+        assert_eq!(connected_players.count(), 2);
+        assert_eq!(connected_players.client_ids(), &[1, 3]);
+
+        app.world.send_event(DisconnectEvent::new(1));
+        app.world.send_event(DisconnectEvent::new(3));
+        app.update();
+        assert_eq!(app.world.resource::<ConnectedPlayers>().count(), 0);
+    }
+
+    fn walk_right() -> Inputs {
+        Inputs::Direction(Direction {
+            up: false,
+            down: false,
+            left: false,
+            right: true,
+        })
+    }
+
+    #[test]
+    fn inputs_arriving_together_are_spread_over_ticks() {
+        let mut pending_moves = PendingMoves::default();
+        // three inputs from a lagging client arrive in the same tick
+        for _ in 0..3 {
+            pending_moves.push(1, walk_right());
+        }
+        pending_moves.push(2, Inputs::None);
+
+        let first_tick = pending_moves.take(1);
+        assert_eq!(
+            first_tick
+                .iter()
+                .filter(|(client_id, _)| *client_id == 1)
+                .count(),
+            1
+        );
+        assert!(first_tick.contains(&(2, Inputs::None)));
+        assert_eq!(pending_moves.take(1), vec![(1, walk_right())]);
+        assert_eq!(pending_moves.take(1), vec![(1, walk_right())]);
+        assert!(pending_moves.take(1).is_empty());
+    }
+
+    #[test]
+    fn stopping_cancels_the_queued_moves() {
+        let mut pending_moves = PendingMoves::default();
+        pending_moves.push(1, walk_right());
+        pending_moves.push(1, walk_right());
+        pending_moves.cancel_moves(1, 0);
+        assert_eq!(pending_moves.take(usize::MAX), vec![(1, Inputs::None); 2]);
+    }
+
+    fn spawns_app() -> App {
+        let mut app = App::new();
+        app.insert_resource(LevelGrid::from_rows(&["....", "....", "....", "...."]));
+        app.init_resource::<Time<Real>>();
+        app.init_resource::<ColorAssignments>();
+        app.init_resource::<ReclaimablePlayers>();
+        app.init_resource::<SpawnGuard>();
+        app.add_event::<ComponentInsertEvent<PlayerPosition>>();
+        app.add_systems(Update, replicate_players);
+        app
+    }
+
+    /// A player pre-spawned by client 1, as replicated to the server
+    fn request_spawn(app: &mut App) {
+        let entity = spawn_player(app, 1);
+        app.world
+            .send_event(ComponentInsertEvent::<PlayerPosition>::new(entity, 1));
+    }
+
+    #[test]
+    fn repeated_spawns_yield_one_player() {
+        let mut app = spawns_app();
+        for _ in 0..3 {
+            request_spawn(&mut app);
         }
+        app.update();
+        // a later spawn while the player exists is refused too
+        request_spawn(&mut app);
+        app.update();
+
+        let players = app
+            .world
+            .query::<&PlayerPosition>()
+            .iter(&app.world)
+            .count();
+        assert_eq!(players, 1);
+        let replicated = app
+            .world
+            .query_filtered::<&PlayerId, With<Replicate>>()
+            .iter(&app.world)
+            .count();
+        assert_eq!(replicated, 1);
+    }
+
+    /// Pixels a test player walks along x every update
+    #[derive(Component)]
+    struct Walking(f32);
+
+    fn walk(mut players: Query<(&mut PlayerPosition, &Walking)>) {
+        for (mut position, walking) in players.iter_mut() {
+            position.0.x += walking.0;
+        }
+    }
+
+    #[test]
+    fn players_walking_into_each_other_stop_side_by_side() {
+        let mut app = App::new();
+        let mut settings = Settings::embedded().shared;
+        settings.fixed_point_movement = false;
+        app.insert_resource(settings);
+        app.insert_resource(LevelGrid::from_rows(&[".........."; 10]));
+        app.add_systems(Update, (walk, separate_players).chain());
+        let left = app
+            .world
+            .spawn((PlayerPosition(Vec2::new(100.0, 160.0)), Walking(10.0)))
+            .id();
+        let right = app
+            .world
+            .spawn((PlayerPosition(Vec2::new(220.0, 160.0)), Walking(-10.0)))
+            .id();
+
+        // long enough for them to walk through each other otherwise
+        for _ in 0..20 {
+            app.update();
+        }
+        let left = app.world.get::<PlayerPosition>(left).unwrap().0;
+        let right = app.world.get::<PlayerPosition>(right).unwrap().0;
+        assert!(left.x < right.x, "{} walked through {}", left, right);
+        assert!(right.x - left.x >= PLAYER_HITBOX.x - 0.001);
+        // pushed back evenly, and only along the way they walk
+        assert_eq!(left.x + right.x, 320.0);
+        assert_eq!((left.y, right.y), (160.0, 160.0));
+    }
+
+    fn hazards_app(rows: &[&str]) -> App {
+        let mut app = App::new();
+        app.init_resource::<Time>();
+        app.init_resource::<PendingMoves>();
+        app.insert_resource(LevelGrid::from_rows(rows));
+        app.add_systems(Update, hazard_damage);
+        app
+    }
+
+    #[test]
+    fn hazards_damage_at_their_rate() {
+        let mut app = hazards_app(&["^^^^^"; 5]);
+        // in the middle of a hazard cell, where nothing pushes the player off
+        let player = app
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::splat(80.0)),
+                Throttled(PlayerHealth::default()),
+            ))
+            .id();
+
+        // two seconds in steps of 100ms
+        for _ in 0..20 {
+            app.world
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_millis(100));
+            app.update();
+        }
+        let health = app.world.get::<Throttled<PlayerHealth>>(player).unwrap();
+        let hits = (2000 / HAZARD_COOLDOWN.as_millis()) as u32;
+        assert_eq!(
+            health.0,
+            PlayerHealth(PlayerHealth::MAX - hits * HAZARD_DAMAGE)
+        );
+        assert!(app.world.get::<Knockback>(player).is_none());
+    }
+
+    #[test]
+    fn players_dying_on_hazards_respawn_at_rest() {
+        let mut app = hazards_app(&["...^^"; 5]);
+        let player = app
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::new(144.0, 80.0)),
+                PlayerVelocity(Vec2::new(4.0, 0.0)),
+                Throttled(PlayerHealth(HAZARD_DAMAGE)),
+                HazardCooldown(Timer::new(Duration::ZERO, TimerMode::Once)),
+            ))
+            .id();
+        for _ in 0..3 {
+            app.world
+                .resource_mut::<PendingMoves>()
+                .push(1, walk_right());
+        }
+        app.update();
+
+        // the spawn point is the corner of this level, half out of it
+        let spawn = nearest_free_position(app.world.resource::<LevelGrid>(), Vec2::ZERO).unwrap();
+        assert_ne!(spawn, Vec2::ZERO);
+        let player = app.world.entity(player);
+        assert_eq!(player.get::<PlayerPosition>().unwrap().0, spawn);
+        assert_eq!(player.get::<PlayerVelocity>().unwrap().0, Vec2::ZERO);
+        assert_eq!(
+            player.get::<Throttled<PlayerHealth>>().unwrap().0,
+            PlayerHealth::default()
+        );
+        assert!(!player.contains::<HazardCooldown>());
+        assert!(!player.contains::<Knockback>());
+        let moves = app.world.resource_mut::<PendingMoves>().take(usize::MAX);
+        assert!(moves.iter().all(|(_, input)| *input == Inputs::None));
+    }
+
+    fn interest_app() -> App {
+        let mut app = App::new();
+        let mut settings = Settings::embedded().server;
+        settings.interest_radius = Some(200.0);
+        app.insert_resource(settings);
+        app.init_resource::<ViewRadii>();
+        app.add_systems(Update, update_areas_of_interest);
+        app
+    }
+
+    fn spawn_replicated(app: &mut App, client_id: ClientId, position: Vec2) -> Entity {
+        app.world
+            .spawn((
+                PlayerId(client_id),
+                PlayerPosition(position),
+                Replicate::default(),
+            ))
+            .id()
+    }
+
+    fn replication_target(app: &App, entity: Entity) -> NetworkTarget {
+        app.world
+            .get::<Replicate>(entity)
+            .unwrap()
+            .replication_target
+            .clone()
+    }
+
+    #[test]
+    fn far_players_are_not_replicated() {
+        let mut app = interest_app();
+        let near = spawn_replicated(&mut app, 1, Vec2::ZERO);
+        let close = spawn_replicated(&mut app, 2, Vec2::new(100.0, 0.0));
+        let far = spawn_replicated(&mut app, 3, Vec2::new(1000.0, 0.0));
+        app.update();
+
+        assert_eq!(
+            replication_target(&app, near),
+            NetworkTarget::Only(vec![1, 2])
+        );
+        assert_eq!(
+            replication_target(&app, close),
+            NetworkTarget::Only(vec![1, 2])
+        );
+        // only its owner sees it
+        assert_eq!(replication_target(&app, far), NetworkTarget::Only(vec![3]));
+
+        // walking up to the others brings it into their area
+        app.world.get_mut::<PlayerPosition>(far).unwrap().0 = Vec2::new(150.0, 0.0);
+        app.update();
+        assert_eq!(
+            replication_target(&app, far),
+            NetworkTarget::Only(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn small_view_radius_sees_fewer_players() {
+        let mut app = interest_app();
+        let own = spawn_replicated(&mut app, 1, Vec2::ZERO);
+        let near = spawn_replicated(&mut app, 2, Vec2::new(40.0, 0.0));
+        let further = spawn_replicated(&mut app, 3, Vec2::new(150.0, 0.0));
+        // a weak device asked to only see what is right around it
+        app.world.resource_mut::<ViewRadii>().0.insert(1, 50.0);
+        app.update();
+
+        let seen_by = |app: &App, client_id: ClientId| {
+            [own, near, further]
+                .into_iter()
+                .filter(|entity| replication_target(app, *entity).should_send_to(&client_id))
+                .count()
+        };
+        assert_eq!(seen_by(&app, 1), 2);
+        assert_eq!(seen_by(&app, 2), 3);
+    }
+
+    #[test]
+    fn spawns_out_of_the_level_are_corrected() {
+        let mut app = spawns_app();
+        let entity = app
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::new(-500.0, 60.0)),
+            ))
+            .id();
+        app.world
+            .send_event(ComponentInsertEvent::<PlayerPosition>::new(entity, 1));
+        app.update();
+
+        assert!(app.world.get::<Replicate>(entity).is_some());
+        assert_eq!(
+            app.world.get::<PlayerPosition>(entity),
+            Some(&PlayerPosition(Vec2::new(0.0, 60.0)))
+        );
+    }
+
+    #[test]
+    fn only_idle_clients_time_out() {
+        let timeout = Duration::from_secs(60);
+        let mut last_activity = LastActivity::default();
+        // client 1 only ever sends no-ops, client 2 keeps playing
+        for second in 0..=90 {
+            let now = Duration::from_secs(second);
+            last_activity.record(1, false, now);
+            last_activity.record(2, true, now);
+        }
+        assert!(last_activity
+            .idle(Duration::from_secs(60), timeout)
+            .is_empty());
+        assert_eq!(
+            last_activity.idle(Duration::from_secs(90), timeout),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn active_client_is_not_kicked() {
+        let mut app = idle_clients_app(false);
+        app.world
+            .resource_mut::<LastActivity>()
+            .record(2, true, Duration::from_secs(85));
+        app.update();
+        let pending_disconnects = app.world.resource::<PendingDisconnects>();
+        assert!(pending_disconnects.timers.contains_key(&1));
+        assert!(!pending_disconnects.timers.contains_key(&2));
+    }
+
+    #[test]
+    fn missed_inputs_are_counted_per_client() {
+        let mut app = App::new();
+        app.init_resource::<bevy::diagnostic::DiagnosticsStore>();
+        app.register_diagnostic(Diagnostic::new(MISSED_INPUTS));
+        app.init_resource::<InputStats>();
+        app.add_systems(Update, measure_missed_inputs);
+
+        let mut input_stats = app.world.resource_mut::<InputStats>();
+        for _ in 0..10 {
+            input_stats.receive(1);
+        }
+        for _ in 0..9 {
+            input_stats.receive(2);
+        }
+        assert_eq!(input_stats.miss(2), 1);
+        assert_eq!(
+            input_stats.get(1),
+            InputCounters {
+                received: 10,
+                missed: 0
+            }
+        );
+        assert_eq!(
+            input_stats.get(2),
+            InputCounters {
+                received: 9,
+                missed: 1
+            }
+        );
+        app.update();
+
+        // one input missed out of the twenty expected from both clients
+        let diagnostics = app.world.resource::<bevy::diagnostic::DiagnosticsStore>();
+        let missed = diagnostics.get(&MISSED_INPUTS).and_then(|d| d.value());
+        assert!(missed.is_some_and(|missed| (missed - 5.0).abs() < 1e-9));
+        // the per-client counters keep counting, only the totals start over
+        let input_stats = app.world.resource::<InputStats>();
+        assert_eq!(input_stats.get(2).missed, 1);
+        assert_eq!(input_stats.pending, InputCounters::default());
+    }
+
+    #[test]
+    fn despawn_removes_players_right_away() {
+        let mut app = connections_app();
+        app.world.send_event(ConnectEvent::new(1));
+        app.update();
+        let player = spawn_player(&mut app, 1);
+
+        app.world.send_event(DisconnectEvent::new(1));
+        app.update();
+        assert!(app.world.get_entity(player).is_none());
+        assert!(app.world.resource::<Ghosts>().0.is_empty());
+    }
+
+    #[test]
+    fn ghosts_linger_then_go_away() {
+        let mut app = connections_app();
+        app.world.resource_mut::<ServerSettings>().on_disconnect =
+            DisconnectBehavior::Ghost { linger_secs: 5 };
+        app.add_systems(Update, despawn_ghosts.after(handle_disconnections));
+        app.world.send_event(ConnectEvent::new(1));
+        app.update();
+        let player = spawn_player(&mut app, 1);
+
+        app.world.send_event(DisconnectEvent::new(1));
+        app.update();
+        assert!(app.world.get::<Ghost>(player).is_some());
+        assert_eq!(
+            app.world.get::<PlayerPosition>(player),
+            Some(&PlayerPosition(Vec2::new(10.0, 20.0)))
+        );
+
+        app.world
+            .resource_mut::<Time<Real>>()
+            .advance_by(Duration::from_secs(4));
+        app.update();
+        assert!(app.world.get_entity(player).is_some());
+
+        app.world
+            .resource_mut::<Time<Real>>()
+            .advance_by(Duration::from_secs(2));
+        app.update();
+        assert!(app.world.get_entity(player).is_none());
+    }
+
+    #[test]
+    fn double_spawn_collapses_to_one_player() {
+        let mut app = App::new();
+        app.add_systems(Update, enforce_single_player);
+        // spawned by the server, and again while the client reconnected
+        let replicated = app
+            .world
+            .spawn((
+                PlayerId(1),
+                PlayerSlot(0),
+                PlayerPosition(Vec2::ZERO),
+                Replicate::default(),
+            ))
+            .id();
+        let extra = spawn_player(&mut app, 1);
+        // other slots and clients are not duplicates
+        let second_slot = app
+            .world
+            .spawn((PlayerId(1), PlayerSlot(1), PlayerPosition(Vec2::ZERO)))
+            .id();
+        let other_client = spawn_player(&mut app, 2);
+        app.update();
+
+        assert!(app.world.get_entity(replicated).is_some());
+        assert!(app.world.get_entity(extra).is_none());
+        assert!(app.world.get_entity(second_slot).is_some());
+        assert!(app.world.get_entity(other_client).is_some());
+        let mut players = app.world.query::<&PlayerId>();
+        assert_eq!(players.iter(&app.world).filter(|id| id.0 == 1).count(), 2);
     }
 }
@@ -0,0 +1,66 @@
+//! How far the client's ticks run ahead of the server's, to debug the timing of the prediction.
+//!
+//! The server sends its current tick every [`TICK_BROADCAST_INTERVAL`]. The client compares it with
+//! its own tick when the message arrives. The offset should stay steady around the prediction's lead,
+//! about half the round trip plus the input delay. A drifting or jumping offset means the client's
+//! clock keeps being resynced.
+
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::client::*;
+use lightyear::prelude::*;
+
+use super::protocol::ServerTick;
+
+/// Measures the tick offset on the client, shown in the inspector
+pub struct TickOffsetPlugin;
+
+impl Plugin for TickOffsetPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(TICK_OFFSET).with_suffix("ticks"));
+        app.add_systems(Update, measure_tick_offset);
+    }
+}
+
+/// How many ticks the client is ahead of the server when the server's tick reaches it
+pub const TICK_OFFSET: DiagnosticPath = DiagnosticPath::const_new("tick_offset");
+
+/// How often the server sends its tick
+pub const TICK_BROADCAST_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) fn measure_tick_offset(
+    mut server_ticks: EventReader<MessageEvent<ServerTick>>,
+    tick_manager: Res<TickManager>,
+    mut diagnostics: Diagnostics,
+) {
+    // an older tick arriving late would only be further off
+    let Some(server_tick) = server_ticks.read().last() else {
+        return;
+    };
+    let offset = tick_manager.tick() - server_tick.message().0;
+    diagnostics.add_measurement(&TICK_OFFSET, || f64::from(offset));
+}
+
+/// Send the server's tick to every client
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn broadcast_server_tick(
+    time: Res<Time<Real>>,
+    tick_manager: Res<TickManager>,
+    mut server: super::bandwidth::CountedServer,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(TICK_BROADCAST_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    server
+        .send_message_to_target::<super::protocol::TickChannel, ServerTick>(
+            ServerTick(tick_manager.tick()),
+            NetworkTarget::All,
+        )
+        .unwrap_or_else(|e| {
+            error!("Failed to send the server tick: {:?}", e);
+        });
+}
@@ -0,0 +1,230 @@
+//! TLS termination for the WebSocket transport.
+//!
+//! Lightyear's WebSocket server only speaks plain `ws://`, but browsers loading the client over HTTPS
+//! refuse to open anything but `wss://`. When a WebSocket transport has a [`TlsSettings`], we accept the
+//! TLS connections on the configured port ourselves and forward the decrypted stream to the lightyear
+//! listener, which is bound to a random port on the loopback interface.
+//!
+//! The native client does the same the other way around with the `SecureWebSocket` transport:
+//! lightyear connects over `ws://` to a loopback listener of ours, which forwards the stream to the
+//! server over TLS. The browser client can't, its WebSocket is opened by lightyear as `ws://`.
+//!
+//! For local testing, a self-signed certificate can be created with:
+//! ```sh
+//! openssl req -x509 -newkey rsa:4096 -nodes -days 365 -subj "/CN=localhost" \
+//!     -keyout assets/certs/key.pem -out assets/certs/cert.pem
+//! ```
+//! Then open `https://localhost:<port>` once in the browser and accept the certificate warning,
+//! otherwise the browser silently refuses the WebSocket handshake.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bevy::log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TlsSettings {
+    /// Path to the PEM encoded certificate chain
+    pub cert_path: PathBuf,
+
+    /// Path to the PEM encoded private key (PKCS#8, RSA or EC)
+    pub key_path: PathBuf,
+}
+
+/// How the client checks the certificate of a `wss://` server
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ClientTlsSettings {
+    /// The name the server's certificate was issued for, `localhost` for the self-signed one
+    pub server_name: String,
+
+    /// Path to the PEM encoded certificate of the authority that signed the server's certificate,
+    /// or to the self-signed certificate itself
+    pub ca_cert_path: PathBuf,
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Load the certificate chain and private key referenced by the settings
+fn load_tls_config(settings: &TlsSettings) -> std::io::Result<rustls::ServerConfig> {
+    let mut certs = vec![];
+    let mut key = None;
+    for path in [&settings.cert_path, &settings.key_path] {
+        let mut reader = BufReader::new(File::open(path)?);
+        for item in rustls_pemfile::read_all(&mut reader)? {
+            match item {
+                rustls_pemfile::Item::X509Certificate(cert) => certs.push(Certificate(cert)),
+                rustls_pemfile::Item::PKCS8Key(k)
+                | rustls_pemfile::Item::RSAKey(k)
+                | rustls_pemfile::Item::ECKey(k) => key = Some(PrivateKey(k)),
+                _ => {}
+            }
+        }
+    }
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificate found in {}",
+            settings.cert_path.display()
+        )));
+    }
+    let key = key.ok_or_else(|| {
+        invalid_data(format!(
+            "no private key found in {}",
+            settings.key_path.display()
+        ))
+    })?;
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(e.to_string()))
+}
+
+/// Load the certificates the client trusts for the server
+fn load_client_tls_config(settings: &ClientTlsSettings) -> std::io::Result<rustls::ClientConfig> {
+    let mut reader = BufReader::new(File::open(&settings.ca_cert_path)?);
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader)? {
+        roots
+            .add(&Certificate(cert))
+            .map_err(|e| invalid_data(e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificate found in {}",
+            settings.ca_cert_path.display()
+        )));
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// The loopback port the WebSocket server will listen on behind the TLS proxy.
+///
+/// The port stays taken until this is dropped, so that nothing else grabs it in the meantime.
+/// Drop it right before the lightyear server binds the port.
+pub struct ReservedPort {
+    _listener: TcpListener,
+}
+
+/// Start accepting TLS connections on `public_addr` in a background thread.
+///
+/// Returns the loopback address the plain WebSocket server must listen on, reserved until then.
+pub fn spawn_tls_proxy(
+    public_addr: SocketAddr,
+    settings: &TlsSettings,
+) -> std::io::Result<(SocketAddr, ReservedPort)> {
+    let acceptor = TlsAcceptor::from(Arc::new(load_tls_config(settings)?));
+
+    // let the OS pick a free port for the internal listener
+    let reservation = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+    let internal_addr = reservation.local_addr()?;
+    let public_listener = TcpListener::bind(public_addr)?;
+    public_listener.set_nonblocking(true)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = match tokio::net::TcpListener::from_std(public_listener) {
+                Ok(listener) => listener,
+                Err(e) => return error!("Failed to start the TLS listener: {:?}", e),
+            };
+            info!("Accepting wss:// connections on {}", public_addr);
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept a TLS connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls_stream = match acceptor.accept(stream).await {
+                        Ok(tls_stream) => tls_stream,
+                        Err(e) => return error!("TLS handshake with {} failed: {:?}", peer, e),
+                    };
+                    let mut upstream = match tokio::net::TcpStream::connect(internal_addr).await {
+                        Ok(upstream) => upstream,
+                        Err(e) => return error!("Failed to reach the WebSocket server: {:?}", e),
+                    };
+                    let _ = tokio::io::copy_bidirectional(&mut tls_stream, &mut upstream).await;
+                });
+            }
+        });
+    });
+    Ok((
+        internal_addr,
+        ReservedPort {
+            _listener: reservation,
+        },
+    ))
+}
+
+/// Start forwarding the connections made to a loopback port to the `wss://` server at
+/// `server_addr`, in a background thread.
+///
+/// Returns the loopback address lightyear's plain WebSocket client must connect to.
+pub fn spawn_tls_client_proxy(
+    server_addr: SocketAddr,
+    settings: &ClientTlsSettings,
+) -> std::io::Result<SocketAddr> {
+    let connector = TlsConnector::from(Arc::new(load_client_tls_config(settings)?));
+    let server_name = rustls::ServerName::try_from(settings.server_name.as_str())
+        .map_err(|e| invalid_data(format!("invalid server name: {e}")))?;
+
+    // the listener is handed to the proxy thread, so the port is never free for someone else to take
+    let local_listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+    let local_addr = local_listener.local_addr()?;
+    local_listener.set_nonblocking(true)?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let listener = match tokio::net::TcpListener::from_std(local_listener) {
+                Ok(listener) => listener,
+                Err(e) => return error!("Failed to start the TLS client proxy: {:?}", e),
+            };
+            loop {
+                let (mut stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Failed to accept a local WebSocket connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let connector = connector.clone();
+                let server_name = server_name.clone();
+                tokio::spawn(async move {
+                    let upstream = match tokio::net::TcpStream::connect(server_addr).await {
+                        Ok(upstream) => upstream,
+                        Err(e) => return error!("Failed to reach {}: {:?}", server_addr, e),
+                    };
+                    let mut tls_stream = match connector.connect(server_name, upstream).await {
+                        Ok(tls_stream) => tls_stream,
+                        Err(e) => {
+                            return error!("TLS handshake with {} failed: {:?}", server_addr, e)
+                        }
+                    };
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut tls_stream).await;
+                });
+            }
+        });
+    });
+    info!("Connecting to wss://{} through {}", server_addr, local_addr);
+    Ok(local_addr)
+}
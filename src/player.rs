@@ -1,60 +1,300 @@
 use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::client::Predicted;
 use lightyear::{connection::netcode::ClientId, shared::replication::components::NetworkTarget};
 
-use crate::networking::protocol::{PlayerId, PlayerPosition, Replicate};
+use crate::networking::protocol::{
+    Direction, PlayerId, PlayerPosition, PlayerSlot, PlayerVelocity, Replicate,
+};
+use crate::networking::{AnimationClip, AnimationSettings};
 
 /// Plugin for spawning the player and controlling them.
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, animate_sprite);
+        app.add_systems(Update, animate_sprite.in_set(AnimationSet));
     }
 }
 
-#[derive(Component)]
+/// The systems animating the player sprites, which stop while the game is paused
+#[derive(SystemSet, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AnimationSet;
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
 pub struct AnimationIndices {
     pub first: usize,
     pub last: usize,
 }
 
+impl From<&AnimationClip> for AnimationIndices {
+    fn from(clip: &AnimationClip) -> Self {
+        Self {
+            first: clip.first,
+            last: clip.last,
+        }
+    }
+}
+
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationTimer(pub Timer);
 
+/// How long a player has to stay in place before it is considered standing still.
+/// Positions are only updated on fixed ticks, so a single frame without movement doesn't mean much.
+const IDLE_DELAY: Duration = Duration::from_millis(100);
+
+/// The direction a player is looking at, following its last movement
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Facing {
+    #[default]
+    Down,
+    Up,
+    Left,
+    Right,
+}
+
+impl Facing {
+    /// The direction of a movement, going with the dominant axis for diagonals
+    fn from_movement(delta: Vec2) -> Self {
+        if delta.x.abs() > delta.y.abs() {
+            if delta.x < 0.0 {
+                Facing::Left
+            } else {
+                Facing::Right
+            }
+        } else if delta.y > 0.0 {
+            Facing::Up
+        } else {
+            Facing::Down
+        }
+    }
+
+    /// The direction of an input, like [`Facing::from_movement`] for diagonals. None when not moving.
+    pub fn from_direction(direction: &Direction) -> Option<Self> {
+        match (
+            direction.up,
+            direction.down,
+            direction.left,
+            direction.right,
+        ) {
+            (true, false, _, _) => Some(Facing::Up),
+            (false, true, _, _) => Some(Facing::Down),
+            (_, _, true, false) => Some(Facing::Left),
+            (_, _, false, true) => Some(Facing::Right),
+            _ => None,
+        }
+    }
+}
+
+/// The direction a player last moved in, kept while it stands still so that it doesn't turn around.
+/// Our own players follow their inputs, the others the movements replicated by the server.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LastFacing(pub Facing);
+
+/// Tracks whether a player is moving, to pick the animation to play
+#[derive(Component, Default)]
+pub struct MovementTracker {
+    last_position: Vec2,
+    still_for: Duration,
+}
+
+impl MovementTracker {
+    /// Record the current position, returns how far the player moved since the last one
+    fn update(&mut self, position: Vec2, delta: Duration) -> Vec2 {
+        let moved = position - self.last_position;
+        if moved != Vec2::ZERO {
+            self.last_position = position;
+            self.still_for = Duration::ZERO;
+        } else {
+            self.still_for += delta;
+        }
+        moved
+    }
+
+    fn is_moving(&self) -> bool {
+        self.still_for < IDLE_DELAY
+    }
+}
+
+impl AnimationSettings {
+    /// The animation of a player standing still, looking in the direction it last moved in
+    fn idle_clip(&self, facing: Facing) -> &AnimationClip {
+        let clip = match facing {
+            Facing::Down => &self.idle_down,
+            Facing::Up => &self.idle_up,
+            Facing::Left => &self.idle_left,
+            Facing::Right => &self.idle_right,
+        };
+        clip.as_ref().unwrap_or(&self.idle)
+    }
+
+    /// The animation to play for a player in the given state
+    fn select(&self, moving: bool, facing: Facing) -> &AnimationClip {
+        if !moving {
+            return self.idle_clip(facing);
+        }
+        match facing {
+            Facing::Down => &self.walk_down,
+            Facing::Up => &self.walk_up,
+            Facing::Left => &self.walk_left,
+            Facing::Right => &self.walk_right,
+        }
+    }
+}
+
 fn animate_sprite(
     time: Res<Time>,
-    mut query: Query<(&AnimationIndices, &mut AnimationTimer, &mut TextureAtlas)>,
+    animations: Res<AnimationSettings>,
+    mut query: Query<(
+        &mut AnimationIndices,
+        &mut AnimationTimer,
+        &mut TextureAtlas,
+        &mut MovementTracker,
+        &mut LastFacing,
+        &PlayerPosition,
+        Has<Predicted>,
+    )>,
 ) {
-    for (indices, mut timer, mut atlas) in &mut query {
-        timer.tick(time.delta());
-        if timer.just_finished() {
-            atlas.index = if atlas.index == indices.last {
-                indices.first
-            } else {
-                atlas.index + 1
-            };
+    for (mut indices, mut timer, mut atlas, mut tracker, mut facing, position, predicted) in
+        &mut query
+    {
+        let moved = tracker.update(position.0, time.delta());
+        if moved != Vec2::ZERO && !predicted {
+            facing.0 = Facing::from_movement(moved);
         }
+        let clip = animations.select(tracker.is_moving(), facing.0);
+        if *indices != AnimationIndices::from(clip) {
+            // switch to the new animation from its first frame
+            *indices = AnimationIndices::from(clip);
+            atlas.index = indices.first;
+            timer.set_duration(Duration::from_secs_f32(1.0 / clip.fps));
+            timer.reset();
+        }
+        timer.tick(time.delta());
+        // after a stall the timer can finish several times at once, catch up on all of those frames
+        atlas.index = indices.advance(atlas.index, timer.times_finished_this_tick() as usize);
+    }
+}
+
+impl AnimationIndices {
+    /// The frame `frames` frames after `index`, looping back to the first one after the last
+    pub fn advance(&self, index: usize, frames: usize) -> usize {
+        let len = self.last - self.first + 1;
+        // a frame outside of the animation restarts it
+        let offset = index.checked_sub(self.first).filter(|offset| *offset < len);
+        self.first + (offset.unwrap_or(0) + frames) % len
     }
 }
 
 #[derive(Bundle)]
 pub struct PlayerBundle {
     id: PlayerId,
+    slot: PlayerSlot,
     pub position: PlayerPosition,
+    velocity: PlayerVelocity,
     replicate: Replicate,
 }
 
 impl PlayerBundle {
-    pub(crate) fn new(id: ClientId, position: Vec2) -> Self {
+    pub(crate) fn new(id: ClientId, slot: u8, position: Vec2) -> Self {
         Self {
             id: PlayerId(id),
+            slot: PlayerSlot(slot),
             position: PlayerPosition(position),
+            velocity: PlayerVelocity::default(),
             replicate: Replicate {
                 // prediction_target: NetworkTarget::None,
                 prediction_target: NetworkTarget::Only(vec![id]),
-                interpolation_target: NetworkTarget::AllExcept(vec![id]),
+                // the owner only shows its interpolated copy with `ClientSettings::show_confirmed_player`
+                interpolation_target: NetworkTarget::All,
                 ..default()
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WALK: AnimationIndices = AnimationIndices { first: 4, last: 7 };
+
+    #[test]
+    fn advance_loops_over_the_animation() {
+        assert_eq!(WALK.advance(4, 1), 5);
+        assert_eq!(WALK.advance(7, 1), 4);
+        assert_eq!(WALK.advance(5, 0), 5);
+        // a frame of another animation starts this one over
+        assert_eq!(WALK.advance(0, 1), 5);
+    }
+
+    #[test]
+    fn advance_catches_up_after_a_stall() {
+        let mut timer = Timer::from_seconds(0.1, TimerMode::Repeating);
+        // a frame taking over a second, e.g. while the window was dragged
+        timer.tick(Duration::from_millis(1050));
+        let frames = timer.times_finished_this_tick() as usize;
+        assert_eq!(frames, 10);
+        // ten frames of a four frame animation, two and a half loops
+        assert_eq!(WALK.advance(4, frames), 6);
+        assert_eq!(WALK.advance(6, 4 * 1000 + 1), 7);
+    }
+
+    fn animated_player(app: &mut App, position: Vec2) -> Entity {
+        let animations = app.world.resource::<AnimationSettings>();
+        let indices = AnimationIndices::from(&animations.idle);
+        app.world
+            .spawn((
+                indices,
+                AnimationTimer(Timer::from_seconds(0.1, TimerMode::Repeating)),
+                TextureAtlas {
+                    layout: Handle::default(),
+                    index: indices.first,
+                },
+                MovementTracker {
+                    last_position: position,
+                    still_for: IDLE_DELAY,
+                },
+                LastFacing::default(),
+                PlayerPosition(position),
+            ))
+            .id()
+    }
+
+    fn step(app: &mut App, millis: u64) {
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_millis(millis));
+        app.update();
+    }
+
+    #[test]
+    fn players_keep_facing_left_after_idling() {
+        let mut app = App::new();
+        app.init_resource::<Time>();
+        app.insert_resource(crate::networking::Settings::embedded().client.animations);
+        app.add_systems(Update, animate_sprite);
+        let player = animated_player(&mut app, Vec2::new(100.0, 100.0));
+
+        for x in [95.0, 90.0, 85.0] {
+            app.world.get_mut::<PlayerPosition>(player).unwrap().0.x = x;
+            step(&mut app, 16);
+        }
+        assert_eq!(
+            app.world.get::<LastFacing>(player),
+            Some(&LastFacing(Facing::Left))
+        );
+
+        // standing still long enough to switch to the idle animation
+        for _ in 0..10 {
+            step(&mut app, 16);
+        }
+        assert_eq!(
+            app.world.get::<LastFacing>(player),
+            Some(&LastFacing(Facing::Left))
+        );
+        let animations = app.world.resource::<AnimationSettings>();
+        let idle = AnimationIndices::from(animations.idle_clip(Facing::Left));
+        assert_eq!(app.world.get::<AnimationIndices>(player), Some(&idle));
+    }
+}
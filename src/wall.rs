@@ -8,3 +8,42 @@ pub struct Wall;
 pub struct WallBundle {
     wall: Wall,
 }
+
+/// A cell that hurts the players standing on it, the damage is handled by the server
+#[derive(Default, Component)]
+pub struct Hazard;
+
+#[derive(Default, Bundle, LdtkIntCell)]
+pub struct HazardBundle {
+    hazard: Hazard,
+}
+
+/// A cell slowing the players down, see [`crate::level::Cell::Water`]
+#[derive(Default, Component)]
+pub struct Water;
+
+#[derive(Default, Bundle, LdtkIntCell)]
+pub struct WaterBundle {
+    water: Water,
+}
+
+/// A cell the players slide on, see [`crate::level::Cell::Ice`]
+#[derive(Default, Component)]
+pub struct Ice;
+
+#[derive(Default, Bundle, LdtkIntCell)]
+pub struct IceBundle {
+    ice: Ice,
+}
+
+/// Spawns the bundle of each value of the LDtk walls layer, the same values as [`crate::level::Cell`]
+pub struct TerrainPlugin;
+
+impl Plugin for TerrainPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_ldtk_int_cell::<WallBundle>(1)
+            .register_ldtk_int_cell::<HazardBundle>(2)
+            .register_ldtk_int_cell::<WaterBundle>(3)
+            .register_ldtk_int_cell::<IceBundle>(4);
+    }
+}
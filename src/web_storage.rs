@@ -0,0 +1,172 @@
+//! Persistence of the player's preferences and client id in the browser's `localStorage`, since a
+//! wasm client can't write to disk. Storage may be unavailable (private browsing, disabled cookies),
+//! in which case nothing is persisted and the embedded defaults are used.
+//!
+//! Only what a player picks for themselves is kept. Everything else, like the server address or the
+//! transport, always comes from the embedded settings so that a new deployment can change it.
+
+use bevy::scene::ron;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::networking::{
+    protocol::ReconnectToken, random_client_id, CameraLookaheadSettings, ClientSettings,
+    PresentModeSetting, BOT_IDS,
+};
+
+const CLIENT_PREFERENCES_KEY: &str = "matrix-rpg.client-preferences";
+/// Where older versions saved all of the client settings
+const LEGACY_CLIENT_SETTINGS_KEY: &str = "matrix-rpg.client-settings";
+const CLIENT_ID_KEY: &str = "matrix-rpg.client-id";
+const RECONNECT_TOKEN_KEY: &str = "matrix-rpg.reconnect-token";
+/// Adding this to the page's query string (`?reset-client-id`) makes the client start over with a new id
+const RESET_CLIENT_ID_QUERY: &str = "reset-client-id";
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+/// The client settings that are up to the player, the ones missing from the storage keep their
+/// embedded value
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct ClientPreferences {
+    sprite_scale: Option<f32>,
+    camera_offset: Option<(f32, f32)>,
+    camera_lookahead: Option<CameraLookaheadSettings>,
+    local_players: Option<u8>,
+    present_mode: Option<PresentModeSetting>,
+    show_confirmed_player: Option<bool>,
+    show_link_quality: Option<bool>,
+    auto_spawn: Option<bool>,
+    view_radius: Option<Option<f32>>,
+}
+
+impl ClientPreferences {
+    fn from_settings(settings: &ClientSettings) -> Self {
+        Self {
+            sprite_scale: Some(settings.sprite_scale),
+            camera_offset: Some(settings.camera_offset),
+            camera_lookahead: Some(settings.camera_lookahead),
+            local_players: Some(settings.local_players),
+            present_mode: Some(settings.present_mode),
+            show_confirmed_player: Some(settings.show_confirmed_player),
+            show_link_quality: Some(settings.show_link_quality),
+            auto_spawn: Some(settings.auto_spawn),
+            view_radius: Some(settings.view_radius),
+        }
+    }
+
+    fn apply(self, settings: &mut ClientSettings) {
+        let Self {
+            sprite_scale,
+            camera_offset,
+            camera_lookahead,
+            local_players,
+            present_mode,
+            show_confirmed_player,
+            show_link_quality,
+            auto_spawn,
+            view_radius,
+        } = self;
+        settings.sprite_scale = sprite_scale.unwrap_or(settings.sprite_scale);
+        settings.camera_offset = camera_offset.unwrap_or(settings.camera_offset);
+        settings.camera_lookahead = camera_lookahead.unwrap_or(settings.camera_lookahead);
+        settings.local_players = local_players.unwrap_or(settings.local_players);
+        settings.present_mode = present_mode.unwrap_or(settings.present_mode);
+        settings.show_confirmed_player =
+            show_confirmed_player.unwrap_or(settings.show_confirmed_player);
+        settings.show_link_quality = show_link_quality.unwrap_or(settings.show_link_quality);
+        settings.auto_spawn = auto_spawn.unwrap_or(settings.auto_spawn);
+        settings.view_radius = view_radius.unwrap_or(settings.view_radius);
+    }
+}
+
+/// Apply the preferences saved by a previous visit over the embedded client settings
+pub fn load_client_preferences(settings: &mut ClientSettings) {
+    let Some(storage) = local_storage() else {
+        return;
+    };
+    // the whole settings saved by older versions would pin the server address and transport
+    let _ = storage.remove_item(LEGACY_CLIENT_SETTINGS_KEY);
+    let stored = storage.get_item(CLIENT_PREFERENCES_KEY).ok().flatten();
+    // preferences saved by an older version might not parse anymore, keep the defaults then
+    if let Some(preferences) =
+        stored.and_then(|stored| ron::de::from_str::<ClientPreferences>(&stored).ok())
+    {
+        preferences.apply(settings);
+    }
+}
+
+/// Save the player's preferences so that they are used on the next visit
+pub fn save_client_preferences(settings: &ClientSettings) {
+    let Some(storage) = local_storage() else {
+        return;
+    };
+    if let Ok(serialized) = ron::ser::to_string(&ClientPreferences::from_settings(settings)) {
+        let _ = storage.set_item(CLIENT_PREFERENCES_KEY, &serialized);
+    }
+}
+
+/// Get the id this browser used on previous visits, or generate and store a new one on the first visit.
+///
+/// Keeping the id across reloads lets the server recognize a returning player.
+pub fn load_or_create_client_id(rng: &mut impl Rng) -> u64 {
+    if reset_requested() {
+        reset_client_id();
+    }
+    let Some(storage) = local_storage() else {
+        return random_client_id(rng);
+    };
+    let stored = storage
+        .get_item(CLIENT_ID_KEY)
+        .ok()
+        .flatten()
+        .and_then(|id| id.parse::<u64>().ok())
+        // ids stored by older versions may be reserved for bots since
+        .filter(|id| !BOT_IDS.contains(id));
+    if let Some(client_id) = stored {
+        return client_id;
+    }
+    let client_id = random_client_id(rng);
+    let _ = storage.set_item(CLIENT_ID_KEY, &client_id.to_string());
+    client_id
+}
+
+/// Forget the stored client id, the next call to [`load_or_create_client_id`] generates a new one
+pub fn reset_client_id() {
+    if let Some(storage) = local_storage() {
+        let _ = storage.remove_item(CLIENT_ID_KEY);
+        // the token is only valid for the old id
+        let _ = storage.remove_item(RECONNECT_TOKEN_KEY);
+    }
+}
+
+/// Read the reconnect token received before the page was reloaded
+pub fn load_reconnect_token() -> Option<ReconnectToken> {
+    let stored = local_storage()?
+        .get_item(RECONNECT_TOKEN_KEY)
+        .ok()
+        .flatten()?;
+    ron::de::from_str(&stored).ok()
+}
+
+pub fn save_reconnect_token(token: &ReconnectToken) {
+    let Some(storage) = local_storage() else {
+        return;
+    };
+    if let Ok(serialized) = ron::ser::to_string(token) {
+        let _ = storage.set_item(RECONNECT_TOKEN_KEY, &serialized);
+    }
+}
+
+fn reset_requested() -> bool {
+    web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .is_some_and(|search| {
+            search
+                .trim_start_matches('?')
+                .split('&')
+                .any(|param| param.split('=').next() == Some(RESET_CLIENT_ID_QUERY))
+        })
+}